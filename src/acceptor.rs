@@ -1,22 +1,23 @@
-use crossbeam::channel::{Sender, Receiver, TryRecvError};
 use std::thread;
+use std::time::Instant;
+use std::collections::{BTreeMap, VecDeque};
 
-use crate::utils::{P1a, P1b, P2a, P2b, Ballot, Pvalue};
+use crate::utils::{P1a, P1b, P2a, P2b, Ballot, Pvalue, ControlSignal, Message};
+use crate::node::{self, Node};
+use crate::storage::AcceptorStorage;
+use crate::state_machine::StateMachine;
+use crate::replica::SystemMessage;
+use crate::transport::{NodeId, Transport};
 
 enum OperatingState {
     Paused,
     Run(u32),
+    // everything is dropped until the restart signal
+    Crashed,
     Exit,
 }
 
-#[derive(Clone)]
-pub enum ControlSignal {
-    Paused,
-    Run(u32),
-    Exit,
-}
-
-pub struct Context {
+pub struct Context<S: StateMachine> {
     // ID of the leader
     id: u32,
 
@@ -24,94 +25,44 @@ pub struct Context {
     // ballot number
     ballot_num: Option<Ballot>,
 
-    // accepted set of pvalues
-    accepted: Vec<Pvalue>,
-
-    // handle for the broadcast channel between all scouts and the acceptor
-    scout_acceptor_broadcast_chan_receiver: Vec<Receiver<P1a>>,
+    // accepted pvalues indexed by slot
+    // only the pvalue with the highest ballot is kept for each slot
+    accepted: BTreeMap<u32, Pvalue<S::Command>>,
 
-    // handle for the broadcast channel between all commanders and the acceptor
-    commander_acceptor_broadcast_chan_receiver: Vec<Receiver<P2a>>,
+    // endpoint of the acceptor, P1a and P2a come in and P1b and P2b go out to the leaders
+    transport: Box<dyn Transport<SystemMessage<S>>>,
 
-    // vec of handle for the mpsc channels from the acceptor to all the leaders for the commanders
-    // the sender handle is shared with other acceptors
-    acceptor_leader_for_commander_mpsc_chan_senders: Vec<Sender<P2b>>,
-
-    // vec of handle for the mpsc channels from the acceptor to all the leaders for the scouts
-    // the sender handle is shared with other acceptors
-    acceptor_leader_for_scout_mpsc_chan_senders: Vec<Sender<P1b>>,
-
-    // handle for controlling the leader operating state
-    control_chan_receiver: Receiver<ControlSignal>,
+    // messages that arrived while the acceptor was paused, handled once it runs
+    deferred: VecDeque<SystemMessage<S>>,
 
     // operating state of the leader
     operating_state: OperatingState,
+
+    // storage backend, promises and accepts are recorded here before P1b/P2b are sent
+    storage: Box<dyn AcceptorStorage<S::Command>>,
 }
 
-pub fn new(
+pub fn new<S: StateMachine>(
     id: u32,
-    scout_acceptor_broadcast_chan_receiver: Vec<Receiver<P1a>>,
-    commander_acceptor_broadcast_chan_receiver: Vec<Receiver<P2a>>,
-    acceptor_leader_for_commander_mpsc_chan_senders: Vec<Sender<P2b>>,
-    acceptor_leader_for_scout_mpsc_chan_senders: Vec<Sender<P1b>>,
-    control_chan_receiver: Receiver<ControlSignal>,
-) -> Context {
-    let context = Context {
+    transport: Box<dyn Transport<SystemMessage<S>>>,
+    storage: Box<dyn AcceptorStorage<S::Command>>,
+) -> Context<S> {
+    let mut context = Context {
         id,
         ballot_num: None,
-        accepted: Vec::new(),
-        scout_acceptor_broadcast_chan_receiver,
-        commander_acceptor_broadcast_chan_receiver,
-        acceptor_leader_for_commander_mpsc_chan_senders,
-        acceptor_leader_for_scout_mpsc_chan_senders,
-        control_chan_receiver,
+        accepted: BTreeMap::new(),
+        transport,
+        deferred: VecDeque::new(),
         operating_state: OperatingState::Paused,
+        storage,
     };
-
+    context.load();
     context
 }
 
-impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
-            .spawn(move || {
-                loop {
-                    match self.operating_state {
-                        OperatingState::Paused => {
-                            // println!("Acceptor {} in paused mode", self.id);
-                            let signal = self.control_chan_receiver.recv().unwrap();
-                            self.control_signal_processing(signal);
-                        }
-
-                        OperatingState::Run(num_msgs) => {
-                            // analyzing under various control channel state
-                            match self.control_chan_receiver.try_recv() {
-                                Ok(signal) => {
-                                    // Exit signal received
-                                    self.control_signal_processing(signal);
-                                }
-
-                                // empty control channel, feel free to continue interacting with the replicas
-                                Err(TryRecvError::Empty) => {
-                                    self.processing_p1a_message_from_scout();
-                                    self.processing_p2a_message_from_commander();
-                                }
-
-                                // Disconnected control channel
-                                Err(TryRecvError::Disconnected) => {
-                                    panic!("Acceptor {} control channel detached", self.id)
-                                }
-                            }
-                        }
-
-                        OperatingState::Exit => {
-                            println!("Acceptor {} deactivated.......................", self.id);
-                            break;
-                        }
-                    }
-                }
-            })
-            .unwrap();
+impl<S: StateMachine> Context<S> {
+    pub fn start(self) -> thread::JoinHandle<()> {
+        node::spawn(Box::new(self))
     }
 
 
@@ -119,57 +70,83 @@ impl Context {
 
 
 
-    fn processing_p1a_message_from_scout(&mut self) {
-        for handle in &self.scout_acceptor_broadcast_chan_receiver {
-            match handle.try_recv() {
-                Ok(message) => {
-                    // ballot check
-                    // println!("Acceptor {} has received P1a", self.id);
-                    match self.ballot_num.clone() {
-                        Some(b) => {
-                            if message.get_ballot_num() > b {
-                                self.ballot_num = Some(message.get_ballot_num());
-                            }
-                        }
+    // rebuilding the state recorded before a restart
+    fn load(&mut self) {
+        let state = self
+            .storage
+            .load()
+            .unwrap_or_else(|err| panic!("Acceptor {} can't load its log: {}", self.id, err));
+        self.ballot_num = state.ballot_num;
+        self.accepted = state.accepted;
+    }
 
-                        None => { self.ballot_num = Some(message.get_ballot_num()); }
-                    }
 
 
-                    // send the P1b message to the scout
-                    self.acceptor_leader_for_scout_mpsc_chan_senders[message.get_leader_id() as usize]
-                    .send( P1b::create(
-                                        self.id.clone(),
-                                        self.ballot_num.clone().unwrap(), 
-                                        self.accepted.clone(), 
-                                        message.get_scout_id(),
-                                    ));
-                    // println!("Acceptor {} has sent P1b", self.id);
-                }
-                _ => {}
-            }
+    fn processing_p1a_message_from_scout(&mut self, message: P1a) {
+        // ballot check
+        // println!("Acceptor {} has received P1a", self.id);
+        let promise = match self.ballot_num.clone() {
+            Some(b) => message.get_ballot_num() > b,
+            None => true,
+        };
+        if promise {
+            // the promise has to be durable before the P1b goes out
+            self.storage
+                .record_promise(&message.get_ballot_num())
+                .unwrap_or_else(|err| panic!("Acceptor {} can't write its log: {}", self.id, err));
+            self.ballot_num = Some(message.get_ballot_num());
         }
+
+
+        // send the P1b message to the scout
+        self.transport.send(
+            NodeId::Leader(message.get_leader_id()),
+            Message::P1b(P1b::create(
+                            self.id,
+                            self.ballot_num.clone().unwrap(), 
+                            self.accepted
+                                .range(message.get_slot_watermark() + 1..)
+                                .map(|(_, pvalue)| pvalue.clone())
+                                .collect(),
+                            message.get_scout_id(),
+                        )),
+        );
+        // println!("Acceptor {} has sent P1b", self.id);
     }
 
 
-    fn processing_p2a_message_from_commander(&mut self) {
-        for handle in &self.commander_acceptor_broadcast_chan_receiver {
-            match handle.try_recv() {
-                Ok(message) => {
-                    // println!("Acceptor {} has received P2a", self.id);
-                    if message.get_ballot_num() == self.ballot_num.clone().unwrap() {
-                        // inserting the pvalue
-                        self.accepted.push(message.get_pvalue());
-                    }
-
-                    // send the P2b message to the commander
-                    self.acceptor_leader_for_commander_mpsc_chan_senders[message.get_leader_id() as usize]
-                    .send(P2b::create(self.id.clone(), self.ballot_num.clone().unwrap(), message.get_commander_id()));
-                    // println!("Acceptor {} has sent P2b", self.id);
-                }
-                _ => {}
+    fn processing_p2a_message_from_commander(&mut self, message: P2a<S::Command>) {
+        // println!("Acceptor {} has received P2a", self.id);
+        // as in PMMC, a ballot at least as high as the promised one is adopted and accepted
+        // a restarted acceptor may not have promised anything yet
+        let accept = match self.ballot_num.clone() {
+            Some(b) => message.get_ballot_num() >= b,
+            None => true,
+        };
+        if accept {
+            // the accept has to be durable before the P2b goes out
+            let pvalue = message.get_pvalue();
+            self.storage
+                .record_accept(&pvalue)
+                .unwrap_or_else(|err| panic!("Acceptor {} can't write its log: {}", self.id, err));
+            self.ballot_num = Some(message.get_ballot_num());
+
+            // inserting the pvalue, replacing the one with a lower ballot for the slot
+            let newer = match self.accepted.get(&pvalue.get_slot()) {
+                Some(current) => pvalue.get_ballot_num() > current.get_ballot_num(),
+                None => true,
+            };
+            if newer {
+                self.accepted.insert(pvalue.get_slot(), pvalue);
             }
         }
+
+        // send the P2b message to the commander
+        self.transport.send(
+            NodeId::Leader(message.get_leader_id()),
+            Message::P2b(P2b::create(self.id, self.ballot_num.clone().unwrap(), message.get_commander_id())),
+        );
+        // println!("Acceptor {} has sent P2b", self.id);
     }
 
 
@@ -184,12 +161,79 @@ impl Context {
             ControlSignal::Run(num_msgs) => {
                 println!("Acceptor {} activated", self.id);
                 self.operating_state = OperatingState::Run(num_msgs);
+
+                // the messages deferred while paused come first
+                let deferred = std::mem::take(&mut self.deferred);
+                for message in deferred {
+                    self.handle_message(message);
+                }
+            }
+
+            // the promises and accepts in memory are gone, the ones in the storage stay
+            ControlSignal::Crash => {
+                println!("Acceptor {} crashed", self.id);
+                self.operating_state = OperatingState::Crashed;
+                self.ballot_num = None;
+                self.accepted.clear();
+                self.deferred.clear();
+            }
+
+            // back with whatever the storage has kept, nothing at all with the memory storage
+            ControlSignal::Restart => {
+                println!("Acceptor {} restarted", self.id);
+                self.deferred.clear();
+                self.load();
+                self.operating_state = OperatingState::Run(0);
             }
 
             ControlSignal::Exit => {
                 // println!("Exit signal at Acceptor {} received", self.id);
+                println!("Acceptor {} deactivated.......................", self.id);
                 self.operating_state = OperatingState::Exit;
             }
+
+            ControlSignal::Wakeup => {}
         }
     }
 }
+
+
+
+
+impl<S: StateMachine> Node<S> for Context<S> {
+    fn get_id(&self) -> NodeId {
+        NodeId::Acceptor(self.id)
+    }
+
+    fn transport(&self) -> &dyn Transport<SystemMessage<S>> {
+        self.transport.as_ref()
+    }
+
+    fn handle_message(&mut self, message: SystemMessage<S>) {
+        match (&self.operating_state, message) {
+            // a crashed acceptor only comes back on the restart signal, or exits
+            (OperatingState::Crashed, Message::Control(signal @ (ControlSignal::Restart | ControlSignal::Exit))) => {
+                self.control_signal_processing(signal)
+            }
+            (OperatingState::Crashed, _) => {}
+            (_, Message::Control(signal)) => self.control_signal_processing(signal),
+            // only control signals are handled while paused, the rest waits until the acceptor runs
+            (OperatingState::Paused, message) => self.deferred.push_back(message),
+            (OperatingState::Run(_), Message::P1a(message)) => self.processing_p1a_message_from_scout(message),
+            (OperatingState::Run(_), Message::P2a(message)) => self.processing_p2a_message_from_commander(message),
+            // nothing else is meant for an acceptor
+            _ => {}
+        }
+    }
+
+    // an acceptor only ever answers
+    fn handle_timers(&mut self) {}
+
+    fn next_wakeup(&self) -> Option<Instant> {
+        None
+    }
+
+    fn has_exited(&self) -> bool {
+        matches!(self.operating_state, OperatingState::Exit)
+    }
+}
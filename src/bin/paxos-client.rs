@@ -0,0 +1,148 @@
+use paxos::{
+    ClientHandle, ClientOptions, ClusterConfig, ClusterSize, Counter, Decode, Encode, KvStore, NodeId, RetryPolicy, StateMachine,
+    SystemMessage, TcpTransport,
+};
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+
+const USAGE: &str = "usage: paxos-client --id <n> (--config <file> | --peers <node=addr,...>) [--state-machine <counter|kv>]
+                    [--first-command-id <n>] [--timeout-ms <n>] [--retry-ms <n>] [command...]
+
+  --id                ID of the client, it needs an address of its own in --peers
+  --config            cluster config in TOML, as given to paxos-node
+  --peers             addresses of every node of the cluster, as given to paxos-node
+  --state-machine     application replicated by the replicas, counter by default
+  --first-command-id  ID of the first command, 1 by default
+                      the replicas answer a command ID they have seen before from their cache, so a client
+                      started again with the same ID has to continue after the last command it sent
+  --timeout-ms        time to wait for the answer to a command, 10000 by default
+  --retry-ms          time to wait before sending a command again, the same command ID every time, 200 by default
+                      the wait doubles with every retry
+
+Every command is one argument, e.g. \"put key value\" or \"add 2\", they are read from stdin one per line when
+none is given. counter takes add n, sub n and mul n, kv takes get key, put key value, delete key and
+cas key expected new with - for an absent expected value. The result of every command is printed.";
+
+
+struct Args {
+    id: u32,
+    peers: HashMap<NodeId, SocketAddr>,
+    state_machine: String,
+    first_command_id: u32,
+    retry: RetryPolicy,
+    commands: Vec<String>,
+}
+
+
+fn parse_args() -> Result<Args, String> {
+    let mut id = None;
+    let mut config = None;
+    let mut peers = None;
+    let mut state_machine = "counter".to_string();
+    let mut first_command_id = 1u32;
+    let mut retry = RetryPolicy::default();
+    let mut commands = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--id" => id = Some(value()?.parse::<u32>().map_err(|err| format!("Invalid --id: {}", err))?),
+            "--config" => config = Some(value()?),
+            "--peers" => peers = Some(paxos::parse_addrs(&value()?)?),
+            "--state-machine" => state_machine = value()?,
+            "--first-command-id" => {
+                first_command_id = value()?.parse().map_err(|err| format!("Invalid --first-command-id: {}", err))?
+            }
+            "--timeout-ms" => {
+                retry.deadline = Duration::from_millis(value()?.parse().map_err(|err| format!("Invalid --timeout-ms: {}", err))?)
+            }
+            "--retry-ms" => {
+                retry.retry_interval = Duration::from_millis(value()?.parse().map_err(|err| format!("Invalid --retry-ms: {}", err))?);
+                retry.max_retry_interval = retry.max_retry_interval.max(retry.retry_interval);
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            other if other.starts_with("--") => return Err(format!("Unknown argument {:?}", other)),
+            command => commands.push(command.to_string()),
+        }
+    }
+
+    Ok(Args {
+        id: id.ok_or("--id is required")?,
+        peers: match (config, peers) {
+            (Some(path), None) => {
+                let config = ClusterConfig::load(Path::new(&path)).map_err(|err| err.to_string())?;
+                config.require_addresses().map_err(|err| err.to_string())?;
+                config.addrs
+            }
+            (None, Some(peers)) => peers,
+            (Some(_), Some(_)) => return Err("--config and --peers don't go together".to_string()),
+            (None, None) => return Err("--config or --peers is required".to_string()),
+        },
+        state_machine,
+        first_command_id,
+        retry,
+        commands,
+    })
+}
+
+
+
+
+// submitting the commands one after the other through a client handle, each to every replica
+// the first response is the one printed, the other replicas answer the same
+fn run<S: StateMachine>(args: &Args) -> Result<(), String>
+where
+    S::Command: FromStr<Err = String>,
+    SystemMessage<S>: Encode + Decode,
+{
+    let ids: Vec<NodeId> = args.peers.keys().cloned().collect();
+    let cluster = ClusterSize::from_nodes(&ids).map_err(|node| format!("{} has no address in --peers", node))?;
+
+    let node = NodeId::Client(args.id);
+    let endpoint = TcpTransport::<SystemMessage<S>>::bind(node, &args.peers)
+        .map_err(|err| format!("Can't listen for {} on {:?}: {}", node, args.peers.get(&node), err))?;
+    let options = ClientOptions { retry: args.retry.clone(), first_command_id: args.first_command_id };
+    let client = ClientHandle::<S>::new(args.id, Box::new(endpoint), cluster.replicas, options);
+
+    let commands: Box<dyn Iterator<Item = String>> = if args.commands.is_empty() {
+        Box::new(io::stdin().lock().lines().map_while(Result::ok).filter(|line| !line.trim().is_empty()))
+    } else {
+        Box::new(args.commands.clone().into_iter())
+    };
+
+    for line in commands {
+        let command: S::Command = line.parse()?;
+        let result = client.submit(command).map_err(|err| format!("No answer to {:?}: {}", line, err))?;
+        println!("{} -> {:?}", line.trim(), result);
+    }
+    Ok(())
+}
+
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, USAGE);
+        process::exit(2);
+    });
+
+    let outcome = match args.state_machine.as_str() {
+        "counter" => run::<Counter>(&args),
+        "kv" => run::<KvStore>(&args),
+        other => Err(format!("Unknown state machine {:?}", other)),
+    };
+    if let Err(err) = outcome {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,182 @@
+use paxos::{
+    ClusterConfig, ClusterSize, Counter, ControlSignal, Decode, Encode, KvStore, Message, NodeId, StateMachine, SystemMessage,
+    SystemOptions, TcpTransport, Transport, Workload, counter_workload, kv_workload,
+};
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+
+const USAGE: &str = "usage: paxos-node --role <acceptor|leader|replica|server> --id <n> (--config <file> | --peers <node=addr,...>)
+                  [--state-machine <counter|kv>] [--wal-dir <dir>] [--snapshot-interval <n>] [--paused]
+
+  --role               what the node runs, server runs the replica, the leader and the acceptor with the ID
+  --id                 ID of the node within its role
+  --config             cluster config in TOML with the addresses of all the nodes and the options
+  --peers              addresses of every node of the cluster, e.g. replica:0=127.0.0.1:7000,leader:0=127.0.0.1:7001
+                       the roles of this node listen on their own addresses, clients have to be listed to get answers
+  --state-machine      application replicated by the replicas, counter by default
+  --wal-dir            directory for the write-ahead log of the acceptor, kept in memory only without it
+  --snapshot-interval  number of slots between two snapshots of the replica
+                       both override the config
+  --paused             wait for the control node to send the run signal instead of running right away
+
+The node runs until the control node sends it the exit signal, or until it is killed.";
+
+
+#[derive(Clone, Copy)]
+enum Role {
+    Acceptor,
+    Leader,
+    Replica,
+    Server,
+}
+
+struct Args {
+    role: Role,
+    id: u32,
+    peers: HashMap<NodeId, SocketAddr>,
+    cluster: ClusterSize,
+    state_machine: String,
+    options: SystemOptions,
+    paused: bool,
+}
+
+
+fn parse_args() -> Result<Args, String> {
+    let mut role = None;
+    let mut id = None;
+    let mut config = None;
+    let mut peers = None;
+    let mut state_machine = "counter".to_string();
+    let mut wal_dir = None;
+    let mut snapshot_interval = None;
+    let mut paused = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--role" => {
+                role = Some(match value()?.as_str() {
+                    "acceptor" => Role::Acceptor,
+                    "leader" => Role::Leader,
+                    "replica" => Role::Replica,
+                    "server" => Role::Server,
+                    other => return Err(format!("Unknown role {:?}", other)),
+                })
+            }
+            "--id" => id = Some(value()?.parse::<u32>().map_err(|err| format!("Invalid --id: {}", err))?),
+            "--config" => config = Some(PathBuf::from(value()?)),
+            "--peers" => peers = Some(paxos::parse_addrs(&value()?)?),
+            "--state-machine" => state_machine = value()?,
+            "--wal-dir" => wal_dir = Some(PathBuf::from(value()?)),
+            "--snapshot-interval" => {
+                snapshot_interval = Some(value()?.parse().map_err(|err| format!("Invalid --snapshot-interval: {}", err))?)
+            }
+            "--paused" => paused = true,
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            other => return Err(format!("Unknown argument {:?}", other)),
+        }
+    }
+
+    let (peers, cluster, mut options) = cluster_of(config.as_deref(), peers)?;
+    if wal_dir.is_some() {
+        options.acceptor_wal_dir = wal_dir;
+    }
+    if let Some(snapshot_interval) = snapshot_interval {
+        options.snapshot_interval = snapshot_interval;
+    }
+
+    Ok(Args {
+        role: role.ok_or("--role is required")?,
+        id: id.ok_or("--id is required")?,
+        peers,
+        cluster,
+        state_machine,
+        options,
+        paused,
+    })
+}
+
+
+
+// the addresses, the size and the options of the cluster, from the config or from the peer list
+fn cluster_of(
+    config: Option<&Path>,
+    peers: Option<HashMap<NodeId, SocketAddr>>,
+) -> Result<(HashMap<NodeId, SocketAddr>, ClusterSize, SystemOptions), String> {
+    match (config, peers) {
+        (Some(path), None) => {
+            let config = ClusterConfig::load(path).map_err(|err| err.to_string())?;
+            config.require_addresses().map_err(|err| err.to_string())?;
+            Ok((config.addrs, config.cluster, config.options))
+        }
+        (None, Some(peers)) => {
+            let ids: Vec<NodeId> = peers.keys().cloned().collect();
+            let cluster = ClusterSize::from_nodes(&ids).map_err(|node| format!("{} has no address in --peers", node))?;
+            Ok((peers, cluster, SystemOptions::default()))
+        }
+        (Some(_), Some(_)) => Err("--config and --peers don't go together".to_string()),
+        (None, None) => Err("--config or --peers is required".to_string()),
+    }
+}
+
+
+
+
+// starting every role of the node on a TCP endpoint of its own and waiting for all of them to exit
+fn run<S: StateMachine>(args: &Args, new_state_machine: fn() -> S, workload: Workload<S::Command>) -> Result<(), String>
+where
+    SystemMessage<S>: Encode + Decode,
+{
+    let nodes = match args.role {
+        Role::Acceptor => vec![NodeId::Acceptor(args.id)],
+        Role::Leader => vec![NodeId::Leader(args.id)],
+        Role::Replica => vec![NodeId::Replica(args.id)],
+        Role::Server => vec![NodeId::Replica(args.id), NodeId::Leader(args.id), NodeId::Acceptor(args.id)],
+    };
+
+    let mut threads = Vec::new();
+    for node in nodes {
+        if !args.peers.contains_key(&node) {
+            return Err(format!("{} is not in the cluster", node));
+        }
+        let endpoint = TcpTransport::<SystemMessage<S>>::bind(node, &args.peers)
+            .map_err(|err| format!("Can't listen for {} on {}: {}", node, args.peers[&node], err))?;
+        if !args.paused {
+            endpoint.send(node, Message::Control(ControlSignal::Run(0)));
+        }
+        println!("{} listening on {}", node, endpoint.get_listen_addr());
+        threads.push(paxos::start_node(node, Box::new(endpoint), args.cluster, &args.options, &new_state_machine, workload.clone()));
+    }
+
+    for thread in threads {
+        thread.join().map_err(|_| "A role of the node panicked".to_string())?;
+    }
+    Ok(())
+}
+
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, USAGE);
+        process::exit(2);
+    });
+
+    let outcome = match args.state_machine.as_str() {
+        "counter" => run(&args, Counter::default, Arc::new(counter_workload)),
+        "kv" => run(&args, KvStore::default, Arc::new(kv_workload)),
+        other => Err(format!("Unknown state machine {:?}", other)),
+    };
+    if let Err(err) = outcome {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
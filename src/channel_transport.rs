@@ -0,0 +1,64 @@
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::transport::{NodeId, Transport};
+
+
+// in-process transport, every node has an unbounded channel as its inbox
+// messages are never lost, except for the ones sent to a node that has exited
+pub struct ChannelTransport<M> {
+    // address of this endpoint
+    id: NodeId,
+
+    // receiving handle of the inbox of this node
+    inbox: Receiver<M>,
+
+    // sending handles of the inboxes of all the nodes, this one included
+    // shared by all the endpoints
+    inboxes: Arc<HashMap<NodeId, Sender<M>>>,
+}
+
+// constructing one endpoint per node, every endpoint can reach all the others
+pub fn construct<M>(nodes: &[NodeId]) -> HashMap<NodeId, ChannelTransport<M>> {
+    let mut senders: HashMap<NodeId, Sender<M>> = HashMap::new();
+    let mut receivers: Vec<(NodeId, Receiver<M>)> = Vec::new();
+
+    for node in nodes {
+        let (sender, receiver) = unbounded();
+        senders.insert(*node, sender);
+        receivers.push((*node, receiver));
+    }
+
+    let inboxes = Arc::new(senders);
+    receivers
+        .into_iter()
+        .map(|(id, inbox)| (id, ChannelTransport { id, inbox, inboxes: inboxes.clone() }))
+        .collect()
+}
+
+impl<M: Clone + Send> Transport<M> for ChannelTransport<M> {
+    fn get_id(&self) -> NodeId {
+        self.id
+    }
+
+    fn send(&self, to: NodeId, message: M) {
+        if let Some(inbox) = self.inboxes.get(&to) {
+            // a node that has exited has dropped its inbox, nothing to do about it
+            let _ = inbox.send(message);
+        }
+    }
+
+    fn recv(&self) -> Option<M> {
+        self.inbox.recv().ok()
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Option<M> {
+        self.inbox.recv_timeout(timeout).ok()
+    }
+
+    fn try_recv(&self) -> Option<M> {
+        self.inbox.try_recv().ok()
+    }
+}
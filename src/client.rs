@@ -1,9 +1,14 @@
-use crossbeam::channel::{Receiver, TryRecvError};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::broadcast_channel::BroadcastSender;
-use crate::utils::{Operation, Command, Request, Response};
+use crate::history::History;
+use crate::node::{self, Node};
+use crate::replica::SystemMessage;
+use crate::state_machine::StateMachine;
+use crate::transport::{self, NodeId, Transport};
+use crate::utils::{Operation, Command, Request, Response, ControlSignal, Message, ClientDone};
 
 enum OperatingState {
     Paused,
@@ -11,125 +16,182 @@ enum OperatingState {
     Exit,
 }
 
-#[derive(Clone)]
-pub enum ControlSignal {
-    Paused,
-    Run(u32),
-    Exit,
+// generates the command with the given command ID, shared by all clients
+pub type Workload<C> = Arc<dyn Fn(u32) -> C + Send + Sync>;
+
+
+
+
+// how a client gets a command through when requests or responses get lost
+// a request without response is sent again after retry_interval, the interval doubles up to max_retry_interval
+// every retry carries the same command ID, a replica answers it from its session if the command was executed already
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RetryPolicy {
+    pub retry_interval: Duration,
+    pub max_retry_interval: Duration,
+
+    // time from the first request after which the command is given up on
+    pub deadline: Duration,
 }
 
-pub struct Context {
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            retry_interval: Duration::from_millis(200),
+            max_retry_interval: Duration::from_secs(2),
+            deadline: Duration::from_secs(10),
+        }
+    }
+}
+
+// what to do about a command still waiting for its response
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RetryAction {
+    Wait,
+    Resend,
+    GiveUp,
+}
+
+// retry timer of one outstanding command
+#[derive(Debug, Clone)]
+pub struct Retransmission {
+    deadline: Instant,
+    next_send: Instant,
+    interval: Duration,
+}
+
+impl Retransmission {
+    // the first request goes out now
+    pub fn start(policy: &RetryPolicy, now: Instant) -> Retransmission {
+        Retransmission {
+            deadline: now + policy.deadline,
+            next_send: now + policy.retry_interval,
+            interval: policy.retry_interval,
+        }
+    }
+
+    // the next time the timer has to be looked at
+    pub fn wakeup(&self) -> Instant {
+        self.next_send.min(self.deadline)
+    }
+
+    // a Resend moves the timer on, the request has to go out again right away
+    pub fn poll(&mut self, policy: &RetryPolicy, now: Instant) -> RetryAction {
+        if now >= self.deadline {
+            RetryAction::GiveUp
+        } else if now >= self.next_send {
+            self.interval = (self.interval * 2).min(policy.max_retry_interval);
+            self.next_send = now + self.interval;
+            RetryAction::Resend
+        } else {
+            RetryAction::Wait
+        }
+    }
+}
+
+pub struct Context<S: StateMachine> {
     // ID of the client
     id: u32,
 
-    // handle to send broadcast message to replica
-    client_replica_broadcast_chan_sender: BroadcastSender<Request>,
-
-    // handle to the receiver handle for mpsc channel from all replicas
-    replica_client_mpsc_chan_receiver: Receiver<Response>,
+    // endpoint of the client, requests go out to the replicas and responses come back
+    // the control node is told here once the responses for all the commands have been received
+    transport: Box<dyn Transport<SystemMessage<S>>>,
 
-    // handle to receive control signals
-    control_chan_receiver: Receiver<ControlSignal>,
+    // all the replicas, every request is sent to them
+    replicas: Vec<NodeId>,
 
     // state of the replica
     operating_state: OperatingState,
 
-    // response commands IDs
-    response_command_ids: Vec<u32>,
+    // command ID of the next command to send
+    num_commands: u32,
+
+    // one more than the number of commands answered or given up on
+    num_responses: u32,
+
+    // commands sent and not answered yet, with their retry timers
+    outstanding: BTreeMap<u32, (Command<S::Command>, Retransmission)>,
+
+    // commands given up on since the last ClientDone
+    timed_out: Vec<u32>,
+
+    // commands sent by the client
+    workload: Workload<S::Command>,
+
+    retry_policy: RetryPolicy,
+
+    // where the client records when each command went out and when its result came back
+    history: Arc<History<S>>,
 }
 
-pub fn new(
+pub fn new<S: StateMachine>(
     id: u32,
-    client_replica_broadcast_chan_sender: BroadcastSender<Request>,
-    replica_client_mpsc_chan_receiver: Receiver<Response>,
-    control_chan_receiver: Receiver<ControlSignal>,
-) -> Context {
-    let context = Context {
+    transport: Box<dyn Transport<SystemMessage<S>>>,
+    num_replicas: u32,
+    workload: Workload<S::Command>,
+    retry_policy: RetryPolicy,
+    history: Arc<History<S>>,
+) -> Context<S> {
+    Context {
         id,
-        client_replica_broadcast_chan_sender,
-        replica_client_mpsc_chan_receiver,
-        control_chan_receiver,
+        transport,
+        replicas: transport::nodes(NodeId::Replica, num_replicas),
         operating_state: OperatingState::Paused,
-        response_command_ids: Vec::new(),
-    };
-
-    context
+        num_commands: 1u32,
+        num_responses: 1u32,
+        outstanding: BTreeMap::new(),
+        timed_out: Vec::new(),
+        workload,
+        retry_policy,
+        history,
+    }
 }
 
 
 
-impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
-            .spawn(move || {
-                let mut num_commands = 1u32;
-                let mut num_responses = 1u32;
-
-                loop {
-                                       
-                    match self.operating_state {
-                        OperatingState::Paused => {
-                            // println!("Client {} in paused mode", self.id);
-                            let signal = self.control_chan_receiver.recv().unwrap();
-                            // transition in operating state
-                            self.handle_control_signal(signal);
-                        }
-
-                        OperatingState::Run(num_msgs) => {
-                            // pattern matching the control channel messages
-                            match self.control_chan_receiver.try_recv() {
-                                Ok(signal) => {
-                                    println!("Client {} Not handled properly yet! Increase the grace time period!", self.id);
-                                    // transition in operating state
-                                    self.handle_control_signal(signal);
-                                }
-                                Err(TryRecvError::Empty) => {
-                                    if num_commands <= num_msgs {
-                                        self.send_broadcast_message(num_commands.clone());
-                                        num_commands += 1;
-                                    }
-                                    
-                                    if num_responses <= num_msgs {
-                                        num_responses = self.processing_response_message(num_responses.clone());
-                                    } else {
-                                        // println!("Client {} Going into paused state", self.id);
-                                        self.operating_state = OperatingState::Paused;
-                                    }
-                                }
-                                Err(TryRecvError::Disconnected) => {
-                                    panic!("Client control channel detached")
-                                }
-                            };
-                        }
-
-                        OperatingState::Exit => {
-                            println!("Client {} deactivated.......................", self.id);
-                            break;
-                        }
-                    }
-
-                    thread::sleep(Duration::from_nanos(100));
-                }
-            })
-            .unwrap();
+impl<S: StateMachine> Context<S> {
+    pub fn start(self) -> thread::JoinHandle<()> {
+        node::spawn(Box::new(self))
     }
 
 
 
-    fn send_broadcast_message(&self, num: u32) {
-        let mut operation = Operation::Null;
+    fn send_broadcast_message(&mut self, num: u32) {
+        let command = (self.workload)(num);
+        self.history.invoke(self.id, num, command.clone(), self.transport.now());
 
-        match num%4 {
-            0 => { operation = Operation::Add(1i32); }
-            1 => { operation = Operation::Subtract(1i32); }
-            2 => { operation = Operation::Multiply(2i32); }
-            _ => { operation = Operation::Add(1i32); }
-        }
+        // the commands before the oldest one still outstanding have all been answered or given up on
+        let acknowledged = self.outstanding.keys().next().map_or(num, |oldest| (*oldest).min(num));
+        let command = Command::create_with_acknowledged(self.id, num, acknowledged, Operation::Apply(command));
 
-        let command = Command::create( self.id.clone(), num, operation);
+        self.transport.broadcast(&self.replicas, Message::Request(Request::create(command.clone())));
+        self.outstanding.insert(num, (command, Retransmission::start(&self.retry_policy, self.transport.now())));
+    }
 
-        self.client_replica_broadcast_chan_sender
-            .send(Request::create(command));
+
+
+
+    // sending again the requests due for a retry, and giving up on the ones past their deadline
+    // a command given up on counts as answered, the control node learns about it from ClientDone
+    fn retransmit(&mut self) {
+        let now = self.transport.now();
+        let mut given_up = Vec::new();
+        for (command_id, (command, timer)) in self.outstanding.iter_mut() {
+            match timer.poll(&self.retry_policy, now) {
+                RetryAction::Wait => {}
+                RetryAction::Resend => {
+                    self.transport.broadcast(&self.replicas, Message::Request(Request::create(command.clone())));
+                }
+                RetryAction::GiveUp => given_up.push(*command_id),
+            }
+        }
+
+        for command_id in given_up {
+            println!("Command with command ID {} at client {} timed out", command_id, self.id);
+            self.outstanding.remove(&command_id);
+            self.timed_out.push(command_id);
+            self.num_responses += 1;
+        }
     }
 
 
@@ -137,22 +199,20 @@ impl Context {
 
 
     // function for handling response message coming from the replicas
-    fn processing_response_message(&mut self, mut num_responses: u32) -> u32 {
-
-        if let Ok(response) = &self.replica_client_mpsc_chan_receiver.try_recv() {
-            if self.response_command_ids.contains(&response.get_command_id()) == false {
-                println!("Result for command with command ID {} at client {} is: {}", 
-                        response.get_command_id(), 
-                        self.id,
-                        response.get_result());
-
-                // update num_responses only it is a new command
-                self.response_command_ids.push(response.get_command_id().clone());
-                num_responses += 1;
-            }
+    fn processing_response_message(&mut self, response: Response<S::Result>) {
+
+        // every replica answers, only the first response for a command counts
+        // a response to a command given up on is dropped, it has been counted already
+        if self.outstanding.remove(&response.get_command_id()).is_some() {
+            self.history.complete(self.id, response.get_command_id(), response.get_result(), self.transport.now());
+            println!("Result for command with command ID {} at client {} is: {:?}", 
+                    response.get_command_id(), 
+                    self.id,
+                    response.get_result());
+
+            // update num_responses only it is a new command
+            self.num_responses += 1;
         }
-
-        num_responses
     } 
 
 
@@ -173,8 +233,74 @@ impl Context {
 
             ControlSignal::Exit => {
                 // println!("Exit signal at Client {} received", self.id);
+                println!("Client {} deactivated.......................", self.id);
                 self.operating_state = OperatingState::Exit;
             }
+
+            ControlSignal::Crash | ControlSignal::Restart | ControlSignal::Wakeup => {}
+        }
+    }
+}
+
+
+
+
+impl<S: StateMachine> Node<S> for Context<S> {
+    fn get_id(&self) -> NodeId {
+        NodeId::Client(self.id)
+    }
+
+    fn transport(&self) -> &dyn Transport<SystemMessage<S>> {
+        self.transport.as_ref()
+    }
+
+    fn handle_message(&mut self, message: SystemMessage<S>) {
+        match (&self.operating_state, message) {
+            // the clients stand for the users of the system, they are not crashed
+            // and they wake up on their own timers
+            (_, Message::Control(ControlSignal::Crash | ControlSignal::Restart | ControlSignal::Wakeup)) => {}
+            (OperatingState::Run(_), Message::Control(signal)) => {
+                println!("Client {} stopped before all its commands were answered", self.id);
+                // transition in operating state
+                self.handle_control_signal(signal);
+            }
+            (_, Message::Control(signal)) => self.handle_control_signal(signal),
+            (OperatingState::Run(_), Message::Response(response)) => self.processing_response_message(response),
+            // late responses for answered commands are dropped while paused, nothing else is meant for a client
+            _ => {}
+        }
+    }
+
+    // sending the next command, then the retries
+    // the control node is told once every command has been answered or given up on
+    fn handle_timers(&mut self) {
+        if let OperatingState::Run(num_msgs) = self.operating_state {
+            if self.num_commands <= num_msgs {
+                self.send_broadcast_message(self.num_commands);
+                self.num_commands += 1;
+            }
+
+            self.retransmit();
+
+            if self.num_responses > num_msgs {
+                // println!("Client {} Going into paused state", self.id);
+                let timed_out = std::mem::take(&mut self.timed_out);
+                self.transport.send(NodeId::Control, Message::ClientDone(ClientDone::create(self.id, timed_out)));
+                self.operating_state = OperatingState::Paused;
+            }
         }
     }
+
+    // right away while commands are waiting to go out, once all of them are out only for the retries
+    fn next_wakeup(&self) -> Option<Instant> {
+        match self.operating_state {
+            OperatingState::Run(num_msgs) if self.num_commands <= num_msgs => Some(self.transport.now()),
+            OperatingState::Run(_) => self.outstanding.values().map(|(_, timer)| timer.wakeup()).min(),
+            _ => None,
+        }
+    }
+
+    fn has_exited(&self) -> bool {
+        matches!(self.operating_state, OperatingState::Exit)
+    }
 }
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::{RetryPolicy, RetryAction, Retransmission};
+use crate::history::History;
+use crate::replica::{SystemMessage, SystemTransport};
+use crate::state_machine::StateMachine;
+use crate::transport::{self, NodeId, Transport};
+use crate::utils::{Operation, Command, Request, ControlSignal, Message};
+
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    // when a request is sent again, and when the submission gives up on its result
+    pub retry: RetryPolicy,
+
+    // command ID of the first command submitted, the following ones count up from it
+    // the replicas answer an ID they have seen before from their cache, so a client ID used again has to start past its last command
+    pub first_command_id: u32,
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions { retry: RetryPolicy::default(), first_command_id: 1 }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ClientError {
+    // no replica answered before the deadline, the command may still get decided later
+    Timeout { command_id: u32 },
+
+    // the handle has stopped, after an exit signal from the control node
+    Closed,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Timeout { command_id } => write!(f, "command {} not answered in time", command_id),
+            ClientError::Closed => write!(f, "client closed"),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+
+
+
+// client for applications embedding the system, in place of the client threads running a workload
+// submit blocks until the result of the command is in, submit_async returns a future of it
+// the handle can be shared between threads, any number of commands can be outstanding
+pub struct ClientHandle<S: StateMachine> {
+    shared: Arc<Shared<S>>,
+
+    // thread taking the responses off the transport and completing the submissions
+    dispatcher: Option<thread::JoinHandle<()>>,
+}
+
+struct Shared<S: StateMachine> {
+    // ID of the client, the commands carry it
+    id: u32,
+
+    // endpoint of the client, the submitting threads send on it and the dispatcher receives on it
+    transport: Arc<dyn Transport<SystemMessage<S>>>,
+
+    // all the replicas, every request is sent to them
+    replicas: Vec<NodeId>,
+
+    retry: RetryPolicy,
+
+    // command ID for the next submission
+    next_command_id: AtomicU32,
+
+    // submissions waiting for a response, by command ID
+    // None once the dispatcher has stopped
+    pending: Mutex<Option<HashMap<u32, Pending<S>>>>,
+
+    // where every submission and its result are recorded
+    history: Arc<History<S>>,
+}
+
+struct Pending<S: StateMachine> {
+    // sent again on every retry, with the same command ID
+    request: Request<S::Command>,
+    timer: Retransmission,
+    slot: Arc<Slot<S::Result>>,
+}
+
+// where the dispatcher leaves the outcome of a submission
+struct Slot<R> {
+    state: Mutex<SlotState<R>>,
+    // for blocking submissions
+    done: Condvar,
+}
+
+struct SlotState<R> {
+    outcome: Option<Result<R, ClientError>>,
+    // for async submissions, the task to wake once the outcome is in
+    waker: Option<Waker>,
+}
+
+impl<R> Slot<R> {
+    fn new() -> Slot<R> {
+        Slot { state: Mutex::new(SlotState { outcome: None, waker: None }), done: Condvar::new() }
+    }
+
+    fn complete(&self, outcome: Result<R, ClientError>) {
+        let mut state = self.state.lock().unwrap();
+        state.outcome = Some(outcome);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        self.done.notify_all();
+    }
+
+    fn wait(&self) -> Result<R, ClientError> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(outcome) = state.outcome.take() {
+                return outcome;
+            }
+            state = self.done.wait(state).unwrap();
+        }
+    }
+}
+
+
+
+
+impl<S: StateMachine> ClientHandle<S> {
+    // the transport is the endpoint of Client(id), the handle is ready right away
+    // it needs no run signal, the exit signal of the control node stops it
+    pub fn new(id: u32, transport: SystemTransport<S>, num_replicas: u32, options: ClientOptions) -> ClientHandle<S> {
+        ClientHandle::with_history(id, transport, num_replicas, options, Arc::new(History::new()))
+    }
+
+    // recording the commands into a history shared with other clients, e.g. the one of a SystemHandles
+    pub fn with_history(
+        id: u32,
+        transport: SystemTransport<S>,
+        num_replicas: u32,
+        options: ClientOptions,
+        history: Arc<History<S>>,
+    ) -> ClientHandle<S> {
+        let shared = Arc::new(Shared {
+            id,
+            transport: Arc::from(transport),
+            replicas: transport::nodes(NodeId::Replica, num_replicas),
+            retry: options.retry,
+            next_command_id: AtomicU32::new(options.first_command_id),
+            pending: Mutex::new(Some(HashMap::new())),
+            history,
+        });
+
+        let dispatcher_shared = shared.clone();
+        let dispatcher = thread::Builder::new()
+            .spawn(move || dispatcher_shared.dispatch())
+            .unwrap();
+
+        ClientHandle { shared, dispatcher: Some(dispatcher) }
+    }
+
+    pub fn get_id(&self) -> u32 {
+        self.shared.id
+    }
+
+    // the commands submitted through the handle, and the ones of the clients sharing its history
+    pub fn get_history(&self) -> &Arc<History<S>> {
+        &self.shared.history
+    }
+
+    // submit the command and wait for its result
+    pub fn submit(&self, command: S::Command) -> Result<S::Result, ClientError> {
+        self.shared.submit(command)?.wait()
+    }
+
+    // submit the command, the future resolves to its result
+    // the command goes out right away, not on the first poll
+    pub fn submit_async(&self, command: S::Command) -> Submission<S::Result> {
+        match self.shared.submit(command) {
+            Ok(slot) => Submission { slot: Some(slot), error: None },
+            Err(error) => Submission { slot: None, error: Some(error) },
+        }
+    }
+}
+
+impl<S: StateMachine> Drop for ClientHandle<S> {
+    fn drop(&mut self) {
+        // the dispatcher stops on an exit signal, from the control node or from here
+        self.shared.transport.send(NodeId::Client(self.shared.id), Message::Control(ControlSignal::Exit));
+        if let Some(dispatcher) = self.dispatcher.take() {
+            let _ = dispatcher.join();
+        }
+    }
+}
+
+
+
+
+impl<S: StateMachine> Shared<S> {
+    fn submit(&self, command: S::Command) -> Result<Arc<Slot<S::Result>>, ClientError> {
+        let slot = Arc::new(Slot::new());
+
+        // the ID is handed out under the lock, so no lower ID can still be on its way into pending
+        let request = match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => {
+                let command_id = self.next_command_id.fetch_add(1, Ordering::SeqCst);
+                // the commands before the oldest one pending have all been answered or given up on
+                let acknowledged = pending.keys().min().map_or(command_id, |oldest| (*oldest).min(command_id));
+                let request = Request::create(Command::create_with_acknowledged(self.id, command_id, acknowledged, Operation::Apply(command.clone())));
+                let now = Instant::now();
+                self.history.invoke(self.id, command_id, command, now);
+                let timer = Retransmission::start(&self.retry, now);
+                pending.insert(command_id, Pending { request: request.clone(), timer, slot: slot.clone() });
+                request
+            }
+            None => return Err(ClientError::Closed),
+        };
+
+        self.transport.broadcast(&self.replicas, Message::Request(request));
+        // the dispatcher may be waiting without a timer, it has to look at the new one
+        self.transport.send(NodeId::Client(self.id), Message::Control(ControlSignal::Wakeup));
+        Ok(slot)
+    }
+
+    // matching the responses to the submissions until the exit signal, and retrying the requests not answered
+    // every replica answers, only the first response for a command counts
+    fn dispatch(&self) {
+        loop {
+            let wakeup = self.pending.lock().unwrap().as_ref().and_then(|pending| {
+                pending.values().map(|entry| entry.timer.wakeup()).min()
+            });
+            let message = match wakeup {
+                Some(wakeup) => self.transport.recv_timeout(wakeup.saturating_duration_since(Instant::now())),
+                None => self.transport.recv(),
+            };
+
+            match message {
+                Some(Message::Response(response)) => {
+                    let entry = self.pending.lock().unwrap().as_mut().and_then(|pending| pending.remove(&response.get_command_id()));
+                    if let Some(entry) = entry {
+                        self.history.complete(self.id, response.get_command_id(), response.get_result(), Instant::now());
+                        entry.slot.complete(Ok(response.get_result()));
+                    }
+                }
+                Some(Message::Control(ControlSignal::Exit)) => break,
+                // the other signals only make the dispatcher look at the timers again
+                _ => {}
+            }
+
+            // sending again the requests due for a retry, timing out the submissions past their deadline
+            let now = Instant::now();
+            let mut resend = Vec::new();
+            let mut expired = Vec::new();
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.retain(|command_id, entry| match entry.timer.poll(&self.retry, now) {
+                    RetryAction::Wait => true,
+                    RetryAction::Resend => {
+                        resend.push(entry.request.clone());
+                        true
+                    }
+                    RetryAction::GiveUp => {
+                        expired.push((*command_id, entry.slot.clone()));
+                        false
+                    }
+                });
+            }
+            for request in resend {
+                self.transport.broadcast(&self.replicas, Message::Request(request));
+            }
+            for (command_id, slot) in expired {
+                slot.complete(Err(ClientError::Timeout { command_id }));
+            }
+        }
+
+        // no response is coming anymore
+        let pending = self.pending.lock().unwrap().take().unwrap_or_default();
+        for entry in pending.into_values() {
+            entry.slot.complete(Err(ClientError::Closed));
+        }
+    }
+}
+
+
+
+
+// result of a command submitted with submit_async
+pub struct Submission<R> {
+    slot: Option<Arc<Slot<R>>>,
+    // the submission failed before the command went out
+    error: Option<ClientError>,
+}
+
+impl<R> Future for Submission<R> {
+    type Output = Result<R, ClientError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<Result<R, ClientError>> {
+        if let Some(error) = self.error.take() {
+            return Poll::Ready(Err(error));
+        }
+        let slot = self.slot.as_ref().expect("Submission polled after completion");
+        let outcome = {
+            let mut state = slot.state.lock().unwrap();
+            match state.outcome.take() {
+                Some(outcome) => outcome,
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        };
+        // the slot is given up with the outcome, polling again is a bug of the caller
+        self.slot = None;
+        Poll::Ready(outcome)
+    }
+}
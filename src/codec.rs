@@ -0,0 +1,679 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::utils::{
+    Operation, Command, Ballot, Pvalue, ControlSignal, Message, Request, Response, Propose, Decision,
+    P1a, P1b, P2a, P2b, Heartbeat, ClientDone, CatchUpRequest, CatchUpReply, CatchUpMessage,
+};
+
+
+// binary encoding of the values that have to outlive the process or go over the network, integers in little endian
+pub trait Encode {
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+// decoding counterpart of Encode, an error when the bytes run out or don't make sense
+// never panics, whatever the bytes
+pub trait Decode: Sized {
+    fn decode(cursor: &mut Cursor) -> Result<Self, DecodeError>;
+}
+
+
+// version of the encoding, the first byte of every message sent or stored with to_bytes
+// bumped whenever the encoding of any value changes
+pub const WIRE_VERSION: u8 = 3;
+
+// why some bytes couldn't be decoded, offsets are from the start of the bytes given to the cursor
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    // the bytes ended in the middle of a value
+    UnexpectedEnd { offset: usize },
+
+    // an enum tag that none of the variants of the type has
+    InvalidTag { type_name: &'static str, tag: u8, offset: usize },
+
+    // a string that isn't UTF-8
+    InvalidUtf8 { offset: usize },
+
+    // written with a version of the encoding this one doesn't know
+    UnsupportedVersion(u8),
+
+    // bytes left over after the value
+    TrailingBytes { offset: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd { offset } => write!(f, "unexpected end of input at byte {}", offset),
+            DecodeError::InvalidTag { type_name, tag, offset } => {
+                write!(f, "invalid tag {} for {} at byte {}", tag, type_name, offset)
+            }
+            DecodeError::InvalidUtf8 { offset } => write!(f, "invalid UTF-8 in the string at byte {}", offset),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported encoding version {}, expected {}", version, WIRE_VERSION)
+            }
+            DecodeError::TrailingBytes { offset } => write!(f, "trailing bytes after the value at byte {}", offset),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+
+// the encoding of value behind the version byte
+pub fn to_bytes<T: Encode>(value: &T) -> Vec<u8> {
+    let mut buf = vec![WIRE_VERSION];
+    value.encode(&mut buf);
+    buf
+}
+
+// decoding bytes written by to_bytes, they must hold exactly one value
+pub fn from_bytes<T: Decode>(bytes: &[u8]) -> Result<T, DecodeError> {
+    let mut cursor = Cursor::new(bytes);
+    let version = cursor.get_u8()?;
+    if version != WIRE_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let value = T::decode(&mut cursor)?;
+    if cursor.get_offset() != bytes.len() {
+        return Err(DecodeError::TrailingBytes { offset: cursor.get_offset() });
+    }
+    Ok(value)
+}
+
+
+
+
+// read position in a byte slice
+pub struct Cursor<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(bytes: &'a [u8]) -> Cursor<'a> {
+        Cursor { bytes, offset: 0 }
+    }
+
+    pub fn get_offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, DecodeError> {
+        let raw = self.get_bytes(1)?;
+        Ok(raw[0])
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, DecodeError> {
+        let raw = self.get_bytes(4)?;
+        Ok(u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]))
+    }
+
+    pub fn get_bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let raw = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .ok_or(DecodeError::UnexpectedEnd { offset: self.bytes.len() })?;
+        self.offset += len;
+        Ok(raw)
+    }
+
+    // error for the tag just read, for the enums with no variant of that tag
+    pub fn invalid_tag<T>(&self, type_name: &'static str, tag: u8) -> Result<T, DecodeError> {
+        Err(DecodeError::InvalidTag { type_name, tag, offset: self.offset - 1 })
+    }
+}
+
+
+
+
+impl Encode for u32 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decode for u32 {
+    fn decode(cursor: &mut Cursor) -> Result<u32, DecodeError> {
+        cursor.get_u32()
+    }
+}
+
+
+impl Encode for i32 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decode for i32 {
+    fn decode(cursor: &mut Cursor) -> Result<i32, DecodeError> {
+        cursor.get_u32().map(|value| value as i32)
+    }
+}
+
+
+// a borrowed value is encoded like the value itself
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (**self).encode(buf);
+    }
+}
+
+
+// a slice is encoded like a Vec and decoded as one
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).encode(buf);
+        for elem in self {
+            elem.encode(buf);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_slice().encode(buf);
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(cursor: &mut Cursor) -> Result<Vec<T>, DecodeError> {
+        let len = cursor.get_u32()?;
+        let mut elems = Vec::new();
+        for _ in 0..len {
+            elems.push(T::decode(cursor)?);
+        }
+        Ok(elems)
+    }
+}
+
+
+
+impl Encode for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).encode(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode(cursor: &mut Cursor) -> Result<String, DecodeError> {
+        let len = cursor.get_u32()? as usize;
+        let offset = cursor.get_offset();
+        let raw = cursor.get_bytes(len)?;
+        String::from_utf8(raw.to_vec()).map_err(|_| DecodeError::InvalidUtf8 { offset })
+    }
+}
+
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+        self.1.encode(buf);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode(cursor: &mut Cursor) -> Result<(A, B), DecodeError> {
+        Ok((A::decode(cursor)?, B::decode(cursor)?))
+    }
+}
+
+
+// entries in key order
+impl<K: Encode, V: Encode> Encode for BTreeMap<K, V> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).encode(buf);
+        for (key, value) in self {
+            key.encode(buf);
+            value.encode(buf);
+        }
+    }
+}
+
+impl<K: Decode + Ord, V: Decode> Decode for BTreeMap<K, V> {
+    fn decode(cursor: &mut Cursor) -> Result<BTreeMap<K, V>, DecodeError> {
+        let len = cursor.get_u32()?;
+        let mut map = BTreeMap::new();
+        for _ in 0..len {
+            let key = K::decode(cursor)?;
+            map.insert(key, V::decode(cursor)?);
+        }
+        Ok(map)
+    }
+}
+
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            None => buf.push(0),
+            Some(value) => {
+                buf.push(1);
+                value.encode(buf);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(cursor: &mut Cursor) -> Result<Option<T>, DecodeError> {
+        match cursor.get_u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(cursor)?)),
+            tag => cursor.invalid_tag("Option", tag),
+        }
+    }
+}
+
+
+
+impl<T: Encode, E: Encode> Encode for Result<T, E> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Ok(value) => {
+                buf.push(0);
+                value.encode(buf);
+            }
+            Err(err) => {
+                buf.push(1);
+                err.encode(buf);
+            }
+        }
+    }
+}
+
+impl<T: Decode, E: Decode> Decode for Result<T, E> {
+    fn decode(cursor: &mut Cursor) -> Result<Result<T, E>, DecodeError> {
+        match cursor.get_u8()? {
+            0 => Ok(Ok(T::decode(cursor)?)),
+            1 => Ok(Err(E::decode(cursor)?)),
+            tag => cursor.invalid_tag("Result", tag),
+        }
+    }
+}
+
+
+
+impl Encode for Ballot {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_count().encode(buf);
+        self.get_leader_id().encode(buf);
+    }
+}
+
+impl Decode for Ballot {
+    fn decode(cursor: &mut Cursor) -> Result<Ballot, DecodeError> {
+        let count = cursor.get_u32()?;
+        let leader_id = cursor.get_u32()?;
+        Ok(Ballot::create_with_count(count, leader_id))
+    }
+}
+
+
+impl<C: Encode> Encode for Operation<C> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Operation::Null => buf.push(0),
+            Operation::Apply(command) => {
+                buf.push(1);
+                command.encode(buf);
+            }
+            Operation::Reconfig(leaders) => {
+                buf.push(2);
+                leaders.encode(buf);
+            }
+        }
+    }
+}
+
+impl<C: Decode> Decode for Operation<C> {
+    fn decode(cursor: &mut Cursor) -> Result<Operation<C>, DecodeError> {
+        match cursor.get_u8()? {
+            0 => Ok(Operation::Null),
+            1 => Ok(Operation::Apply(C::decode(cursor)?)),
+            2 => Ok(Operation::Reconfig(Vec::decode(cursor)?)),
+            tag => cursor.invalid_tag("Operation", tag),
+        }
+    }
+}
+
+
+impl<C: Encode + Clone> Encode for Command<C> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_client_id().encode(buf);
+        self.get_command_id().encode(buf);
+        self.get_acknowledged().encode(buf);
+        self.get_operation().encode(buf);
+    }
+}
+
+impl<C: Decode + Clone> Decode for Command<C> {
+    fn decode(cursor: &mut Cursor) -> Result<Command<C>, DecodeError> {
+        let client_id = cursor.get_u32()?;
+        let command_id = cursor.get_u32()?;
+        let acknowledged = cursor.get_u32()?;
+        let operation = Operation::decode(cursor)?;
+        Ok(Command::create_with_acknowledged(client_id, command_id, acknowledged, operation))
+    }
+}
+
+
+impl<C: Encode + Clone> Encode for Pvalue<C> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_ballot_num().encode(buf);
+        self.get_slot().encode(buf);
+        self.get_command().encode(buf);
+    }
+}
+
+impl<C: Decode + Clone> Decode for Pvalue<C> {
+    fn decode(cursor: &mut Cursor) -> Result<Pvalue<C>, DecodeError> {
+        let ballot = Ballot::decode(cursor)?;
+        let slot = cursor.get_u32()?;
+        let command = Command::decode(cursor)?;
+        Ok(Pvalue::create(ballot, slot, command))
+    }
+}
+
+
+
+
+// the messages between the nodes
+// every enum starts with a tag byte, the fields follow in declaration order
+
+impl Encode for ControlSignal {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            ControlSignal::Paused => buf.push(0),
+            ControlSignal::Run(num_msgs) => {
+                buf.push(1);
+                num_msgs.encode(buf);
+            }
+            ControlSignal::Exit => buf.push(2),
+            ControlSignal::Crash => buf.push(3),
+            ControlSignal::Restart => buf.push(4),
+            ControlSignal::Wakeup => buf.push(5),
+        }
+    }
+}
+
+impl Decode for ControlSignal {
+    fn decode(cursor: &mut Cursor) -> Result<ControlSignal, DecodeError> {
+        match cursor.get_u8()? {
+            0 => Ok(ControlSignal::Paused),
+            1 => Ok(ControlSignal::Run(cursor.get_u32()?)),
+            2 => Ok(ControlSignal::Exit),
+            3 => Ok(ControlSignal::Crash),
+            4 => Ok(ControlSignal::Restart),
+            5 => Ok(ControlSignal::Wakeup),
+            tag => cursor.invalid_tag("ControlSignal", tag),
+        }
+    }
+}
+
+
+impl<C: Encode + Clone> Encode for Request<C> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_command().encode(buf);
+    }
+}
+
+impl<C: Decode + Clone> Decode for Request<C> {
+    fn decode(cursor: &mut Cursor) -> Result<Request<C>, DecodeError> {
+        Ok(Request::create(Command::decode(cursor)?))
+    }
+}
+
+
+impl<R: Encode + Clone> Encode for Response<R> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_command_id().encode(buf);
+        self.get_result().encode(buf);
+    }
+}
+
+impl<R: Decode + Clone> Decode for Response<R> {
+    fn decode(cursor: &mut Cursor) -> Result<Response<R>, DecodeError> {
+        let command_id = cursor.get_u32()?;
+        Ok(Response::create(command_id, R::decode(cursor)?))
+    }
+}
+
+
+impl Encode for ClientDone {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_client_id().encode(buf);
+        self.get_timed_out().encode(buf);
+    }
+}
+
+impl Decode for ClientDone {
+    fn decode(cursor: &mut Cursor) -> Result<ClientDone, DecodeError> {
+        let client_id = cursor.get_u32()?;
+        Ok(ClientDone::create(client_id, Vec::decode(cursor)?))
+    }
+}
+
+
+impl<C: Encode + Clone> Encode for Propose<C> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_replica_id().encode(buf);
+        self.get_slot().encode(buf);
+        self.get_command().encode(buf);
+    }
+}
+
+impl<C: Decode + Clone> Decode for Propose<C> {
+    fn decode(cursor: &mut Cursor) -> Result<Propose<C>, DecodeError> {
+        let replica_id = cursor.get_u32()?;
+        let slot = cursor.get_u32()?;
+        Ok(Propose::create(replica_id, slot, Command::decode(cursor)?))
+    }
+}
+
+
+impl<C: Encode + Clone> Encode for Decision<C> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_slot().encode(buf);
+        self.get_command().encode(buf);
+    }
+}
+
+impl<C: Decode + Clone> Decode for Decision<C> {
+    fn decode(cursor: &mut Cursor) -> Result<Decision<C>, DecodeError> {
+        let slot = cursor.get_u32()?;
+        Ok(Decision::create(slot, Command::decode(cursor)?))
+    }
+}
+
+
+impl Encode for P1a {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_leader_id().encode(buf);
+        self.get_ballot_num().encode(buf);
+        self.get_scout_id().encode(buf);
+        self.get_slot_watermark().encode(buf);
+    }
+}
+
+impl Decode for P1a {
+    fn decode(cursor: &mut Cursor) -> Result<P1a, DecodeError> {
+        let leader_id = cursor.get_u32()?;
+        let ballot = Ballot::decode(cursor)?;
+        let scout_id = cursor.get_u32()?;
+        let slot_watermark = cursor.get_u32()?;
+        Ok(P1a::create(leader_id, ballot, scout_id, slot_watermark))
+    }
+}
+
+
+impl<C: Encode + Clone> Encode for P1b<C> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_acceptor_id().encode(buf);
+        self.get_ballot().encode(buf);
+        self.get_pvalues().encode(buf);
+        (self.get_scout_id() as u32).encode(buf);
+    }
+}
+
+impl<C: Decode + Clone> Decode for P1b<C> {
+    fn decode(cursor: &mut Cursor) -> Result<P1b<C>, DecodeError> {
+        let acceptor_id = cursor.get_u32()?;
+        let ballot = Ballot::decode(cursor)?;
+        let accepted = Vec::decode(cursor)?;
+        let scout_id = cursor.get_u32()?;
+        Ok(P1b::create(acceptor_id, ballot, accepted, scout_id))
+    }
+}
+
+
+impl<C: Encode + Clone> Encode for P2a<C> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_leader_id().encode(buf);
+        self.get_pvalue().encode(buf);
+        self.get_commander_id().encode(buf);
+    }
+}
+
+impl<C: Decode + Clone> Decode for P2a<C> {
+    fn decode(cursor: &mut Cursor) -> Result<P2a<C>, DecodeError> {
+        let leader_id = cursor.get_u32()?;
+        let pvalue = Pvalue::decode(cursor)?;
+        let commander_id = cursor.get_u32()?;
+        Ok(P2a::create(leader_id, pvalue, commander_id))
+    }
+}
+
+
+impl Encode for P2b {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_acceptor_id().encode(buf);
+        self.get_ballot().encode(buf);
+        (self.get_commander_id() as u32).encode(buf);
+    }
+}
+
+impl Decode for P2b {
+    fn decode(cursor: &mut Cursor) -> Result<P2b, DecodeError> {
+        let acceptor_id = cursor.get_u32()?;
+        let ballot = Ballot::decode(cursor)?;
+        let commander_id = cursor.get_u32()?;
+        Ok(P2b::create(acceptor_id, ballot, commander_id))
+    }
+}
+
+
+impl Encode for Heartbeat {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_leader_id().encode(buf);
+    }
+}
+
+impl Decode for Heartbeat {
+    fn decode(cursor: &mut Cursor) -> Result<Heartbeat, DecodeError> {
+        Ok(Heartbeat::create(cursor.get_u32()?))
+    }
+}
+
+
+impl Encode for CatchUpRequest {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_replica_id().encode(buf);
+        self.get_slot().encode(buf);
+    }
+}
+
+impl Decode for CatchUpRequest {
+    fn decode(cursor: &mut Cursor) -> Result<CatchUpRequest, DecodeError> {
+        let replica_id = cursor.get_u32()?;
+        let slot = cursor.get_u32()?;
+        Ok(CatchUpRequest::create(replica_id, slot))
+    }
+}
+
+
+impl<C: Encode + Clone, P: Encode + Clone> Encode for CatchUpReply<C, P> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.get_snapshot().encode(buf);
+        self.get_decisions().encode(buf);
+    }
+}
+
+impl<C: Decode + Clone, P: Decode + Clone> Decode for CatchUpReply<C, P> {
+    fn decode(cursor: &mut Cursor) -> Result<CatchUpReply<C, P>, DecodeError> {
+        let snapshot = Option::decode(cursor)?;
+        let decisions = Vec::decode(cursor)?;
+        Ok(CatchUpReply::create(snapshot, decisions))
+    }
+}
+
+
+impl<C: Encode + Clone, P: Encode + Clone> Encode for CatchUpMessage<C, P> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            CatchUpMessage::Request(request) => {
+                buf.push(0);
+                request.encode(buf);
+            }
+            CatchUpMessage::Reply(reply) => {
+                buf.push(1);
+                reply.encode(buf);
+            }
+        }
+    }
+}
+
+impl<C: Decode + Clone, P: Decode + Clone> Decode for CatchUpMessage<C, P> {
+    fn decode(cursor: &mut Cursor) -> Result<CatchUpMessage<C, P>, DecodeError> {
+        match cursor.get_u8()? {
+            0 => Ok(CatchUpMessage::Request(CatchUpRequest::decode(cursor)?)),
+            1 => Ok(CatchUpMessage::Reply(CatchUpReply::decode(cursor)?)),
+            tag => cursor.invalid_tag("CatchUpMessage", tag),
+        }
+    }
+}
+
+
+impl<C: Encode + Clone, R: Encode + Clone, P: Encode + Clone> Encode for Message<C, R, P> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Message::Control(signal) => { buf.push(0); signal.encode(buf); }
+            Message::Request(request) => { buf.push(1); request.encode(buf); }
+            Message::Response(response) => { buf.push(2); response.encode(buf); }
+            Message::Propose(propose) => { buf.push(3); propose.encode(buf); }
+            Message::Decision(decision) => { buf.push(4); decision.encode(buf); }
+            Message::P1a(p1a) => { buf.push(5); p1a.encode(buf); }
+            Message::P1b(p1b) => { buf.push(6); p1b.encode(buf); }
+            Message::P2a(p2a) => { buf.push(7); p2a.encode(buf); }
+            Message::P2b(p2b) => { buf.push(8); p2b.encode(buf); }
+            Message::Heartbeat(heartbeat) => { buf.push(9); heartbeat.encode(buf); }
+            Message::CatchUp(catch_up) => { buf.push(10); catch_up.encode(buf); }
+            Message::ClientDone(done) => { buf.push(11); done.encode(buf); }
+        }
+    }
+}
+
+impl<C: Decode + Clone, R: Decode + Clone, P: Decode + Clone> Decode for Message<C, R, P> {
+    fn decode(cursor: &mut Cursor) -> Result<Message<C, R, P>, DecodeError> {
+        match cursor.get_u8()? {
+            0 => Ok(Message::Control(ControlSignal::decode(cursor)?)),
+            1 => Ok(Message::Request(Request::decode(cursor)?)),
+            2 => Ok(Message::Response(Response::decode(cursor)?)),
+            3 => Ok(Message::Propose(Propose::decode(cursor)?)),
+            4 => Ok(Message::Decision(Decision::decode(cursor)?)),
+            5 => Ok(Message::P1a(P1a::decode(cursor)?)),
+            6 => Ok(Message::P1b(P1b::decode(cursor)?)),
+            7 => Ok(Message::P2a(P2a::decode(cursor)?)),
+            8 => Ok(Message::P2b(P2b::decode(cursor)?)),
+            9 => Ok(Message::Heartbeat(Heartbeat::decode(cursor)?)),
+            10 => Ok(Message::CatchUp(CatchUpMessage::decode(cursor)?)),
+            11 => Ok(Message::ClientDone(ClientDone::decode(cursor)?)),
+            tag => cursor.invalid_tag("Message", tag),
+        }
+    }
+}
@@ -1,15 +1,11 @@
-use crossbeam::channel::{Sender, Receiver, TryRecvError};
-use std::collections::VecDeque;
-use std::thread;
-use std::time::Duration;
-use std::collections::HashMap;
+use crate::transport::{NodeId, Transport};
+use crate::utils::{Command, Decision, Ballot, P2a, P2b, Preempted, CommanderMessage, Pvalue, Payload, Message};
 
-use crate::broadcast_channel::BroadcastSender;
-use crate::utils::{Operation, Command, Decision, Ballot, P1a, P1b, P2a, P2b, Adopted, Preempted, ScoutMessage, Pvalue};
 
 
-
-pub struct Context {
+// phase 2 of a ballot for a single slot, driven by the leader
+// the leader routes the P2b for this commander to handle_p2b until the commander has an outcome
+pub struct Context<C: Payload> {
 
     // Id of the commander
     commander_id: u32,
@@ -17,110 +13,96 @@ pub struct Context {
     // Id of the leader
     leader_id: u32,
 
-    // sending handle of the broadcast channel from commander to replica
-    leader_replica_broadcast_chan_sender: BroadcastSender<Decision>,
-
-    // broadcast handle to all acceptors
-    commander_acceptor_broadcast_chan_sender: BroadcastSender<P2a>,
-
-    // receiving handle of the channels from the leader to the commander
-    // for receiving P2b
-    leader_commander_receiver: Receiver<P2b>,
-
-    // sending handle of the channel from the  commander to the leader
-    // for sending preempted
-    commander_leader_sender: Sender<Preempted>,
-
-    // list of all acceptors that have replied back with P1b
+    // list of all acceptors that have replied back with P2b
     waitfor: Vec<u32>,
 
-    // ballot num the scout is responsible for
+    // ballot num the commander is responsible for
     ballot_num: Ballot,
-    
-    // slot 
+
+    // slot
     slot: u32,
-    
+
     // command
-    command: Command
+    command: Command<C>,
 
+    // number of acceptors that have to reply, intersects every phase 1 quorum
+    quorum: u32,
 }
 
 
-pub fn new(
+pub fn new<C: Payload>(
     commander_id: u32,
     leader_id: u32,
-    leader_replica_broadcast_chan_sender: BroadcastSender<Decision>,
-    commander_acceptor_broadcast_chan_sender: BroadcastSender<P2a>,
-    leader_commander_receiver: Receiver<P2b>,
-    commander_leader_sender: Sender<Preempted>,
     ballot_num: Ballot,
     slot: u32,
-    command: Command,
-) -> Context {
-    let context = Context {
+    command: Command<C>,
+    quorum: u32,
+) -> Context<C> {
+    Context {
         commander_id,
         leader_id,
-        leader_replica_broadcast_chan_sender,
-        commander_acceptor_broadcast_chan_sender,
-        leader_commander_receiver,
-        commander_leader_sender,
         waitfor: Vec::new(),
         ballot_num,
         slot,
         command,
-    };
-
-    context
+        quorum,
+    }
 }
 
 
 
-impl Context{
+impl<C: Payload> Context<C> {
+    // broadcast the P2a message to all acceptors
+    pub fn start<R: Clone, P: Clone>(&self, transport: &dyn Transport<Message<C, R, P>>, acceptors: &[NodeId]) {
+        transport.broadcast(
+            acceptors,
+            Message::P2a(P2a::create(
+                self.leader_id,
+                Pvalue::create(self.ballot_num.clone(), self.slot, self.command.clone()),
+                self.commander_id,
+            )),
+        );
+        // println!("Commander of the leader {} has broadcast P2a message", self.leader_id);
+    }
 
-    pub fn start(mut self, num_acceptors: u32) {
 
-        // broadcast th P2a message to all acceptors
-        self.commander_acceptor_broadcast_chan_sender
-            .send(
-                P2a::create(
-                    self.leader_id,
-                    Pvalue::create(self.ballot_num.clone(), self.slot.clone(), self.command.clone()),
-                    self.commander_id.clone(),
-                )
-            );
-        // println!("Commander of the leader {} has broadcast P2a message", self.leader_id);
 
-        
-        // thread spawning
-        thread::Builder::new()
-            .spawn( move || {
-                loop{
-                    match self.leader_commander_receiver.try_recv() {
-                        Ok(message) => {
-                            // checking the ballot
-                            if message.get_ballot() == self.ballot_num.clone() {
-                                // updating waitfor
-                                self.waitfor.push(message.get_acceptor_id());
-                                // observe that the following inequality is opposite from in the PMMC
-                                if self.waitfor.len() as u32 > num_acceptors/2 {
-                                    // broadcast to all replicas
-                                    self.leader_replica_broadcast_chan_sender
-                                        .send(Decision::create(self.slot, self.command));
-                                    break;
-                                } 
-                            } else {
-                                // sending preempted message
-                                self.commander_leader_sender
-                                    .send(Preempted::create(message.get_ballot()));
-                                break;
-                            }
-                        }
-                        _ => {}
-                    }
-
-                }
-            }).unwrap();
+    // the P2a again to the acceptors that haven't replied, one of the messages may have been lost
+    pub fn resend<R: Clone, P: Clone>(&self, transport: &dyn Transport<Message<C, R, P>>, acceptors: &[NodeId]) {
+        let silent: Vec<NodeId> = acceptors
+            .iter()
+            .filter(|acceptor| !matches!(acceptor, NodeId::Acceptor(id) if self.waitfor.contains(id)))
+            .cloned()
+            .collect();
+        self.start(transport, &silent);
+    }
+
+
 
+    pub fn get_ballot_num(&self) -> Ballot {
+        self.ballot_num.clone()
     }
 
-}
\ No newline at end of file
+
+
+    // taking in a P2b from an acceptor
+    // returns the decided or preempted message once the commander is done, the commander can be dropped then
+    // the decision still has to be broadcast to the replicas
+    pub fn handle_p2b(&mut self, message: P2b) -> Option<CommanderMessage<C>> {
+        // checking the ballot
+        if message.get_ballot() != self.ballot_num {
+            // sending preempted message
+            return Some(CommanderMessage::Preempted(Preempted::create(message.get_ballot())));
+        }
+
+        // updating waitfor
+        if !self.waitfor.contains(&message.get_acceptor_id()) {
+            self.waitfor.push(message.get_acceptor_id());
+        }
+        // PMMC waits for a majority, which is the default quorum
+        if self.waitfor.len() as u32 >= self.quorum {
+            return Some(CommanderMessage::Decided(Decision::create(self.slot, self.command.clone())));
+        }
+        None
+    }
+}
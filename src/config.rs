@@ -0,0 +1,322 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::client::RetryPolicy;
+use crate::leader::BackoffPolicy;
+use crate::transport::{ClusterSize, NodeId};
+use crate::SystemOptions;
+
+
+// shape of a cluster read from a TOML file, e.g.
+//
+//     [options]
+//     failure_timeout_ms = 200
+//     phase1_quorum = 2
+//
+//     [[nodes]]
+//     role = "replica"
+//     id = 0
+//     address = "127.0.0.1:7000"
+//
+// every option is optional, see RawOptions for all of them
+// addresses are only needed when the nodes run in separate processes
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    // number of nodes of every role
+    pub cluster: ClusterSize,
+
+    // addresses of the nodes that have one, the control node included
+    pub addrs: HashMap<NodeId, SocketAddr>,
+
+    pub options: SystemOptions,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ConfigError {
+    // the file couldn't be read
+    Io { path: PathBuf, message: String },
+
+    // not TOML, or TOML with fields of the wrong type or that don't exist
+    Syntax(String),
+
+    // a role other than control, client, replica, leader or acceptor
+    UnknownRole(String),
+
+    // a node other than the control node without an id
+    MissingId(String),
+
+    // the same role and ID given twice
+    DuplicateNode(NodeId),
+
+    // the IDs of every role go from 0 without gaps, this one is missing
+    MissingNode(NodeId),
+
+    // a cluster needs at least one replica, one leader and one acceptor
+    NoNodes(&'static str),
+
+    InvalidAddress { node: NodeId, address: String },
+
+    DuplicateAddress { address: SocketAddr, first: NodeId, second: NodeId },
+
+    // the node has no address but runs in a process of its own
+    MissingAddress(NodeId),
+
+    // a quorum of no acceptor or of more acceptors than there are
+    QuorumOutOfRange { phase: u32, quorum: u32, acceptors: u32 },
+
+    // a phase 1 quorum and a phase 2 quorum could miss each other, two ballots could then decide different commands
+    QuorumsDontIntersect { phase1: u32, phase2: u32, acceptors: u32 },
+
+    InvalidOption { name: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, message } => write!(f, "can't read {}: {}", path.display(), message),
+            ConfigError::Syntax(message) => write!(f, "invalid cluster config: {}", message),
+            ConfigError::UnknownRole(role) => {
+                write!(f, "unknown role {:?}, expected control, client, replica, leader or acceptor", role)
+            }
+            ConfigError::MissingId(role) => write!(f, "a {} node has no id", role),
+            ConfigError::DuplicateNode(node) => write!(f, "{} is declared twice", node),
+            ConfigError::MissingNode(node) => write!(f, "{} is missing, the IDs of a role go from 0 without gaps", node),
+            ConfigError::NoNodes(role) => write!(f, "the cluster has no {}", role),
+            ConfigError::InvalidAddress { node, address } => write!(f, "invalid address {:?} of {}", address, node),
+            ConfigError::DuplicateAddress { address, first, second } => {
+                write!(f, "{} and {} have the same address {}", first, second, address)
+            }
+            ConfigError::MissingAddress(node) => write!(f, "{} has no address", node),
+            ConfigError::QuorumOutOfRange { phase, quorum, acceptors } => {
+                write!(f, "phase {} quorum {} is not between 1 and the {} acceptors", phase, quorum, acceptors)
+            }
+            ConfigError::QuorumsDontIntersect { phase1, phase2, acceptors } => write!(
+                f,
+                "phase 1 quorum {} and phase 2 quorum {} don't intersect with {} acceptors, they have to add up to more",
+                phase1, phase2, acceptors
+            ),
+            ConfigError::InvalidOption { name, reason } => write!(f, "invalid {}: {}", name, reason),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+
+
+
+// the file as written, checked and turned into a ClusterConfig by validate
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    options: RawOptions,
+
+    #[serde(default)]
+    nodes: Vec<RawNode>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawNode {
+    role: String,
+    id: Option<u32>,
+    address: Option<String>,
+}
+
+// the defaults of SystemOptions for every field left out
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawOptions {
+    // immediate, randomized-exponential or wait-for-winner
+    backoff: Option<String>,
+    // delays of randomized-exponential
+    backoff_base_ms: Option<u64>,
+    backoff_max_ms: Option<u64>,
+
+    acceptor_wal_dir: Option<PathBuf>,
+    snapshot_interval: Option<u32>,
+    heartbeat_interval_ms: Option<u64>,
+    failure_timeout_ms: Option<u64>,
+    progress_timeout_ms: Option<u64>,
+    catch_up_timeout_ms: Option<u64>,
+    phase1_quorum: Option<u32>,
+    phase2_quorum: Option<u32>,
+    client_retry_interval_ms: Option<u64>,
+    client_max_retry_interval_ms: Option<u64>,
+    client_deadline_ms: Option<u64>,
+}
+
+
+
+
+impl ClusterConfig {
+    pub fn load(path: &Path) -> Result<ClusterConfig, ConfigError> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| ConfigError::Io { path: path.to_path_buf(), message: err.to_string() })?;
+        ClusterConfig::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<ClusterConfig, ConfigError> {
+        let raw: RawConfig = toml::from_str(text).map_err(|err| ConfigError::Syntax(err.to_string()))?;
+        validate(raw)
+    }
+
+    // every node but the control node has to have an address, for running the nodes in separate processes
+    pub fn require_addresses(&self) -> Result<(), ConfigError> {
+        for node in self.cluster.nodes() {
+            if node != NodeId::Control && !self.addrs.contains_key(&node) {
+                return Err(ConfigError::MissingAddress(node));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+
+
+fn validate(raw: RawConfig) -> Result<ClusterConfig, ConfigError> {
+    // the nodes
+    let mut ids: Vec<NodeId> = Vec::new();
+    let mut addrs: HashMap<NodeId, SocketAddr> = HashMap::new();
+    for node in raw.nodes {
+        let id = node.id.unwrap_or(0);
+        let node_id = match node.role.as_str() {
+            "control" => NodeId::Control,
+            "client" => NodeId::Client(id),
+            "replica" => NodeId::Replica(id),
+            "leader" => NodeId::Leader(id),
+            "acceptor" => NodeId::Acceptor(id),
+            _ => return Err(ConfigError::UnknownRole(node.role)),
+        };
+        if node_id != NodeId::Control && node.id.is_none() {
+            return Err(ConfigError::MissingId(node.role));
+        }
+        if ids.contains(&node_id) {
+            return Err(ConfigError::DuplicateNode(node_id));
+        }
+        ids.push(node_id);
+
+        if let Some(address) = node.address {
+            let addr: SocketAddr = address
+                .parse()
+                .map_err(|_| ConfigError::InvalidAddress { node: node_id, address: address.clone() })?;
+            if let Some((first, _)) = addrs.iter().find(|(_, other)| **other == addr) {
+                return Err(ConfigError::DuplicateAddress { address: addr, first: *first, second: node_id });
+            }
+            addrs.insert(node_id, addr);
+        }
+    }
+
+    let cluster = ClusterSize::from_nodes(&ids).map_err(ConfigError::MissingNode)?;
+    for (count, role) in [(cluster.replicas, "replica"), (cluster.leaders, "leader"), (cluster.acceptors, "acceptor")] {
+        if count == 0 {
+            return Err(ConfigError::NoNodes(role));
+        }
+    }
+
+    // the options
+    let raw = raw.options;
+    let defaults = SystemOptions::default();
+    let millis = |name: &'static str, value: Option<u64>, default: Duration| -> Result<Duration, ConfigError> {
+        match value {
+            None => Ok(default),
+            Some(0) => Err(ConfigError::InvalidOption { name, reason: "has to be more than 0".to_string() }),
+            Some(ms) => Ok(Duration::from_millis(ms)),
+        }
+    };
+
+    let backoff_policy = match raw.backoff.as_deref() {
+        None => defaults.backoff_policy.clone(),
+        Some("immediate") => BackoffPolicy::Immediate,
+        Some("wait-for-winner") => BackoffPolicy::WaitForWinner,
+        Some("randomized-exponential") => BackoffPolicy::RandomizedExponential {
+            base: millis("backoff_base_ms", raw.backoff_base_ms, Duration::from_millis(10))?,
+            max: millis("backoff_max_ms", raw.backoff_max_ms, Duration::from_millis(1000))?,
+        },
+        Some(other) => {
+            return Err(ConfigError::InvalidOption {
+                name: "backoff",
+                reason: format!("{:?} is none of immediate, randomized-exponential and wait-for-winner", other),
+            })
+        }
+    };
+    if !matches!(backoff_policy, BackoffPolicy::RandomizedExponential { .. })
+        && (raw.backoff_base_ms.is_some() || raw.backoff_max_ms.is_some())
+    {
+        return Err(ConfigError::InvalidOption {
+            name: "backoff",
+            reason: "backoff_base_ms and backoff_max_ms only go with randomized-exponential".to_string(),
+        });
+    }
+    if let BackoffPolicy::RandomizedExponential { base, max } = &backoff_policy {
+        if base > max {
+            return Err(ConfigError::InvalidOption {
+                name: "backoff_base_ms",
+                reason: "can't be more than backoff_max_ms".to_string(),
+            });
+        }
+    }
+
+    let snapshot_interval = raw.snapshot_interval.unwrap_or(defaults.snapshot_interval);
+    if snapshot_interval == 0 {
+        return Err(ConfigError::InvalidOption { name: "snapshot_interval", reason: "has to be more than 0".to_string() });
+    }
+
+    let client_retry = RetryPolicy {
+        retry_interval: millis("client_retry_interval_ms", raw.client_retry_interval_ms, defaults.client_retry.retry_interval)?,
+        max_retry_interval: millis(
+            "client_max_retry_interval_ms",
+            raw.client_max_retry_interval_ms,
+            defaults.client_retry.max_retry_interval,
+        )?,
+        deadline: millis("client_deadline_ms", raw.client_deadline_ms, defaults.client_retry.deadline)?,
+    };
+    if client_retry.max_retry_interval < client_retry.retry_interval {
+        return Err(ConfigError::InvalidOption {
+            name: "client_max_retry_interval_ms",
+            reason: "has to be at least client_retry_interval_ms".to_string(),
+        });
+    }
+
+    let options = SystemOptions {
+        backoff_policy,
+        acceptor_wal_dir: raw.acceptor_wal_dir,
+        snapshot_interval,
+        heartbeat_interval: millis("heartbeat_interval_ms", raw.heartbeat_interval_ms, defaults.heartbeat_interval)?,
+        failure_timeout: millis("failure_timeout_ms", raw.failure_timeout_ms, defaults.failure_timeout)?,
+        progress_timeout: millis("progress_timeout_ms", raw.progress_timeout_ms, defaults.progress_timeout)?,
+        catch_up_timeout: millis("catch_up_timeout_ms", raw.catch_up_timeout_ms, defaults.catch_up_timeout)?,
+        phase1_quorum: raw.phase1_quorum,
+        phase2_quorum: raw.phase2_quorum,
+        client_retry,
+        seed: None,
+    };
+    // otherwise a live leader is suspected between two of its heartbeats
+    if options.failure_timeout <= options.heartbeat_interval {
+        return Err(ConfigError::InvalidOption {
+            name: "failure_timeout_ms",
+            reason: "has to be more than heartbeat_interval_ms".to_string(),
+        });
+    }
+
+    // the quorums, majorities unless given
+    let (phase1, phase2) = options.quorums(cluster.acceptors);
+    for (phase, quorum) in [(1, phase1), (2, phase2)] {
+        if quorum == 0 || quorum > cluster.acceptors {
+            return Err(ConfigError::QuorumOutOfRange { phase, quorum, acceptors: cluster.acceptors });
+        }
+    }
+    if phase1 + phase2 <= cluster.acceptors {
+        return Err(ConfigError::QuorumsDontIntersect { phase1, phase2, acceptors: cluster.acceptors });
+    }
+
+    Ok(ClusterConfig { cluster, addrs, options })
+}
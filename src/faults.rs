@@ -0,0 +1,319 @@
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use rand::Rng;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::transport::{NodeId, Transport};
+
+
+// what can go wrong on a link, every probability is drawn anew for every message
+// the faults combine, e.g. a message can be duplicated and both copies delayed
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkFaults {
+    // the message never arrives
+    pub drop: f64,
+
+    // the message arrives twice
+    pub duplicate: f64,
+
+    // the message arrives up to max_delay later than it would have
+    pub delay: f64,
+    pub max_delay: Duration,
+
+    // the message is held back and arrives right after the next message on the link
+    // when nothing follows on the link it is lost
+    pub reorder: f64,
+}
+
+impl LinkFaults {
+    // every fault with the same probability, the delays up to max_delay
+    pub fn uniform(probability: f64, max_delay: Duration) -> LinkFaults {
+        LinkFaults { drop: probability, duplicate: probability, delay: probability, max_delay, reorder: probability }
+    }
+}
+
+// a reliable link
+impl Default for LinkFaults {
+    fn default() -> LinkFaults {
+        LinkFaults { drop: 0.0, duplicate: 0.0, delay: 0.0, max_delay: Duration::ZERO, reorder: 0.0 }
+    }
+}
+
+
+
+
+// groups of nodes that can't reach each other for a while, every message between two groups is lost
+// the nodes in no group make up one more group together
+// the times count from the start of the run, messages already on their way when the partition starts still arrive
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+    groups: Vec<BTreeSet<NodeId>>,
+    start: Duration,
+    // None for a partition that never heals
+    heal: Option<Duration>,
+}
+
+impl Partition {
+    // from the start of the run on, never healing
+    pub fn new(groups: Vec<Vec<NodeId>>) -> Partition {
+        Partition { groups: groups.into_iter().map(|group| group.into_iter().collect()).collect(), start: Duration::ZERO, heal: None }
+    }
+
+    // the nodes cut off from the rest of the cluster
+    pub fn isolate(nodes: &[NodeId]) -> Partition {
+        Partition::new(vec![nodes.to_vec()])
+    }
+
+    pub fn starting_at(self, start: Duration) -> Partition {
+        Partition { start, ..self }
+    }
+
+    pub fn healing_at(self, heal: Duration) -> Partition {
+        Partition { heal: Some(heal), ..self }
+    }
+
+    pub fn is_active(&self, elapsed: Duration) -> bool {
+        elapsed >= self.start && self.heal.is_none_or(|heal| elapsed < heal)
+    }
+
+    // the group the node is in, the nodes in no group share None
+    fn group_of(&self, node: NodeId) -> Option<usize> {
+        self.groups.iter().position(|group| group.contains(&node))
+    }
+
+    pub fn separates(&self, from: NodeId, to: NodeId, elapsed: Duration) -> bool {
+        self.is_active(elapsed) && self.group_of(from) != self.group_of(to)
+    }
+}
+
+
+
+
+// the faults of every link of a cluster, a link is one direction between two nodes
+// the links to and from the control node and from a node to itself are always reliable, the faults are between the roles
+#[derive(Debug, Clone, Default)]
+pub struct FaultPlan {
+    // for every link without faults of its own
+    default: LinkFaults,
+
+    // by (from, to)
+    links: HashMap<(NodeId, NodeId), LinkFaults>,
+
+    // cut links on top of the faults, each for the time it is scheduled for
+    partitions: Vec<Partition>,
+}
+
+impl FaultPlan {
+    pub fn new(default: LinkFaults) -> FaultPlan {
+        FaultPlan { default, links: HashMap::new(), partitions: Vec::new() }
+    }
+
+    // the partitions may overlap in time, a link cut by any of them is cut
+    pub fn with_partition(mut self, partition: Partition) -> FaultPlan {
+        self.partitions.push(partition);
+        self
+    }
+
+    // the link from one node to the other gets faults of its own
+    pub fn with_link(mut self, from: NodeId, to: NodeId, faults: LinkFaults) -> FaultPlan {
+        self.links.insert((from, to), faults);
+        self
+    }
+
+    // the link both ways gets the same faults
+    pub fn with_links_between(self, a: NodeId, b: NodeId, faults: LinkFaults) -> FaultPlan {
+        self.with_link(a, b, faults.clone()).with_link(b, a, faults)
+    }
+
+    // None for a link that is always reliable
+    pub fn for_link(&self, from: NodeId, to: NodeId) -> Option<&LinkFaults> {
+        if from == NodeId::Control || to == NodeId::Control || from == to {
+            return None;
+        }
+        Some(self.links.get(&(from, to)).unwrap_or(&self.default))
+    }
+
+    // whether a partition cuts the link at the time given, counted from the start of the run
+    pub fn is_cut(&self, from: NodeId, to: NodeId, elapsed: Duration) -> bool {
+        self.for_link(from, to).is_some() && self.partitions.iter().any(|partition| partition.separates(from, to, elapsed))
+    }
+}
+
+
+
+
+// deciding the fate of every message sent, from the fault plan
+// every link draws from an RNG of its own, seeded from the seed and the link
+// so the n-th message on a link meets the same faults in every run, however the sends of different nodes interleave
+pub(crate) struct Injector<M> {
+    plan: FaultPlan,
+    seed: u64,
+    links: HashMap<(NodeId, NodeId), LinkState<M>>,
+}
+
+struct LinkState<M> {
+    rng: StdRng,
+    // message held back for reordering
+    held: Option<M>,
+}
+
+impl<M: Clone> Injector<M> {
+    pub(crate) fn new(plan: FaultPlan, seed: u64) -> Injector<M> {
+        Injector { plan, seed, links: HashMap::new() }
+    }
+
+    // the copies of the message to deliver, in order, each with the delay to add to it
+    // empty when the message is dropped or held back, a message held back earlier may come out after this one
+    // elapsed is the time since the start of the run, for the partitions
+    pub(crate) fn inject(&mut self, from: NodeId, to: NodeId, message: M, elapsed: Duration) -> Vec<(Duration, M)> {
+        // a cut link draws nothing, the faults of the other messages stay the same
+        if self.plan.is_cut(from, to, elapsed) {
+            return Vec::new();
+        }
+        let faults = match self.plan.for_link(from, to) {
+            Some(faults) => faults,
+            None => return vec![(Duration::ZERO, message)],
+        };
+        let seed = self.seed;
+        let link = self.links.entry((from, to)).or_insert_with(|| {
+            let mut hasher = DefaultHasher::new();
+            (seed, from, to).hash(&mut hasher);
+            LinkState { rng: StdRng::seed_from_u64(hasher.finish()), held: None }
+        });
+
+        // all the draws are made for every message, so one fault doesn't shift the draws of the others
+        let dropped = link.rng.gen_bool(faults.drop);
+        let duplicated = link.rng.gen_bool(faults.duplicate);
+        let held_back = link.rng.gen_bool(faults.reorder);
+        let mut delays = [Duration::ZERO; 2];
+        for delay in delays.iter_mut() {
+            if link.rng.gen_bool(faults.delay) {
+                *delay = link.rng.gen_range(Duration::ZERO..=faults.max_delay);
+            }
+        }
+
+        let mut delivered = Vec::new();
+        if !dropped {
+            if held_back && link.held.is_none() {
+                link.held = Some(message);
+                return delivered;
+            }
+            let copies = if duplicated { 2 } else { 1 };
+            delivered.extend(delays.iter().take(copies).map(|delay| (*delay, message.clone())));
+        }
+        // the message held back is overtaken by this one
+        if let Some(held) = link.held.take() {
+            delivered.push((Duration::ZERO, held));
+        }
+        delivered
+    }
+}
+
+
+
+
+// endpoint that passes its messages through the faults of the plan on the way to the wrapped endpoint
+// receiving is left to the wrapped endpoint, so every sender of a faulty link has to be wrapped
+// delayed messages are sent by a thread of the endpoint, they are lost when the endpoint is dropped first
+pub struct FaultyTransport<M> {
+    inner: Arc<dyn Transport<M>>,
+    injector: Mutex<Injector<M>>,
+
+    // start of the run, the partitions are scheduled from it
+    start: Instant,
+
+    // messages to send later, to the delay thread
+    delayed: Sender<(Instant, NodeId, M)>,
+}
+
+impl<M: Clone + Send + 'static> FaultyTransport<M> {
+    // the same seed and plan for every endpoint of a cluster gives every link the same faults in every run
+    // the partitions of the plan are scheduled from start, the same for every endpoint
+    pub fn wrap(inner: Box<dyn Transport<M>>, plan: FaultPlan, seed: u64, start: Instant) -> FaultyTransport<M> {
+        let inner: Arc<dyn Transport<M>> = Arc::from(inner);
+        let (sender, receiver) = unbounded();
+        let delay_inner = inner.clone();
+        thread::Builder::new()
+            .spawn(move || send_delayed(delay_inner, receiver))
+            .unwrap();
+
+        FaultyTransport { inner, injector: Mutex::new(Injector::new(plan, seed)), start, delayed: sender }
+    }
+}
+
+// sending every message once its time has come, until the endpoint is dropped
+fn send_delayed<M: Clone>(inner: Arc<dyn Transport<M>>, receiver: Receiver<(Instant, NodeId, M)>) {
+    // by due time, then in the order handed over
+    let mut queue: BinaryHeap<Reverse<(Instant, u64)>> = BinaryHeap::new();
+    let mut messages: HashMap<u64, (NodeId, M)> = HashMap::new();
+    let mut num_received = 0u64;
+    loop {
+        let received = match queue.peek() {
+            Some(Reverse((due, _))) => match receiver.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                Ok(received) => Some(received),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match receiver.recv() {
+                Ok(received) => Some(received),
+                Err(_) => return,
+            },
+        };
+        if let Some((due, to, message)) = received {
+            queue.push(Reverse((due, num_received)));
+            messages.insert(num_received, (to, message));
+            num_received += 1;
+        }
+
+        let now = Instant::now();
+        while let Some(Reverse((due, number))) = queue.peek().cloned() {
+            if due > now {
+                break;
+            }
+            queue.pop();
+            let (to, message) = messages.remove(&number).unwrap();
+            inner.send(to, message);
+        }
+    }
+}
+
+impl<M: Clone + Send> Transport<M> for FaultyTransport<M> {
+    fn get_id(&self) -> NodeId {
+        self.inner.get_id()
+    }
+
+    fn send(&self, to: NodeId, message: M) {
+        let elapsed = self.now().saturating_duration_since(self.start);
+        let delivered = self.injector.lock().unwrap().inject(self.get_id(), to, message, elapsed);
+        for (delay, message) in delivered {
+            if delay.is_zero() {
+                self.inner.send(to, message);
+            } else {
+                let _ = self.delayed.send((Instant::now() + delay, to, message));
+            }
+        }
+    }
+
+    fn recv(&self) -> Option<M> {
+        self.inner.recv()
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Option<M> {
+        self.inner.recv_timeout(timeout)
+    }
+
+    fn try_recv(&self) -> Option<M> {
+        self.inner.try_recv()
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::iter;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::state_machine::StateMachine;
+
+
+// one command of a client as seen from the client, from the first request to the first response
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recorded<C, R> {
+    pub client_id: u32,
+    pub command_id: u32,
+    pub command: C,
+    pub invoked: Instant,
+    // time and result of the response, None while unanswered or once given up on
+    // such a command may or may not have been executed
+    pub completed: Option<(Instant, R)>,
+}
+
+
+
+
+// invocations and completions of the commands of all the clients of a system
+// shared by the clients, each records its own commands, retries are not new invocations
+pub struct History<S: StateMachine> {
+    operations: Mutex<Operations<S>>,
+}
+
+struct Operations<S: StateMachine> {
+    recorded: Vec<Recorded<S::Command, S::Result>>,
+    // index in recorded by client ID and command ID
+    index: HashMap<(u32, u32), usize>,
+}
+
+impl<S: StateMachine> Default for History<S> {
+    fn default() -> History<S> {
+        History { operations: Mutex::new(Operations { recorded: Vec::new(), index: HashMap::new() }) }
+    }
+}
+
+impl<S: StateMachine> History<S> {
+    pub fn new() -> History<S> {
+        History::default()
+    }
+
+    // the client sent the command for the first time
+    pub fn invoke(&self, client_id: u32, command_id: u32, command: S::Command, at: Instant) {
+        let mut operations = self.operations.lock().unwrap();
+        let position = operations.recorded.len();
+        operations.index.insert((client_id, command_id), position);
+        operations.recorded.push(Recorded { client_id, command_id, command, invoked: at, completed: None });
+    }
+
+    // the first response for the command came in, later ones are ignored
+    pub fn complete(&self, client_id: u32, command_id: u32, result: S::Result, at: Instant) {
+        let mut operations = self.operations.lock().unwrap();
+        if let Some(position) = operations.index.get(&(client_id, command_id)).cloned() {
+            let operation = &mut operations.recorded[position];
+            if operation.completed.is_none() {
+                operation.completed = Some((at, result));
+            }
+        }
+    }
+
+    // everything recorded so far, in the order invoked
+    pub fn get_operations(&self) -> Vec<Recorded<S::Command, S::Result>> {
+        self.operations.lock().unwrap().recorded.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.lock().unwrap().recorded.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<S: StateMachine> History<S>
+where
+    S::Result: PartialEq,
+    S::Snapshot: Hash + Eq,
+{
+    // whether the history so far is linearizable, see check_linearizable
+    pub fn check(&self, new_state_machine: impl Fn() -> S) -> Result<(), NonLinearizable<S::Command, S::Result>> {
+        check_linearizable(new_state_machine, &self.get_operations())
+    }
+}
+
+
+
+
+// a history with no order of its commands that the state machine could have gone through
+// the commands are the smallest part of the history found that still can't be ordered, dropping any one of them makes it linearizable
+#[derive(Debug, Clone, PartialEq)]
+pub struct NonLinearizable<C, R> {
+    pub operations: Vec<Recorded<C, R>>,
+}
+
+impl<C: fmt::Debug, R: fmt::Debug> fmt::Display for NonLinearizable<C, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "history not linearizable, minimal sub-history of {} commands:", self.operations.len())?;
+        // times from the first invocation among them
+        let start = self.operations.iter().map(|operation| operation.invoked).min();
+        for operation in &self.operations {
+            let start = start.unwrap();
+            write!(
+                f,
+                "\n  client {} command {} {:?} invoked at {:?}",
+                operation.client_id,
+                operation.command_id,
+                operation.command,
+                operation.invoked - start
+            )?;
+            match &operation.completed {
+                Some((at, result)) => write!(f, ", returned {:?} at {:?}", result, *at - start)?,
+                None => write!(f, ", never returned")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<C: fmt::Debug, R: fmt::Debug> Error for NonLinearizable<C, R> {}
+
+
+
+
+// checking the history against the state machine, as new_state_machine gives it, applying one command at a time
+// linearizable if the commands can be put in an order that respects real time, a command completed before another was invoked comes first,
+// and in which every completed command returns the result the state machine gives for it
+// a command that never completed may have been executed at any time after its invocation, or not at all
+// Wing and Gong's search with the memoization of Lowe, as in Porcupine
+pub fn check_linearizable<S: StateMachine>(
+    new_state_machine: impl Fn() -> S,
+    operations: &[Recorded<S::Command, S::Result>],
+) -> Result<(), NonLinearizable<S::Command, S::Result>>
+where
+    S::Result: PartialEq,
+    S::Snapshot: Hash + Eq,
+{
+    // restore replaces the whole state, the same state machine serves for every step
+    let mut model = new_state_machine();
+    let initial = model.snapshot();
+    let all: Vec<&Recorded<S::Command, S::Result>> = operations.iter().collect();
+    if is_linearizable(&mut model, &initial, &all) {
+        return Ok(());
+    }
+
+    // dropping the commands one by one, every one that isn't needed for the violation stays out
+    // a command needed when it was tried may not be needed any more once later ones are out, e.g. the one whose result it explained
+    // so the passes go on until one drops nothing
+    let mut kept = all;
+    loop {
+        let before = kept.len();
+        let mut position = 0;
+        while position < kept.len() {
+            let mut without = kept.clone();
+            without.remove(position);
+            if is_linearizable(&mut model, &initial, &without) {
+                position += 1;
+            } else {
+                kept = without;
+            }
+        }
+        if kept.len() == before {
+            break;
+        }
+    }
+    Err(NonLinearizable { operations: kept.into_iter().cloned().collect() })
+}
+
+// invocations and completions of all the commands in time order, in a doubly linked list
+// the search takes a command out of the list with both its events once it is linearized, and puts it back when backtracking
+struct Events {
+    // by event, the first and the last are the head and the tail of the list
+    next: Vec<usize>,
+    prev: Vec<usize>,
+    // command of every event, and for an invocation the completion that goes with it
+    operation: Vec<usize>,
+    completion: Vec<Option<usize>>,
+}
+
+const HEAD: usize = 0;
+
+impl Events {
+    fn new<C, R>(operations: &[&Recorded<C, R>]) -> Events {
+        // an invocation comes before a completion at the same time, the two commands count as concurrent
+        // the commands never completed complete after everything else
+        let mut timed: Vec<(Instant, bool, usize)> = Vec::new();
+        let mut never_completed = Vec::new();
+        for (number, operation) in operations.iter().enumerate() {
+            timed.push((operation.invoked, false, number));
+            match &operation.completed {
+                Some((at, _)) => timed.push((*at, true, number)),
+                None => never_completed.push(number),
+            }
+        }
+        timed.sort_by_key(|(at, is_completion, number)| (*at, *is_completion, *number));
+        let order: Vec<(bool, usize)> = timed
+            .into_iter()
+            .map(|(_, is_completion, number)| (is_completion, number))
+            .chain(never_completed.into_iter().map(|number| (true, number)))
+            .collect();
+
+        // events from 1 on, the head at 0 and the tail after the last one
+        let tail = order.len() + 1;
+        let mut events = Events {
+            next: (1..=tail).chain(iter::once(tail)).collect(),
+            prev: iter::once(HEAD).chain(0..tail).collect(),
+            operation: vec![usize::MAX; tail + 1],
+            completion: vec![None; tail + 1],
+        };
+        let mut invocation_of = vec![0; operations.len()];
+        for (position, (is_completion, number)) in order.into_iter().enumerate() {
+            let event = position + 1;
+            events.operation[event] = number;
+            if is_completion {
+                events.completion[invocation_of[number]] = Some(event);
+            } else {
+                invocation_of[number] = event;
+            }
+        }
+        events
+    }
+
+    fn tail(&self) -> usize {
+        self.next.len() - 1
+    }
+
+    fn is_invocation(&self, event: usize) -> bool {
+        self.completion[event].is_some()
+    }
+
+    fn unlink(&mut self, event: usize) {
+        let (prev, next) = (self.prev[event], self.next[event]);
+        self.next[prev] = next;
+        self.prev[next] = prev;
+    }
+
+    // the event keeps its neighbours while out, so the last one taken out is the first one put back
+    fn relink(&mut self, event: usize) {
+        let (prev, next) = (self.prev[event], self.next[event]);
+        self.next[prev] = event;
+        self.prev[next] = event;
+    }
+
+    fn lift(&mut self, invocation: usize) {
+        self.unlink(invocation);
+        self.unlink(self.completion[invocation].unwrap());
+    }
+
+    fn unlift(&mut self, invocation: usize) {
+        self.relink(self.completion[invocation].unwrap());
+        self.relink(invocation);
+    }
+}
+
+fn is_linearizable<S: StateMachine>(
+    model: &mut S,
+    initial: &S::Snapshot,
+    operations: &[&Recorded<S::Command, S::Result>],
+) -> bool
+where
+    S::Result: PartialEq,
+    S::Snapshot: Hash + Eq,
+{
+    let mut events = Events::new(operations);
+    let tail = events.tail();
+
+    // commands linearized so far, one bit each
+    let mut linearized = vec![0u64; operations.len().div_ceil(64)];
+    let mut state = initial.clone();
+    // states already reached with the same commands linearized, nothing new comes from them
+    let mut seen: HashSet<(Vec<u64>, S::Snapshot)> = HashSet::new();
+    // invocations linearized, with the state before each
+    let mut stack: Vec<(usize, S::Snapshot)> = Vec::new();
+
+    let mut event = events.next[HEAD];
+    while events.next[HEAD] != tail {
+        if events.is_invocation(event) {
+            let number = events.operation[event];
+            let operation = operations[number];
+            model.restore(state.clone());
+            let result = model.apply(&operation.command);
+            let fits = match &operation.completed {
+                Some((_, expected)) => *expected == result,
+                None => true,
+            };
+
+            if fits {
+                let mut with = linearized.clone();
+                with[number / 64] |= 1 << (number % 64);
+                let next_state = model.snapshot();
+                if seen.insert((with.clone(), next_state.clone())) {
+                    stack.push((event, std::mem::replace(&mut state, next_state)));
+                    linearized = with;
+                    events.lift(event);
+                    event = events.next[HEAD];
+                    continue;
+                }
+            }
+            event = events.next[event];
+        } else {
+            // a command completed with nothing left to linearize it before, the last choice was wrong
+            let (invocation, previous) = match stack.pop() {
+                Some(top) => top,
+                None => return false,
+            };
+            let number = events.operation[invocation];
+            linearized[number / 64] &= !(1 << (number % 64));
+            state = previous;
+            events.unlift(invocation);
+            event = events.next[invocation];
+        }
+    }
+    true
+}
@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::codec::{Encode, Decode, Cursor, DecodeError};
+use crate::state_machine::StateMachine;
+
+
+// replicated map from string keys to string values
+// a BTreeMap so that snapshots of equal stores print the same
+#[derive(Debug, Clone, Default)]
+pub struct KvStore {
+    entries: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub enum KvCommand {
+    Get(String),
+    Put(String, String),
+    Delete(String),
+    // replaces the value only if the current one is the expected one, None meaning the key is absent
+    Cas {
+        key: String,
+        expected: Option<String>,
+        new: String,
+    },
+}
+
+impl KvCommand {
+    pub fn get(key: &str) -> KvCommand {
+        KvCommand::Get(key.to_string())
+    }
+
+    pub fn put(key: &str, value: &str) -> KvCommand {
+        KvCommand::Put(key.to_string(), value.to_string())
+    }
+
+    pub fn delete(key: &str) -> KvCommand {
+        KvCommand::Delete(key.to_string())
+    }
+
+    pub fn cas(key: &str, expected: Option<&str>, new: &str) -> KvCommand {
+        KvCommand::Cas {
+            key: key.to_string(),
+            expected: expected.map(|value| value.to_string()),
+            new: new.to_string(),
+        }
+    }
+
+    pub fn get_key(&self) -> &str {
+        match self {
+            KvCommand::Get(key) | KvCommand::Put(key, _) | KvCommand::Delete(key) => key,
+            KvCommand::Cas { key, .. } => key,
+        }
+    }
+}
+
+// written as get key, put key value, delete key or cas key expected new
+// - as the expected value of cas stands for an absent key, keys and values can't contain whitespace
+impl FromStr for KvCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<KvCommand, String> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["get", key] => Ok(KvCommand::get(key)),
+            ["put", key, value] => Ok(KvCommand::put(key, value)),
+            ["delete", key] => Ok(KvCommand::delete(key)),
+            ["cas", key, expected, new] => {
+                let expected = if *expected == "-" { None } else { Some(*expected) };
+                Ok(KvCommand::cas(key, expected, new))
+            }
+            _ => Err(format!("Invalid command {:?}, expected get, put, delete or cas with their arguments", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum KvError {
+    // Get or Delete of a key that isn't in the store
+    KeyNotFound(String),
+
+    // Cas found another value than the expected one
+    CasMismatch {
+        key: String,
+        expected: Option<String>,
+        current: Option<String>,
+    },
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvError::KeyNotFound(key) => write!(f, "key {:?} not found", key),
+            KvError::CasMismatch { key, expected, current } => {
+                write!(f, "CAS mismatch on key {:?}: expected {:?}, found {:?}", key, expected, current)
+            }
+        }
+    }
+}
+
+impl Error for KvError {}
+
+// on success the value the key had before the command, None if it was absent
+// for Get that is simply the value
+pub type KvResult = Result<Option<String>, KvError>;
+
+
+
+
+impl KvStore {
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.entries.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl StateMachine for KvStore {
+    type Command = KvCommand;
+    type Result = KvResult;
+    type Snapshot = BTreeMap<String, String>;
+
+    fn apply(&mut self, command: &KvCommand) -> KvResult {
+        match command {
+            KvCommand::Get(key) => match self.entries.get(key) {
+                Some(value) => Ok(Some(value.clone())),
+                None => Err(KvError::KeyNotFound(key.clone())),
+            },
+
+            KvCommand::Put(key, value) => Ok(self.entries.insert(key.clone(), value.clone())),
+
+            KvCommand::Delete(key) => match self.entries.remove(key) {
+                Some(value) => Ok(Some(value)),
+                None => Err(KvError::KeyNotFound(key.clone())),
+            },
+
+            KvCommand::Cas { key, expected, new } => {
+                let current = self.entries.get(key).cloned();
+                if current != *expected {
+                    return Err(KvError::CasMismatch {
+                        key: key.clone(),
+                        expected: expected.clone(),
+                        current,
+                    });
+                }
+                self.entries.insert(key.clone(), new.clone());
+                Ok(current)
+            }
+        }
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, String> {
+        self.entries.clone()
+    }
+
+    fn restore(&mut self, snapshot: BTreeMap<String, String>) {
+        self.entries = snapshot;
+    }
+}
+
+
+
+
+// the commands sent by the demo clients, num is the command ID
+// cycles through a handful of keys so that every kind of result shows up
+pub fn kv_workload(num: u32) -> KvCommand {
+    let key = format!("key-{}", num % 3);
+    match num % 4 {
+        0 => KvCommand::put(&key, &num.to_string()),
+        1 => KvCommand::get(&key),
+        2 => KvCommand::cas(&key, Some(&(num - 2).to_string()), &num.to_string()),
+        _ => KvCommand::delete(&key),
+    }
+}
+
+
+
+
+impl Encode for KvCommand {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            KvCommand::Get(key) => {
+                buf.push(0);
+                key.encode(buf);
+            }
+            KvCommand::Put(key, value) => {
+                buf.push(1);
+                key.encode(buf);
+                value.encode(buf);
+            }
+            KvCommand::Delete(key) => {
+                buf.push(2);
+                key.encode(buf);
+            }
+            KvCommand::Cas { key, expected, new } => {
+                buf.push(3);
+                key.encode(buf);
+                expected.encode(buf);
+                new.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decode for KvCommand {
+    fn decode(cursor: &mut Cursor) -> Result<KvCommand, DecodeError> {
+        match cursor.get_u8()? {
+            0 => Ok(KvCommand::Get(String::decode(cursor)?)),
+            1 => Ok(KvCommand::Put(String::decode(cursor)?, String::decode(cursor)?)),
+            2 => Ok(KvCommand::Delete(String::decode(cursor)?)),
+            3 => Ok(KvCommand::Cas {
+                key: String::decode(cursor)?,
+                expected: Option::decode(cursor)?,
+                new: String::decode(cursor)?,
+            }),
+            tag => cursor.invalid_tag("KvCommand", tag),
+        }
+    }
+}
+
+
+impl Encode for KvError {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            KvError::KeyNotFound(key) => {
+                buf.push(0);
+                key.encode(buf);
+            }
+            KvError::CasMismatch { key, expected, current } => {
+                buf.push(1);
+                key.encode(buf);
+                expected.encode(buf);
+                current.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decode for KvError {
+    fn decode(cursor: &mut Cursor) -> Result<KvError, DecodeError> {
+        match cursor.get_u8()? {
+            0 => Ok(KvError::KeyNotFound(String::decode(cursor)?)),
+            1 => Ok(KvError::CasMismatch {
+                key: String::decode(cursor)?,
+                expected: Option::decode(cursor)?,
+                current: Option::decode(cursor)?,
+            }),
+            tag => cursor.invalid_tag("KvError", tag),
+        }
+    }
+}
@@ -1,31 +1,98 @@
-use crossbeam::channel::{unbounded, Sender, Receiver, TryRecvError};
 use std::collections::VecDeque;
 use std::thread;
-use std::time::Duration;
-use std::collections::HashMap;
-
-use crate::broadcast_channel::BroadcastSender;
-use crate::utils::{Operation, Command, Decision, Propose, Ballot, P1a, P1b, P2a, P2b, Adopted, Preempted, ScoutMessage, Pvalue};
+use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use rand::Rng;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::utils::{Operation, Command, Decision, Propose, Ballot, P1b, P2b, ScoutMessage, CommanderMessage, Pvalue, Heartbeat, ControlSignal, Message};
+use crate::node::{self, Node};
 use crate::scout;
 use crate::commander;
+use crate::replica::{SystemMessage, WINDOW};
+use crate::state_machine::StateMachine;
+use crate::transport::{self, NodeId, Transport};
+use crate::SystemOptions;
+
+
+// default interval between two heartbeats sent by a leader to all leaders
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(10);
+
+// by default a leader not heard from for this long is suspected to have crashed
+pub const FAILURE_TIMEOUT: Duration = Duration::from_millis(100);
+
+// by default a winner that gets nothing decided for this long, while proposals are waiting, is given up on
+pub const PROGRESS_TIMEOUT: Duration = Duration::from_millis(500);
 
 enum OperatingState {
     Paused,
     Run(u32),
+    // everything is dropped until the restart signal
+    Crashed,
     Exit,
 }
 
-#[derive(Clone)]
-pub enum ControlSignal {
-    Paused,
-    Run(u32),
-    Exit,
+// what a leader does after its scout or commander has been preempted
+#[derive(Debug, Clone, Default)]
+pub enum BackoffPolicy {
+    // spawn a scout with a higher ballot right away, as in PMMC
+    Immediate,
+
+    // spawn the scout after a random delay in [0, base * 2^(n - 1)], capped at max
+    // n is the number of preemptions since the leader was last adopted
+    RandomizedExponential { base: Duration, max: Duration },
+
+    // stay passive until the owner of the preempting ballot is suspected to have crashed
+    // or until it has decided nothing for the progress timeout while proposals are waiting, e.g. because it can't reach the acceptors
+    #[default]
+    WaitForWinner,
+}
+
+
+
+
+// counters for the ballot churn at a leader, and whether it is the one leading right now
+// shared with the SystemHandles so they can be read during and after a run
+#[derive(Debug, Default)]
+pub struct BallotChurn {
+    // number of times a higher ballot preempted the leader
+    preemptions: AtomicU32,
+
+    // number of scouts spawned, each one with a new ballot
+    scouts: AtomicU32,
+
+    // number of times a scout of the leader has been adopted
+    adoptions: AtomicU32,
+
+    // set while the current ballot of the leader is adopted and not preempted
+    active: AtomicBool,
 }
 
+impl BallotChurn {
+    pub fn get_preemptions(&self) -> u32 {
+        self.preemptions.load(Ordering::SeqCst)
+    }
+
+    pub fn get_scouts(&self) -> u32 {
+        self.scouts.load(Ordering::SeqCst)
+    }
 
+    pub fn get_adoptions(&self) -> u32 {
+        self.adoptions.load(Ordering::SeqCst)
+    }
 
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+}
 
-pub struct Context {
+
+
+
+pub struct Context<S: StateMachine> {
     // ID of the leader
     id: u32,
 
@@ -35,8 +102,11 @@ pub struct Context {
     // maximum commander ID's assigned till now
     commander_id: u32,
 
-    // number of acceptors
-    num_acceptors: u32,
+    // number of acceptors a scout needs to hear from for adoption
+    phase1_quorum: u32,
+
+    // number of acceptors a commander needs to hear from for a decision
+    phase2_quorum: u32,
 
 
     // ballot num 
@@ -46,367 +116,548 @@ pub struct Context {
     active: bool,
 
     // a map of slot numbers to proposed commands
-    proposals: HashMap<u32, Command>,
+    proposals: HashMap<u32, Command<S::Command>>,
+
+    // all slots up to the watermark have been decided by the commanders of this leader
+    // sent along with P1a so the acceptors only return the slots above it
+    slot_watermark: u32,
 
-    // handle for the broadcast channel between all replicas and the leader
-    replica_leader_broadcast_chan_receiver: Vec<Receiver<Propose>>,
+    // slots above the watermark decided by the commanders of this leader
+    decided_slots: HashSet<u32>,
 
-    // handle to send broadcast messages to replicas
-    // this will go to the commander
-    leader_replica_broadcast_chan_sender: BroadcastSender<Decision>,
+    // commands of the decided slots less than WINDOW below the highest one
+    // a replica proposing for one of them has missed the decision, and maybe every replica has
+    // a replica only proposes within WINDOW of the slot it executes next, so each lower slot has been executed by some replica
+    decided: BTreeMap<u32, Command<S::Command>>,
 
-    // handle to send broadcast messages from scouts to acceptors
-    scout_acceptor_broadcast_chan_sender: BroadcastSender<P1a>,
+    // endpoint of the leader, for everything it and its scouts and commanders send and receive
+    transport: Box<dyn Transport<SystemMessage<S>>>,
 
-    // handle to send broadcast messages from commanders to acceptors
-    commander_acceptor_broadcast_chan_sender: BroadcastSender<P2a>,
+    // all the replicas, decisions are sent to them
+    replicas: Vec<NodeId>,
 
-    // receiving handle for the mpsc channel to commander from all the acceptors
-    acceptor_leader_for_commander_mpsc_chan_receiver: Receiver<P2b>,
+    // all the leaders, this one included, heartbeats are sent to them
+    leaders: Vec<NodeId>,
 
-    // receiving handle for the mpsc channel to scout from all the acceptors
-    acceptor_leader_for_scout_mpsc_chan_receiver: Receiver<P1b>,
+    // all the acceptors, P1a and P2a are sent to them
+    acceptors: Vec<NodeId>,
 
-    // handle for controlling the leader operating state
-    control_chan_receiver: Receiver<ControlSignal>,
+    // messages that arrived while the leader was paused, handled once it runs
+    deferred: VecDeque<SystemMessage<S>>,
 
     // operating state of the leader
     operating_state: OperatingState,
 
-    // sending handles of the channels from the leader to the scouts
-    // for sending P1b
-    leader_to_all_scouts_sender: Vec<Sender<P1b>>,
+    // scouts of this leader that are still waiting for P1b, indexed by scout ID
+    // only the scout for the current ballot is kept
+    scouts: HashMap<u32, scout::Context<S::Command>>,
+
+    // commanders of this leader that are still waiting for P2b, indexed by commander ID
+    commanders: HashMap<u32, commander::Context<S::Command>>,
+
+
+
+    // for failure detection
+
+    // time the last heartbeat was due
+    last_heartbeat_sent: Instant,
+
+    // time the scouts and commanders last sent their messages again
+    last_resent: Instant,
+
+    // time of the last heartbeat received from each leader
+    last_heard: HashMap<u32, Instant>,
+
+    // ballot that preempted the leader
+    // the leader stays passive until the owner of this ballot is suspected or stops making progress
+    waiting_on: Option<Ballot>,
+
+    // time a slot was last decided, by any leader, or the leader started waiting if that was later
+    last_progress: Instant,
+
+    // leader sets of the reconfigs decided by this leader, indexed by the first slot they govern
+    // once the leader has decided every slot it governs it stops sending heartbeats, so that the new leaders take over
+    configs: BTreeMap<u32, Vec<u32>>,
+
+
+
+    // for backoff after preemption
+
+    // policy followed after a preemption
+    backoff_policy: BackoffPolicy,
+
+    // interval between two heartbeats
+    heartbeat_interval: Duration,
+
+    // silence after which another leader is suspected
+    failure_timeout: Duration,
+
+    // time without a decision after which the leader stops waiting on the winner
+    progress_timeout: Duration,
 
-    // receive handles of the channels from the scouts to the leader
-    // the channel will  be shared between all the scouts
-    all_scouts_leader_receiver: Receiver<ScoutMessage>,
-    // clone of this sender handle will be shared with all scouts
-    all_scouts_leader_sender: Sender<ScoutMessage>,
+    // time at which the next scout is due, set by the randomized exponential backoff
+    scout_due: Option<Instant>,
 
-    // sending handles of the channels from the leader to the commanders
-    leader_to_all_commanders_sender: Vec<Sender<P2b>>,
+    // number of preemptions since the last adoption
+    consecutive_preemptions: u32,
 
-    // receive handles of the channels from the commanders to the leader
-    // the channel will  be shared between all the commanders
-    all_commanders_leader_receiver: Receiver<Preempted>,
-    // clone of this sender handle will be shared with all commanders
-    all_commanders_leader_sender: Sender<Preempted>,
+    // random number generator for the backoff delays
+    rng: StdRng,
+
+    // counters for the ballot churn
+    ballot_churn: Arc<BallotChurn>,
 
 }
 
-pub fn new(
+#[allow(clippy::too_many_arguments)]
+pub fn new<S: StateMachine>(
     id: u32,
+    transport: Box<dyn Transport<SystemMessage<S>>>,
+    num_replicas: u32,
+    num_leaders: u32,
     num_acceptors: u32,
-    replica_leader_broadcast_chan_receiver: Vec<Receiver<Propose>>,
-    leader_replica_broadcast_chan_sender: BroadcastSender<Decision>,
-    scout_acceptor_broadcast_chan_sender: BroadcastSender<P1a>,
-    commander_acceptor_broadcast_chan_sender: BroadcastSender<P2a>,
-    acceptor_leader_for_commander_mpsc_chan_receiver: Receiver<P2b>,
-    acceptor_leader_for_scout_mpsc_chan_receiver: Receiver<P1b>,
-    control_chan_receiver: Receiver<ControlSignal>,
-) -> Context {
-    let (all_scouts_leader_sender, all_scouts_leader_receiver) = unbounded();
-    let (all_commanders_leader_sender, all_commanders_leader_receiver) = unbounded();
-
-    let context = Context {
+    options: &SystemOptions,
+    ballot_churn: Arc<BallotChurn>,
+) -> Context<S> {
+    let (phase1_quorum, phase2_quorum) = options.quorums(num_acceptors);
+    let now = transport.now();
+    let rng = match options.seed {
+        Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(id as u64)),
+        None => StdRng::from_entropy(),
+    };
+    Context {
         id,
-        num_acceptors,
+        phase1_quorum,
+        phase2_quorum,
         scout_id: 0u32,
         commander_id: 0u32,
         ballot_num: Ballot::create(id),
         active: false,
         proposals: HashMap::new(),
-        replica_leader_broadcast_chan_receiver,
-        leader_replica_broadcast_chan_sender,
-        scout_acceptor_broadcast_chan_sender,
-        commander_acceptor_broadcast_chan_sender,
-        acceptor_leader_for_commander_mpsc_chan_receiver,
-        acceptor_leader_for_scout_mpsc_chan_receiver,
-        control_chan_receiver,
+        slot_watermark: 0u32,
+        decided_slots: HashSet::new(),
+        decided: BTreeMap::new(),
+        transport,
+        replicas: transport::nodes(NodeId::Replica, num_replicas),
+        leaders: transport::nodes(NodeId::Leader, num_leaders),
+        acceptors: transport::nodes(NodeId::Acceptor, num_acceptors),
+        deferred: VecDeque::new(),
         operating_state: OperatingState::Paused,
-        leader_to_all_scouts_sender: Vec::new(),
-        leader_to_all_commanders_sender: Vec::new(),
-        all_scouts_leader_receiver,
-        all_scouts_leader_sender,
-        all_commanders_leader_receiver,
-        all_commanders_leader_sender,
-    };
-
-    context
+        scouts: HashMap::new(),
+        commanders: HashMap::new(),
+        last_heartbeat_sent: now,
+        last_resent: now,
+        last_heard: HashMap::new(),
+        waiting_on: None,
+        last_progress: now,
+        configs: BTreeMap::new(),
+        backoff_policy: options.backoff_policy.clone(),
+        heartbeat_interval: options.heartbeat_interval,
+        failure_timeout: options.failure_timeout,
+        progress_timeout: options.progress_timeout,
+        scout_due: None,
+        consecutive_preemptions: 0u32,
+        rng,
+        ballot_churn,
+    }
 }
 
-impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
-            .spawn(move || {
-                loop {
-                    match self.operating_state {
-                        OperatingState::Paused => {
-                            // println!("Leader {} in paused mode", self.id);
-                            let signal = self.control_chan_receiver.recv().unwrap();
-                            self.handle_control_signal(signal);
-                        }
+impl<S: StateMachine> Context<S> {
+    pub fn start(self) -> thread::JoinHandle<()> {
+        node::spawn(Box::new(self))
+    }
 
-                        OperatingState::Run(num_msgs) => {
-                            // analyzing under various control channel state
-                            match self.control_chan_receiver.try_recv() {
-                                Ok(signal) => {
-                                    // Exit signal received
-                                    self.handle_control_signal(signal);
-                                }
-
-                                // empty control channel, continue interacting with the replicas/acceptors
-                                Err(TryRecvError::Empty) => {
-                                    self.relaying_messages();
-                                    self.processing_messages(); 
-                                }
-
-                                // Disconnected control channel
-                                Err(TryRecvError::Disconnected) => {
-                                    panic!("Leader {} control channel detached", self.id)
-                                }
-                            }
-                        }
 
-                        OperatingState::Exit => {
-                            println!("Leader {} deactivated.......................", self.id);
-                            break;
+
+
+    // routing the P1b to the scout it is meant for
+    // replies to scouts that are gone are dropped
+    fn processing_p1b_message_from_acceptor(&mut self, message: P1b<S::Command>) {
+        let scout_id = message.get_scout_id() as u32;
+        let outcome = match self.scouts.get_mut(&scout_id) {
+            Some(scout) => scout.handle_p1b(message),
+            None => None,
+        };
+        if let Some(outcome) = outcome {
+            self.scouts.remove(&scout_id);
+            self.handle_scout_message(outcome);
+        }
+    }
+
+
+
+    // routing the P2b to the commander it is meant for
+    // replies to commanders that are gone are dropped
+    fn processing_p2b_message_from_acceptor(&mut self, message: P2b) {
+        let commander_id = message.get_commander_id() as u32;
+        let outcome = match self.commanders.get_mut(&commander_id) {
+            Some(commander) => commander.handle_p2b(message),
+            None => None,
+        };
+        if let Some(outcome) = outcome {
+            self.commanders.remove(&commander_id);
+            self.handle_commander_message(outcome);
+        }
+    }
+
+
+
+
+    // propose message from replica
+    // slots already decided by this leader keep their command, the replica proposing for one gets the decision again
+    fn processing_propose_message_from_replica(&mut self, message: Propose<S::Command>) {
+        // println!("Leader {} has received propose message", self.id);
+        let slot = message.get_slot();
+        if let Some(command) = self.decided.get(&slot) {
+            let decision = Decision::create(slot, command.clone());
+            self.transport.send(NodeId::Replica(message.get_replica_id()), Message::Decision(decision));
+        } else if !self.proposals.contains_key(&slot) && !self.is_decided(slot) {
+            self.proposals.insert(slot, message.get_command());
+            if self.active {
+                // spawn the commander
+                self.spawn_commander(slot, message.get_command());
+            }
+        }
+    }
+
+
+
+    // adopted and preempted message from a scout
+    fn handle_scout_message(&mut self, message: ScoutMessage<S::Command>) {
+        match message {
+            ScoutMessage::Adopted(adopted) => {
+                
+                if adopted.get_ballot() == self.ballot_num.clone() {
+                    // if an adopted message arrives for an old ballot number, it is ignored
+
+                    let pmax_pvals = self.pmax(adopted.get_pvalues());
+
+                    // first remove Key-Value pair in proposal for which there exists key-value pair in 
+                    // pmax_pvals with same key (value might be different)
+                    // sanitization of proposals hashmap
+                    for slot in pmax_pvals.keys() {
+                        self.proposals.remove(slot);
+                    }
+                    // insert the elements of pmax_pvals into proposals
+                    // slots below the watermark may still come from a scout spawned before it moved
+                    for slot in pmax_pvals.keys() {
+                        if *slot > self.slot_watermark {
+                            let command = pmax_pvals.get(slot).unwrap();
+                            self.proposals.insert(*slot, command.clone());
                         }
                     }
 
-                    thread::sleep(Duration::from_nanos(100));
+                    // commanders of older ballots are replaced by the ones spawned below
+                    let ballot_num = self.ballot_num.clone();
+                    self.commanders.retain(|_, commander| commander.get_ballot_num() == ballot_num);
+
+                    // spawning commander for every element in proposals
+                    let mut slots: Vec<u32> = self.proposals.keys().cloned().collect();
+                    slots.sort_unstable();
+                    for slot in slots {
+                        let command = self.proposals.get(&slot).unwrap().clone();
+                        self.spawn_commander(slot, command);
+                    }
+
+                    self.active = true;
+                    self.ballot_churn.active.store(true, Ordering::SeqCst);
+                    self.consecutive_preemptions = 0;
+                    self.ballot_churn.adoptions.fetch_add(1, Ordering::SeqCst);
                 }
-            })
-            .unwrap();
+
+            }
+
+            ScoutMessage::Preempted(preempted) => {
+                self.handle_preempted(preempted.get_ballot());
+            }
+        }
     }
 
 
 
+    // decided and preempted message from a commander
+    fn handle_commander_message(&mut self, message: CommanderMessage<S::Command>) {
+        match message {
+            CommanderMessage::Decided(decision) => {
+                // broadcast to all replicas, and to the other leaders so that the passive ones see the progress
+                self.transport.broadcast(&self.replicas, Message::Decision(decision.clone()));
+                let others: Vec<NodeId> = self.leaders.iter().cloned().filter(|leader| *leader != self.get_id()).collect();
+                self.transport.broadcast(&others, Message::Decision(decision.clone()));
 
- 
+                self.processing_decision(decision);
+            }
 
+            CommanderMessage::Preempted(preempted) => {
+                self.handle_preempted(preempted.get_ballot());
+            }
+        }
+    }
 
 
-    // relaying P1b and P2b to scouts and commanders
-    fn relaying_messages(&mut self) {
 
-        // relaying P1b to scouts
-        // checking whether any P1b has been sent by any acceptor 
-        match &self.acceptor_leader_for_scout_mpsc_chan_receiver.try_recv() {
-            Ok(message) => {
-                // extracting scout id
-                let scout_id = message.get_scout_id();
-                // using scout id for relaying the message via appropriate handle
-                self.leader_to_all_scouts_sender[scout_id]
-                    .send(message.clone());
-            }
-            _ => {}
+    // a slot decided by a commander of this leader or of another leader
+    fn processing_decision(&mut self, decision: Decision<S::Command>) {
+        let (command, slot) = decision.get_details();
+        if let Operation::Reconfig(leaders) = command.get_operation() {
+            self.configs.insert(slot + WINDOW, leaders);
         }
+        self.decided.insert(slot, command);
+        let highest = *self.decided.keys().next_back().unwrap();
+        self.decided = self.decided.split_off(&(highest + 1).saturating_sub(WINDOW));
+        self.handle_decided(slot);
+        self.last_progress = self.transport.now();
+    }
 
 
-        // relaying P2b to commanders
-        match &self.acceptor_leader_for_commander_mpsc_chan_receiver.try_recv() {
-            Ok(message) => {
-                // extracting commander ID
-                let commander_id = message.get_commander_id();
-                // usig commander id for relaying the message via appropriate handle
-                self.leader_to_all_commanders_sender[commander_id]
-                    .send(message.clone());
-            }
-            _ => {}
+
+    // the slot has been decided
+    // the watermark moves up over every contiguous decided slot, their proposals are not needed anymore
+    fn handle_decided(&mut self, slot: u32) {
+        if slot > self.slot_watermark {
+            self.decided_slots.insert(slot);
+        }
+        while self.decided_slots.remove(&(self.slot_watermark + 1)) {
+            self.slot_watermark += 1;
+            self.proposals.remove(&self.slot_watermark);
         }
+    }
 
+
+
+    // whether the slot is known to be decided by a commander of this leader
+    fn is_decided(&self, slot: u32) -> bool {
+        slot <= self.slot_watermark || self.decided_slots.contains(&slot)
     }
 
 
 
+    // a higher ballot has been seen by a scout or a commander
+    // what happens next is up to the backoff policy
+    fn handle_preempted(&mut self, ballot: Ballot) {
+        if ballot > self.ballot_num {
+            self.active = false;
+            self.ballot_churn.active.store(false, Ordering::SeqCst);
+            self.consecutive_preemptions += 1;
+            self.ballot_churn.preemptions.fetch_add(1, Ordering::SeqCst);
 
+            match self.backoff_policy.clone() {
+                BackoffPolicy::Immediate => {
+                    // getting new ballot number
+                    self.ballot_num = ballot.increment(self.id);
+                    self.spawn_scout();
+                }
 
+                BackoffPolicy::RandomizedExponential { base, max } => {
+                    // getting new ballot number, the scout for it is spawned once the delay is over
+                    self.ballot_num = ballot.increment(self.id);
+                    let exponent = (self.consecutive_preemptions - 1).min(16);
+                    let ceiling = base.checked_mul(1u32 << exponent).unwrap_or(max).min(max);
+                    let delay = ceiling.mul_f64(self.rng.gen::<f64>());
+                    self.scout_due = Some(self.transport.now() + delay);
+                }
 
-    
-    fn processing_messages(&mut self) {
-        
-        // propose message from replica
-        for handle in &self.replica_leader_broadcast_chan_receiver {
-            match handle.try_recv() {
-                Ok(message) => {
-                    // println!("Leader {} has received propose message", self.id);
-                    if self.proposals.contains_key(&message.get_slot()) == false {
-                        self.proposals.insert(message.get_slot(), message.get_command());
-                        if self.active == true {
-                            
-                            // spawn the commander
-                            let (leader_commander_sender, leader_commander_receiver) = unbounded();
-                            let commander_context = commander::new(
-                                                    self.commander_id.clone(),
-                                                    self.id.clone(),
-                                                    self.leader_replica_broadcast_chan_sender.clone(),
-                                                    self.commander_acceptor_broadcast_chan_sender.clone(),
-                                                    leader_commander_receiver,
-                                                    self.all_commanders_leader_sender.clone(),
-                                                    self.ballot_num.clone(),
-                                                    message.get_slot(),
-                                                    message.get_command()
-                                                );
-                            commander_context.start(self.num_acceptors.clone());
-                            self.commander_id = self.commander_id + 1u32;
-                            self.leader_to_all_commanders_sender.push(leader_commander_sender);
-                        }
-                    }
+                BackoffPolicy::WaitForWinner => {
+                    self.waiting_on = Some(ballot);
+                    self.last_progress = self.transport.now();
                 }
-                _ => {}
             }
+        }
+    }
 
+
+
+    // spawning the scout delayed by the randomized exponential backoff
+    fn backoff_timer(&mut self) {
+        if let Some(due) = self.scout_due {
+            if self.transport.now() >= due {
+                self.scout_due = None;
+                self.spawn_scout();
+            }
         }
+    }
 
 
 
+    // scouts and commanders still short of a quorum after the failure timeout ask the silent acceptors again
+    // the P1a, P2a or the reply may have been lost, the acceptors answer a repeated message the same way
+    fn resend_timer(&mut self) {
+        let now = self.transport.now();
+        if now.duration_since(self.last_resent) < self.failure_timeout {
+            return;
+        }
+        self.last_resent = now;
 
-        // adopted and preempted message from scouts
-        match &self.all_scouts_leader_receiver.try_recv() {
-            Ok(message) => {
-                match message {
-                    ScoutMessage::Adopted(adopted) => {
-                        
-                        if adopted.get_ballot() == self.ballot_num.clone() {
-                            // if an adopted message arrives for an old ballot number, it is ignored
-
-                            let pmax_pvals = self.pmax(adopted.get_pvalues());
-
-                            // first remove Key-Value pair in proposal for which there exists key-value pair in 
-                            // pmax_pvals with same key (value might be different)
-                            // sanitization of proposals hashmap
-                            for slot in pmax_pvals.keys() {
-                                self.proposals.remove(slot);
-                            }
-                            // insert the elements of pmax_pvals into proposals
-                            for slot in pmax_pvals.keys() {
-                                let command = pmax_pvals.get(slot).unwrap();
-                                self.proposals.insert(slot.clone(), command.clone());
-                            }
-
-                            // spawning commander for every element in proposals
-                            for slot in self.proposals.keys() {
-                                let command = self.proposals.get(slot).unwrap();
-                                let (leader_commander_sender, leader_commander_receiver) = unbounded();
-                                let commander_context = commander::new(
-                                                        self.commander_id.clone(),
-                                                        self.id.clone(),
-                                                        self.leader_replica_broadcast_chan_sender.clone(),
-                                                        self.commander_acceptor_broadcast_chan_sender.clone(),
-                                                        leader_commander_receiver,
-                                                        self.all_commanders_leader_sender.clone(),
-                                                        self.ballot_num.clone(),
-                                                        slot.clone(),
-                                                        command.clone(),
-                                                    );
-                                commander_context.start(self.num_acceptors.clone());
-                                self.commander_id = self.commander_id + 1u32;
-                                self.leader_to_all_commanders_sender.push(leader_commander_sender);
-
-                            }
-
-                            self.active = true;
-                        }
+        for scout in self.scouts.values() {
+            scout.resend(self.transport.as_ref(), &self.acceptors);
+        }
+        // in slot order, so that the resends don't depend on the order of the map
+        let mut commander_ids: Vec<u32> = self.commanders.keys().cloned().collect();
+        commander_ids.sort_unstable();
+        for commander_id in commander_ids {
+            self.commanders[&commander_id].resend(self.transport.as_ref(), &self.acceptors);
+        }
+    }
 
-                    }
 
-                    ScoutMessage::Preempted(preempted) => {
-                        if preempted.get_ballot() > self.ballot_num.clone() {
-                            self.active = false;
-                            // getting new ballot number
-                            self.ballot_num = preempted.get_ballot().increment(self.id);
-
-
-                            // spawn scout
-                            let (leader_scout_sender, leader_scout_receiver) = unbounded();
-                            let scout_context = scout::new(
-                                                self.scout_id.clone(),
-                                                self.id.clone(),
-                                                self.scout_acceptor_broadcast_chan_sender.clone(),
-                                                leader_scout_receiver,
-                                                self.all_scouts_leader_sender.clone(),
-                                                self.ballot_num.clone(),
-                                            );
-                            scout_context.start(self.num_acceptors.clone());
-                            self.scout_id  = self.scout_id + 1u32;
-                            self.leader_to_all_scouts_sender.push(leader_scout_sender);
-                        }
-                    }
-                }
+
+    // sending heartbeats to all leaders and checking on the leader holding the ballot we are waiting on
+    fn failure_detection(&mut self) {
+        let now = self.transport.now();
+
+        // a retired leader skips its heartbeats but keeps the schedule
+        if now.duration_since(self.last_heartbeat_sent) >= self.heartbeat_interval {
+            if !self.is_retired() {
+                self.transport.broadcast(&self.leaders, Message::Heartbeat(Heartbeat::create(self.id)));
             }
-            _ => {}
+            self.last_heartbeat_sent = now;
         }
 
+        if let Some(ballot) = self.waiting_on.clone() {
+            if self.is_suspected(ballot.get_leader_id(), now) || self.is_stalled(now) {
+                // println!("Leader {} suspects leader {}", self.id, ballot.get_leader_id());
+                self.waiting_on = None;
+                // getting new ballot number
+                self.ballot_num = ballot.increment(self.id);
+                self.spawn_scout();
+            }
+        }
+    }
 
 
 
+    // whether the configuration in force after the contiguous decided slots leaves this leader out
+    fn is_retired(&self) -> bool {
+        match self.configs.range(..=self.slot_watermark + 1).next_back() {
+            Some((_, leaders)) => !leaders.contains(&self.id),
+            None => false,
+        }
+    }
 
-        // preempted message from commander
-        match &self.all_commanders_leader_receiver.try_recv() {
-            Ok(preempted) => {
-                if preempted.get_ballot() > self.ballot_num.clone() {
-                    self.active = false;
-                    // getting new ballot number
-                    self.ballot_num = preempted.get_ballot().increment(self.id);
-
-                    // spawn scout
-                    let (leader_scout_sender, leader_scout_receiver) = unbounded();
-                    let scout_context = scout::new(
-                                        self.scout_id.clone(),
-                                        self.id.clone(),
-                                        self.scout_acceptor_broadcast_chan_sender.clone(),
-                                        leader_scout_receiver,
-                                        self.all_scouts_leader_sender.clone(),
-                                        self.ballot_num.clone(),
-                                    );
-                    scout_context.start(self.num_acceptors.clone());
-                    self.scout_id  = self.scout_id + 1u32;
-                    self.leader_to_all_scouts_sender.push(leader_scout_sender);
-                }
-            }
-            _ => {} 
+
+
+    // whether proposals have been waiting on a decision for longer than the progress timeout
+    // a winner still heartbeating may not reach enough acceptors to decide anything
+    fn is_stalled(&self, now: Instant) -> bool {
+        now.duration_since(self.last_progress) > self.progress_timeout
+            && self.proposals.keys().any(|slot| !self.is_decided(*slot))
+    }
+
+
+
+    // a leader is suspected if no heartbeat has arrived from it for the failure timeout
+    // a stale ballot of this leader itself is never worth waiting on
+    fn is_suspected(&self, leader_id: u32, now: Instant) -> bool {
+        if leader_id == self.id {
+            return true;
+        }
+        match self.last_heard.get(&leader_id) {
+            Some(last) => now.duration_since(*last) > self.failure_timeout,
+            None => true,
+        }
+    }
+
+
+
+    // spawn a commander for the slot with the current ballot
+    // slots already decided by this leader don't need one
+    fn spawn_commander(&mut self, slot: u32, command: Command<S::Command>) {
+        if self.is_decided(slot) {
+            return;
         }
+        let commander_context = commander::new(
+                                self.commander_id,
+                                self.id,
+                                self.ballot_num.clone(),
+                                slot,
+                                command,
+                                self.phase2_quorum,
+                            );
+        commander_context.start(self.transport.as_ref(), &self.acceptors);
+        self.commanders.insert(self.commander_id, commander_context);
+        self.commander_id += 1u32;
+    }
+
 
+
+    // spawn a scout for the current ballot
+    // scouts of older ballots can't get adopted anymore and are dropped
+    fn spawn_scout(&mut self) {
+        let scout_context = scout::new(
+                            self.scout_id,
+                            self.id,
+                            self.ballot_num.clone(),
+                            self.slot_watermark,
+                            self.phase1_quorum,
+                        );
+        scout_context.start(self.transport.as_ref(), &self.acceptors);
+        self.scouts.clear();
+        self.scouts.insert(self.scout_id, scout_context);
+        self.scout_id += 1u32;
+        self.ballot_churn.scouts.fetch_add(1, Ordering::SeqCst);
     }
 
 
 
     // pmax - determining maximum ballot number in each slot
-    // inefficient implementation - can be improved 
-    fn pmax(&self, pvals: Vec<Pvalue>) -> HashMap<u32, Command> {
-        let mut pmax_pvals: HashMap<u32, Command> = HashMap::new();
-
-        // first iteration
-        for elem1 in pvals.iter() {
-            // checking whether slot already present in pmax_pvals
-            if pmax_pvals.contains_key(&elem1.get_slot()) == false {
-                let mut max_ballot_num = elem1.get_ballot_num();
-                let mut max_command = elem1.get_command();
-
-                // second iteration
-                for elem2 in pvals.iter() {
-                    // has to be same slot
-                    if elem2.get_slot() == elem1.get_slot() {
-                        // ballot should be strictly greater
-                        if elem2.get_ballot_num() > elem1.get_ballot_num() {
-                            max_ballot_num = elem2.get_ballot_num();
-                            max_command= elem2.get_command();
-                        }
-                    }
-                }
-
-                pmax_pvals.insert(elem1.get_slot(), max_command);
+    // single pass, keeping the pvalue with the highest ballot seen so far for each slot
+    fn pmax(&self, pvals: Vec<Pvalue<S::Command>>) -> HashMap<u32, Command<S::Command>> {
+        let mut max_pvals: HashMap<u32, Pvalue<S::Command>> = HashMap::new();
+
+        for pvalue in pvals {
+            let newer = match max_pvals.get(&pvalue.get_slot()) {
+                Some(current) => pvalue.get_ballot_num() > current.get_ballot_num(),
+                None => true,
+            };
+            if newer {
+                max_pvals.insert(pvalue.get_slot(), pvalue);
             }
         }
 
-        pmax_pvals
+        max_pvals
+            .into_iter()
+            .map(|(slot, pvalue)| (slot, pvalue.get_command()))
+            .collect()
+    }
+
+
+
 
+
+    // every leader gets a full failure timeout before it can be suspected, then the first scout goes out
+    fn start_leading(&mut self) {
+        let now = self.transport.now();
+        for leader_id in 0..self.leaders.len() as u32 {
+            self.last_heard.insert(leader_id, now);
+        }
+        self.last_heartbeat_sent = now;
+        self.last_resent = now;
+        self.last_progress = now;
+
+        self.spawn_scout();
     }
-    
 
 
 
+    // everything the leader knows about the slots and the other leaders is lost
+    // the ballot and the scout and commander IDs carry on, as if kept on disk, so that none is used twice
+    fn reset(&mut self) {
+        self.active = false;
+        self.ballot_churn.active.store(false, Ordering::SeqCst);
+        self.proposals.clear();
+        self.slot_watermark = 0;
+        self.decided_slots.clear();
+        self.decided.clear();
+        self.deferred.clear();
+        self.scouts.clear();
+        self.commanders.clear();
+        self.last_heard.clear();
+        self.waiting_on = None;
+        self.configs.clear();
+        self.scout_due = None;
+        self.consecutive_preemptions = 0;
+    }
+
+
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
         match signal {
@@ -417,26 +668,106 @@ impl Context {
             ControlSignal::Run(num_msgs) => {
                 println!("Leader {} activated", self.id);
                 self.operating_state = OperatingState::Run(num_msgs);
+                self.start_leading();
 
-                // first spawning of the scout
-                let (leader_scout_sender, leader_scout_receiver) = unbounded();
-                let scout_context = scout::new(
-                                    self.scout_id.clone(),
-                                    self.id.clone(),
-                                    self.scout_acceptor_broadcast_chan_sender.clone(),
-                                    leader_scout_receiver,
-                                    self.all_scouts_leader_sender.clone(),
-                                    self.ballot_num.clone(),
-                                );
-                scout_context.start(self.num_acceptors.clone());
-                self.scout_id  = self.scout_id + 1u32;
-                self.leader_to_all_scouts_sender.push(leader_scout_sender);
+                // the messages deferred while paused come first
+                let deferred = std::mem::take(&mut self.deferred);
+                for message in deferred {
+                    self.handle_message(message);
+                }
+            }
+
+            ControlSignal::Crash => {
+                println!("Leader {} crashed", self.id);
+                self.reset();
+                self.operating_state = OperatingState::Crashed;
+            }
+
+            // a ballot above the last one, the commands proposed with that one may differ from the ones proposed now
+            ControlSignal::Restart => {
+                println!("Leader {} restarted", self.id);
+                self.reset();
+                self.ballot_num = self.ballot_num.increment(self.id);
+                self.operating_state = OperatingState::Run(0);
+                self.start_leading();
             }
 
             ControlSignal::Exit => {
                 // println!("Exit signal at Leader {} received", self.id);
+                println!(
+                    "Leader {} ballot churn: {} preemptions, {} scouts, {} adoptions",
+                    self.id,
+                    self.ballot_churn.get_preemptions(),
+                    self.ballot_churn.get_scouts(),
+                    self.ballot_churn.get_adoptions(),
+                );
+                println!("Leader {} deactivated.......................", self.id);
+                self.ballot_churn.active.store(false, Ordering::SeqCst);
                 self.operating_state = OperatingState::Exit;
             }
+
+            ControlSignal::Wakeup => {}
         }
     }
 }
+
+
+
+
+impl<S: StateMachine> Node<S> for Context<S> {
+    fn get_id(&self) -> NodeId {
+        NodeId::Leader(self.id)
+    }
+
+    fn transport(&self) -> &dyn Transport<SystemMessage<S>> {
+        self.transport.as_ref()
+    }
+
+    fn handle_message(&mut self, message: SystemMessage<S>) {
+        match (&self.operating_state, message) {
+            // a crashed leader only comes back on the restart signal, or exits
+            (OperatingState::Crashed, Message::Control(signal @ (ControlSignal::Restart | ControlSignal::Exit))) => {
+                self.handle_control_signal(signal)
+            }
+            (OperatingState::Crashed, _) => {}
+            (_, Message::Control(signal)) => self.handle_control_signal(signal),
+            // only control signals are handled while paused, the rest waits until the leader runs
+            (OperatingState::Paused, message) => self.deferred.push_back(message),
+            (OperatingState::Run(_), Message::Propose(propose)) => self.processing_propose_message_from_replica(propose),
+            (OperatingState::Run(_), Message::P1b(p1b)) => self.processing_p1b_message_from_acceptor(p1b),
+            (OperatingState::Run(_), Message::P2b(p2b)) => self.processing_p2b_message_from_acceptor(p2b),
+            (OperatingState::Run(_), Message::Decision(decision)) => self.processing_decision(decision),
+            (OperatingState::Run(_), Message::Heartbeat(heartbeat)) => {
+                let now = self.transport.now();
+                self.last_heard.insert(heartbeat.get_leader_id(), now);
+            }
+            // nothing else is meant for a leader
+            _ => {}
+        }
+    }
+
+    fn handle_timers(&mut self) {
+        if let OperatingState::Run(_) = self.operating_state {
+            self.failure_detection();
+            self.backoff_timer();
+            self.resend_timer();
+        }
+    }
+
+    // the next heartbeat, which is also when a suspected leader is noticed, the delayed scout or the resends
+    fn next_wakeup(&self) -> Option<Instant> {
+        match self.operating_state {
+            OperatingState::Run(_) => {
+                let heartbeat_due = self.last_heartbeat_sent + self.heartbeat_interval;
+                let resend_due = self.last_resent + self.failure_timeout;
+                let due = heartbeat_due.min(resend_due);
+                Some(self.scout_due.map_or(due, |scout_due| due.min(scout_due)))
+            }
+            _ => None,
+        }
+    }
+
+    fn has_exited(&self) -> bool {
+        matches!(self.operating_state, OperatingState::Exit)
+    }
+}
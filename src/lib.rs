@@ -1,325 +1,612 @@
 #![allow(unused)]
 
 mod acceptor;
-mod broadcast_channel;
 mod client;
 mod leader;
 mod replica;
 mod utils;
 mod scout;
 mod commander;
+mod storage;
+mod codec;
+mod state_machine;
+mod kv_store;
+mod transport;
+mod channel_transport;
+mod tcp_transport;
+mod config;
+mod node;
+mod simulator;
+mod client_handle;
+mod faults;
+mod history;
+
+
+pub use crate::leader::{BackoffPolicy, BallotChurn};
+pub use crate::storage::{AcceptorState, AcceptorStorage, FileWal, MemoryStorage};
+pub use crate::state_machine::{StateMachine, Counter, CounterCommand, counter_workload};
+pub use crate::client::{Workload, RetryPolicy};
+pub use crate::client_handle::{ClientHandle, ClientOptions, ClientError, Submission};
+pub use crate::kv_store::{KvStore, KvCommand, KvError, KvResult, kv_workload};
+pub use crate::transport::{NodeId, Transport, ClusterSize};
+pub use crate::config::{ClusterConfig, ConfigError};
+pub use crate::simulator::{Simulation, SimulationOptions, DecisionSent};
+pub use crate::faults::{LinkFaults, FaultPlan, FaultyTransport, Partition};
+pub use crate::history::{History, Recorded, NonLinearizable, check_linearizable};
+pub use crate::tcp_transport::{TcpTransport, parse_addrs, format_addrs};
+pub use crate::replica::{SystemMessage, SystemTransport};
+pub use crate::utils::{ControlSignal, Message, Command, Operation, Request, Response};
+pub use crate::codec::{Encode, Decode, Cursor, DecodeError, WIRE_VERSION, to_bytes, from_bytes};
+
+use crate::utils::{Decision, Propose, P1a, P1b, P2a, P2b, Heartbeat};
+use crate::node::Node;
+
+
+use log::{info, warn};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
+// time operation_control gives the clients to get all their commands answered
+pub const OPERATION_TIMEOUT: Duration = Duration::from_secs(30);
 
-use crate::broadcast_channel::BroadcastSender;
-use crate::utils::{Operation, Command, Request, Decision, Response, Propose, P1a, P1b, P2a, P2b};
+// time deactivate gives the threads to exit after the exit signal
+pub const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
 
+// why the clients didn't get all their commands through
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum OperationError {
+    // not every client reported back in time
+    Timeout { clients_done: u32, clients: u32 },
 
-use crossbeam::channel::{unbounded, Receiver, Sender};
-use log::info;
-use std::collections::HashMap;
-use std::thread;
-use std::time::Duration;
-
-// contains the handles for control channels for replica and leaders
-pub struct SystemHandles {
-    client_control_chan_sender: BroadcastSender<client::ControlSignal>,
-    replica_control_chan_sender: BroadcastSender<replica::ControlSignal>,
-    leader_control_chan_sender: BroadcastSender<leader::ControlSignal>,
-    acceptor_control_chan_sender: BroadcastSender<acceptor::ControlSignal>,
+    // commands given up on past their deadline, as (client ID, command ID)
+    CommandsTimedOut(Vec<(u32, u32)>),
+}
+
+impl fmt::Display for OperationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OperationError::Timeout { clients_done, clients } => {
+                write!(f, "only {} of {} clients done in time", clients_done, clients)
+            }
+            OperationError::CommandsTimedOut(commands) => write!(f, "{} commands timed out: {:?}", commands.len(), commands),
+        }
+    }
+}
+
+impl Error for OperationError {}
+
+// knobs of the system that are not counts of nodes
+#[derive(Debug, Clone)]
+pub struct SystemOptions {
+    // what a leader does after being preempted
+    pub backoff_policy: BackoffPolicy,
+
+    // directory for the write-ahead logs of the acceptors
+    // acceptors keep their state only in memory when it is None
+    pub acceptor_wal_dir: Option<PathBuf>,
+
+    // number of executed slots between two snapshots of a replica
+    // decisions below the last snapshot are dropped
+    pub snapshot_interval: u32,
+
+    // interval between two heartbeats of a leader
+    pub heartbeat_interval: Duration,
+
+    // a leader not heard from for this long is suspected to have crashed
+    pub failure_timeout: Duration,
+
+    // a leader waiting on the winner of a ballot stops waiting when nothing is decided for this long while it has proposals
+    pub progress_timeout: Duration,
+
+    // time a replica waits on a missing decision before asking the other replicas for it
+    pub catch_up_timeout: Duration,
+
+    // number of acceptors a scout needs for adoption and a commander for a decision
+    // a majority of the acceptors when None, every phase 1 quorum has to intersect every phase 2 quorum
+    pub phase1_quorum: Option<u32>,
+    pub phase2_quorum: Option<u32>,
+
+    // when the clients send a request again, and when they give up on it
+    pub client_retry: RetryPolicy,
+
+    // seed of the random number generators of the nodes, from entropy when None
+    // the simulator sets it so that a run can be repeated
+    pub seed: Option<u64>,
+}
+
+impl SystemOptions {
+    // the phase 1 and phase 2 quorums with the given number of acceptors
+    pub fn quorums(&self, num_acceptors: u32) -> (u32, u32) {
+        let majority = num_acceptors / 2 + 1;
+        (self.phase1_quorum.unwrap_or(majority), self.phase2_quorum.unwrap_or(majority))
+    }
+}
+
+impl Default for SystemOptions {
+    fn default() -> SystemOptions {
+        SystemOptions {
+            backoff_policy: BackoffPolicy::default(),
+            acceptor_wal_dir: None,
+            snapshot_interval: replica::SNAPSHOT_INTERVAL,
+            heartbeat_interval: leader::HEARTBEAT_INTERVAL,
+            failure_timeout: leader::FAILURE_TIMEOUT,
+            progress_timeout: leader::PROGRESS_TIMEOUT,
+            catch_up_timeout: replica::CATCH_UP_TIMEOUT,
+            phase1_quorum: None,
+            phase2_quorum: None,
+            client_retry: RetryPolicy::default(),
+            seed: None,
+        }
+    }
+}
+
+
+
+
+// contains the endpoint of the control node, for controlling all the other nodes
+// S is the state machine replicated by the replicas
+pub struct SystemHandles<S: StateMachine = Counter> {
+    // endpoint of the control node
+    // control signals and reconfig commands go out, the clients report here once all their commands have been answered
+    transport: SystemTransport<S>,
+
+    // all the nodes spawned, per role
+    clients: Vec<NodeId>,
+    replicas: Vec<NodeId>,
+    leaders: Vec<NodeId>,
+    acceptors: Vec<NodeId>,
+
+    // client ID used for the reconfig commands, no client thread owns it
+    reconfig_client_id: u32,
+    // command ID of the last reconfig command
+    reconfig_command_id: AtomicU32,
+
+    // ballot churn counters of every leader
+    ballot_churn: Vec<Arc<BallotChurn>>,
+
+    // commands of the clients started here and of the client handles, as the clients saw them
+    history: Arc<History<S>>,
+
+    // handles of the threads of all clients, replicas, leaders and acceptors
+    // joined by deactivate
+    threads: Mutex<Vec<thread::JoinHandle<()>>>,
 }
 
-impl SystemHandles {
+impl SystemHandles<Counter> {
     pub fn system_handle_management(
         client_count: usize,
         replica_count: usize,
         leader_count: usize,
         acceptor_count: usize,
     ) -> SystemHandles {
-        // get the client control channels for the clients
-        let (client_control_chan_sender, client_control_chan_receivers) =
-            broadcast_channel::construct::<client::ControlSignal>(client_count.clone() as u32);
-
-        // get the broadcast control channels for the replicas
-        let (replica_control_chan_sender, replica_control_chan_receivers) =
-            broadcast_channel::construct::<replica::ControlSignal>(replica_count.clone() as u32);
+        SystemHandles::system_handle_management_with_options(
+            client_count,
+            replica_count,
+            leader_count,
+            acceptor_count,
+            SystemOptions::default(),
+        )
+    }
 
-        // get the broadcast control channels for the leaders
-        let (leader_control_chan_sender, leader_control_chan_receivers) =
-            broadcast_channel::construct::<leader::ControlSignal>(leader_count.clone() as u32);
 
-        // get the broadcast control channels for the acceptors
-        let (acceptor_control_chan_sender, acceptor_control_chan_receivers) =
-            broadcast_channel::construct::<acceptor::ControlSignal>(acceptor_count.clone() as u32);
 
 
+    pub fn system_handle_management_with_options(
+        client_count: usize,
+        replica_count: usize,
+        leader_count: usize,
+        acceptor_count: usize,
+        options: SystemOptions,
+    ) -> SystemHandles<Counter> {
+        SystemHandles::system_handle_management_with_state_machine(
+            client_count,
+            replica_count,
+            leader_count,
+            acceptor_count,
+            options,
+            Counter::default,
+            Arc::new(counter_workload),
+        )
+    }
+}
 
 
 
-        // get the vector of handles and then reverse it
-        // reversing eases the assignment of the handles to the clients, replicas, leaders and acceptors by pop later
-        let mut split_client_control_chan_receivers = client_control_chan_receivers.handle_split();
-        let mut split_replica_control_chan_receivers =
-            replica_control_chan_receivers.handle_split();
-        let mut split_leader_control_chan_receivers = leader_control_chan_receivers.handle_split();
-        let mut split_acceptor_control_chan_receivers =
-            acceptor_control_chan_receivers.handle_split();
-        split_client_control_chan_receivers.reverse();
-        split_replica_control_chan_receivers.reverse();
-        split_leader_control_chan_receivers.reverse();
-        split_acceptor_control_chan_receivers.reverse();
 
+impl<S: StateMachine> SystemHandles<S> {
+    // the nodes declared in the config, all in this process, addresses are ignored
+    pub fn from_config<F: Fn() -> S>(
+        config: &ClusterConfig,
+        new_state_machine: F,
+        workload: Workload<S::Command>,
+    ) -> SystemHandles<S> {
+        SystemHandles::system_handle_management_with_state_machine(
+            config.cluster.clients as usize,
+            config.cluster.replicas as usize,
+            config.cluster.leaders as usize,
+            config.cluster.acceptors as usize,
+            config.options.clone(),
+            new_state_machine,
+            workload,
+        )
+    }
 
 
 
-        // hashmap for collecting all broadcast channel receiver handles for replicas while iterating over clients
-        let mut hashmap_client_replica_broadcast_chan_receivers: HashMap<usize, Vec<Receiver<Request>>> =
-            HashMap::new();
-        // hashmap for collecting all broadcast channel receiver handles for leaders while iterating over replicas
-        let mut hashmap_replica_leader_broadcast_chan_receivers: HashMap<usize, Vec<Receiver<Propose>>> =
-            HashMap::new();
-        // hashmap for collecting all broadcast channel receiver handles for acceptors while iterating over leaders
-        let mut hashmap_leader_acceptor_broadcast_chan_receivers: HashMap<usize, Vec<Receiver<u8>>> = 
-            HashMap::new();
-        let mut hashmap_scout_acceptor_broadcast_chan_receivers: HashMap<usize, Vec<Receiver<P1a>>> = 
-            HashMap::new();
-        let mut hashmap_commander_acceptor_broadcast_chan_receivers: HashMap<usize, Vec<Receiver<P2a>>> = 
-            HashMap::new();
 
+    // every replica gets its own state machine from new_state_machine
+    // the clients send the commands generated by workload
+    pub fn system_handle_management_with_state_machine<F: Fn() -> S>(
+        client_count: usize,
+        replica_count: usize,
+        leader_count: usize,
+        acceptor_count: usize,
+        options: SystemOptions,
+        new_state_machine: F,
+        workload: Workload<S::Command>,
+    ) -> SystemHandles<S> {
+        let cluster = ClusterSize {
+            clients: client_count as u32,
+            replicas: replica_count as u32,
+            leaders: leader_count as u32,
+            acceptors: acceptor_count as u32,
+        };
+
+        // one endpoint of the in-process transport for every node, and one for the control node kept by the SystemHandles
+        let mut endpoints = channel_transport::construct::<SystemMessage<S>>(&cluster.nodes());
+        let control: SystemTransport<S> = Box::new(endpoints.remove(&NodeId::Control).unwrap());
+        let local_nodes = endpoints
+            .into_iter()
+            .map(|(node, endpoint)| (node, Box::new(endpoint) as SystemTransport<S>))
+            .collect();
+
+        SystemHandles::with_transports(control, cluster, local_nodes, options, new_state_machine, workload)
+    }
 
 
-        // vector for collecting all the sender handles of the mpsc channels from replica to all clients
-        // the sender handles of this channel will be cloned to all the replicas
-        let mut replica_all_clients_mpsc_chan_senders: Vec<Sender<Response>> = Vec::new();
 
-        // vector for collecting all the sender handles of the mpsc channels from acceptors to all leaders
-        // the sender handles of this channel will be cloned to all the replicas
-        let mut acceptor_all_leaders_for_commanders_mpsc_chan_senders: Vec<Sender<P2b>> = Vec::new();
-        let mut acceptor_all_leaders_for_scouts_mpsc_chan_senders: Vec<Sender<P1b>> = Vec::new();
 
+    // every node of the cluster on an endpoint passing its messages through the faults of the plan
+    // the fate of the messages on every link is drawn from the seed, see FaultyTransport
+    // the partitions of the plan are scheduled from the moment the cluster is constructed
+    pub fn with_faults<F: Fn() -> S>(
+        cluster: ClusterSize,
+        options: SystemOptions,
+        plan: FaultPlan,
+        seed: u64,
+        new_state_machine: F,
+        workload: Workload<S::Command>,
+    ) -> SystemHandles<S> {
+        // the links of the control node are reliable, its endpoint needs no wrapping
+        let mut endpoints = channel_transport::construct::<SystemMessage<S>>(&cluster.nodes());
+        let control: SystemTransport<S> = Box::new(endpoints.remove(&NodeId::Control).unwrap());
+        let start = Instant::now();
+        let local_nodes = endpoints
+            .into_iter()
+            .map(|(node, endpoint)| {
+                let faulty = FaultyTransport::wrap(Box::new(endpoint), plan.clone(), seed, start);
+                (node, Box::new(faulty) as SystemTransport<S>)
+            })
+            .collect();
+
+        SystemHandles::with_transports(control, cluster, local_nodes, options, new_state_machine, workload)
+    }
 
-        // hashmap for collecting all broadcast channel sender handles for leaders while iterating over leaders
-        let mut hashmap_leader_replica_broadcast_chan_senders: HashMap<usize, BroadcastSender<Decision>> =
-            HashMap::new();
-        // hashmap for collecting all broadcast channel receiver handles for replicas while iterating over leaders
-        let mut hashmap_leader_replica_broadcast_chan_receivers: HashMap<usize, Vec<Receiver<Decision>>> =
-            HashMap::new();
 
 
 
+    // the replicas, leaders and acceptors of the cluster, with a ClientHandle in place of every client thread
+    // the nodes still wait for activate, the handles are ready right away and deactivate closes them
+    // wait_for_clients is of no use, the handles never report being done
+    pub fn with_client_handles<F: Fn() -> S>(
+        cluster: ClusterSize,
+        options: SystemOptions,
+        new_state_machine: F,
+        client_options: ClientOptions,
+    ) -> (SystemHandles<S>, Vec<ClientHandle<S>>) {
+        let mut endpoints = channel_transport::construct::<SystemMessage<S>>(&cluster.nodes());
+        let control: SystemTransport<S> = Box::new(endpoints.remove(&NodeId::Control).unwrap());
 
+        let mut local_nodes = Vec::new();
+        let mut client_endpoints = Vec::new();
+        for (node, endpoint) in endpoints {
+            match node {
+                NodeId::Client(client_id) => client_endpoints.push((client_id, endpoint)),
+                _ => local_nodes.push((node, Box::new(endpoint) as SystemTransport<S>)),
+            }
+        }
+        // no client thread is started, nothing generates commands
+        let workload: Workload<S::Command> = Arc::new(|_| unreachable!("The clients are handles"));
+        let handles = SystemHandles::with_transports(control, cluster, local_nodes, options, new_state_machine, workload);
+
+        // the handles record into the history of the system
+        client_endpoints.sort_by_key(|(client_id, _)| *client_id);
+        let clients = client_endpoints
+            .into_iter()
+            .map(|(client_id, endpoint)| {
+                ClientHandle::with_history(
+                    client_id,
+                    Box::new(endpoint),
+                    cluster.replicas,
+                    client_options.clone(),
+                    handles.history.clone(),
+                )
+            })
+            .collect();
+        (handles, clients)
+    }
 
 
 
-        // iterating over the leader
-        for leader_id in 0..leader_count {
-            // get the broadcasts channel from the leader to replicas
-            let (leader_replica_broadcast_chan_sender, leader_replica_broadcast_chan_receivers) =
-                broadcast_channel::construct::<Decision>(replica_count.clone() as u32);
 
-            // collect the sender handles for the leaders
-            hashmap_leader_replica_broadcast_chan_senders
-                .insert(leader_id, leader_replica_broadcast_chan_sender);
+    // the nodes in local_nodes are started in this process, each on the endpoint given with it
+    // the rest of the cluster runs elsewhere, started by start_node, and is reached through the transport only
+    pub fn with_transports<F: Fn() -> S>(
+        control: SystemTransport<S>,
+        cluster: ClusterSize,
+        local_nodes: Vec<(NodeId, SystemTransport<S>)>,
+        options: SystemOptions,
+        new_state_machine: F,
+        workload: Workload<S::Command>,
+    ) -> SystemHandles<S> {
+        // ballot churn counters of all leaders, the ones of leaders running elsewhere stay at zero
+        let ballot_churn: Vec<Arc<BallotChurn>> =
+            (0..cluster.leaders).map(|_| Arc::new(BallotChurn::default())).collect();
+        let history = Arc::new(History::new());
+
+        // starting the local nodes in role order: clients, replicas, leaders, acceptors
+        let mut local_nodes = local_nodes;
+        local_nodes.sort_by_key(|(node, _)| *node);
+        let threads = local_nodes
+            .into_iter()
+            .map(|(node, endpoint)| {
+                let leader_ballot_churn = match node {
+                    NodeId::Leader(leader_id) => ballot_churn[leader_id as usize].clone(),
+                    _ => Arc::new(BallotChurn::default()),
+                };
+                start_node_with_churn(
+                    node,
+                    endpoint,
+                    cluster,
+                    &options,
+                    &new_state_machine,
+                    workload.clone(),
+                    leader_ballot_churn,
+                    history.clone(),
+                )
+            })
+            .collect();
 
-            // collect the receiver handles for the replicas
-            let mut split_leader_replica_broadcast_chan_receivers =
-                leader_replica_broadcast_chan_receivers.handle_split();
-            split_leader_replica_broadcast_chan_receivers.reverse();
-            hashmap_leader_replica_broadcast_chan_receivers
-                .insert(leader_id, split_leader_replica_broadcast_chan_receivers);
+        SystemHandles {
+            transport: control,
+            clients: transport::nodes(NodeId::Client, cluster.clients),
+            replicas: transport::nodes(NodeId::Replica, cluster.replicas),
+            leaders: transport::nodes(NodeId::Leader, cluster.leaders),
+            acceptors: transport::nodes(NodeId::Acceptor, cluster.acceptors),
+            reconfig_client_id: cluster.clients,
+            reconfig_command_id: AtomicU32::new(0),
+            ballot_churn,
+            history,
+            threads: Mutex::new(threads),
         }
+    }
 
 
 
 
+    // submit a reconfig command replacing the leader set
+    // the new leaders govern the slots from WINDOW slots after the one the command is decided in
+    pub fn reconfigure(&self, leaders: Vec<u32>) {
+        assert!(!leaders.is_empty(), "Reconfig needs at least one leader");
+        for leader_id in &leaders {
+            assert!(self.leaders.contains(&NodeId::Leader(*leader_id)), "Leader {} doesn't exist", leader_id);
+        }
 
+        let command_id = self.reconfig_command_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let command = Command::create(self.reconfig_client_id, command_id, Operation::Reconfig(leaders));
+        self.transport.broadcast(&self.replicas, Message::Request(Request::create(command)));
+    }
 
 
 
 
-        // iterate over each client
-        for client_id in 0..client_count {
-            // get the broadcast channel from current client to all replicas
-            let (client_replica_broadcast_chan_sender, client_replica_broadcast_chan_receivers) =
-                broadcast_channel::construct::<Request>(replica_count.clone() as u32);
 
-            // get the mpsc channel from replicas to client
-            let (replica_client_mpsc_chan_sender, replica_client_mpsc_chan_receiver) = unbounded();
-            // inserting the handle for the chanel into the vec, to be used in replica
-            replica_all_clients_mpsc_chan_senders.push(replica_client_mpsc_chan_sender);
 
-            // build the client
-            let client_context = client::new(
-                client_id as u32,
-                client_replica_broadcast_chan_sender,
-                replica_client_mpsc_chan_receiver,
-                split_client_control_chan_receivers.pop().unwrap(),
-            );
 
-            // start the client in paused mode
-            client_context.start();
+    // runs the clients through num_broadcasts commands each and stops everything, whatever happened
+    pub fn operation_control(
+        &self,
+        num_broadcasts: u32,
+        client_count: u32,
+        replica_count: u32,
+        leader_count: u32,
+    ) -> Result<(), OperationError> {
+        self.activate(num_broadcasts, client_count, replica_count, leader_count);
 
-            // collect the receiver handles for replicas in the hashmap
-            let mut split_client_replica_broadcast_chan_receivers =
-                client_replica_broadcast_chan_receivers.handle_split();
-            split_client_replica_broadcast_chan_receivers.reverse();
-            hashmap_client_replica_broadcast_chan_receivers
-                .insert(client_id, split_client_replica_broadcast_chan_receivers);
+        let outcome = self.wait_for_clients(OPERATION_TIMEOUT);
+        if let Err(err) = &outcome {
+            println!("Clients not through: {}, deactivating anyway", err);
         }
 
+        self.deactivate();
+        outcome
+    }
 
 
 
 
+    pub fn activate(
+        &self,
+        num_broadcasts: u32,
+        client_count: u32,
+        replica_count: u32,
+        leader_count: u32,
+    ) {
+        // activating clients, replicas, leaders and acceptors; broadcast will start now
+        self.transport.broadcast(
+            &self.clients,
+            Message::Control(ControlSignal::Run(num_broadcasts)),
+        );
+        self.transport.broadcast(
+            &self.replicas,
+            Message::Control(ControlSignal::Run(client_count * num_broadcasts)),
+        );
+        self.transport.broadcast(
+            &self.leaders,
+            Message::Control(ControlSignal::Run(
+                replica_count * client_count * num_broadcasts,
+            )),
+        );
+        self.transport.broadcast(
+            &self.acceptors,
+            Message::Control(ControlSignal::Run(
+                leader_count * replica_count * client_count * num_broadcasts,
+            )),
+        );
+    }
 
 
 
 
+    // stops every thread and waits up to EXIT_TIMEOUT for all of them to exit
+    // the threads still running after that are kept and counted by running_threads, deactivate may be called again
+    // a panic in any of the threads is propagated
+    pub fn deactivate(&self) {
+        // Exit signal being sent to all
+        for nodes in [&self.clients, &self.replicas, &self.leaders, &self.acceptors] {
+            self.transport.broadcast(nodes, Message::Control(ControlSignal::Exit));
+        }
 
-        // iterate over each replica
-        for replica_id in 0..replica_count {
-            // collect the receiver handles of the broadcast channels from all the clients
-            let mut client_replica_broadcast_chan_receivers: Vec<Receiver<Request>> = Vec::new();
-            for client_id in 0..client_count {
-                // retrieving the entry corresponding to replica_id
-                // this approach taken because HashMap doesn't implement IndexMut trait
-                let mut hashmap_entry_client_id = hashmap_client_replica_broadcast_chan_receivers
-                    .remove(&client_id)
-                    .unwrap();
-
-                // update
-                client_replica_broadcast_chan_receivers
-                    .push(hashmap_entry_client_id.pop().unwrap());
+        // waiting for everyone to exit, join can't time out so the threads are polled
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        while self.running_threads() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
 
-                // insert back the entry corresponding to replica_id
-                hashmap_client_replica_broadcast_chan_receivers
-                    .insert(client_id, hashmap_entry_client_id);
+        let mut threads = self.threads.lock().unwrap();
+        let (finished, running): (Vec<_>, Vec<_>) = threads.drain(..).partition(|handle| handle.is_finished());
+        *threads = running;
+        drop(threads);
+        if self.running_threads() > 0 {
+            warn!("{} threads still running {:?} after the exit signal", self.running_threads(), EXIT_TIMEOUT);
+        }
+        for handle in finished {
+            if let Err(panic) = handle.join() {
+                std::panic::resume_unwind(panic);
             }
+        }
+    }
 
-            // collect the receiver handles of the broadcast channels from all the leaders
-            let mut leader_replica_broadcast_chan_receivers: Vec<Receiver<Decision>> = Vec::new();
-            for leader_id in 0..leader_count {
-                let mut hashmap_entry_leader_id = hashmap_leader_replica_broadcast_chan_receivers
-                    .remove(&leader_id)
-                    .unwrap();
 
-                leader_replica_broadcast_chan_receivers
-                    .push(hashmap_entry_leader_id.pop().unwrap());
 
-                hashmap_leader_replica_broadcast_chan_receivers
-                    .insert(leader_id, hashmap_entry_leader_id);
-            }
 
-            // get the broadcast channel from curent replica to all leaders
-            let (replica_leader_broadcast_chan_sender, replica_leader_broadcast_chan_receivers) =
-                broadcast_channel::construct::<Propose>(leader_count.clone() as u32);
-
-            // build the replica
-            // do note that one clone of replica_all_clients_mpsc_chan_senders is left unassigned to any replica
-            let replica_context = replica::new(
-                replica_id as u32,
-                client_replica_broadcast_chan_receivers,
-                replica_all_clients_mpsc_chan_senders.clone(),
-                replica_leader_broadcast_chan_sender,
-                leader_replica_broadcast_chan_receivers,
-                split_replica_control_chan_receivers.pop().unwrap(),
-            );
+    // deactivate a single leader, as if it had crashed
+    pub fn stop_leader(&self, leader_id: u32) {
+        assert!(self.leaders.contains(&NodeId::Leader(leader_id)), "Leader {} doesn't exist", leader_id);
+        self.transport.send(NodeId::Leader(leader_id), Message::Control(ControlSignal::Exit));
+    }
 
-            // start the replica in paused mode
-            replica_context.start();
 
-            // collect the receiver handles for leaders in the hashmap
-            let mut split_replica_leader_broadcast_chan_receivers =
-                replica_leader_broadcast_chan_receivers.handle_split();
-            split_replica_leader_broadcast_chan_receivers.reverse();
-            hashmap_replica_leader_broadcast_chan_receivers
-                .insert(replica_id, split_replica_leader_broadcast_chan_receivers);
-        }
 
 
+    // crash a single replica, leader or acceptor, it drops everything it receives until restarted
+    // its volatile state is lost, an acceptor keeps what its storage has recorded
+    pub fn crash(&self, node: NodeId) {
+        self.assert_crashable(node);
+        self.transport.send(node, Message::Control(ControlSignal::Crash));
+    }
+
+    // bring a single replica, leader or acceptor back running with its durable state
+    // a node that hasn't crashed loses its volatile state too, as on a reboot
+    pub fn restart(&self, node: NodeId) {
+        self.assert_crashable(node);
+        self.transport.send(node, Message::Control(ControlSignal::Restart));
+    }
+
+    fn assert_crashable(&self, node: NodeId) {
+        let exists = self.replicas.contains(&node) || self.leaders.contains(&node) || self.acceptors.contains(&node);
+        assert!(exists, "{} is not a replica, leader or acceptor of the system", node);
+    }
 
 
 
 
+    // number of threads of this system that haven't exited yet
+    // only the nodes started in this process are counted
+    pub fn running_threads(&self) -> usize {
+        self.threads
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|handle| !handle.is_finished())
+            .count()
+    }
 
 
 
 
+    // number of nodes of every role
+    pub fn client_count(&self) -> u32 {
+        self.clients.len() as u32
+    }
 
-        // iterate over each leader
-        for leader_id in 0..leader_count {
-            // collect the receiver handles of the broadcast channels from all the replicas
-            let mut replica_leader_broadcast_chan_receivers: Vec<Receiver<Propose>> = Vec::new();
+    pub fn replica_count(&self) -> u32 {
+        self.replicas.len() as u32
+    }
 
-            for replica_id in 0..replica_count {
-                // retrieving the entry corresponding to replica_id
-                // this approach taken because HashMap doesn't implement IndexMut trait
-                let mut hashmap_entry_replica_id = hashmap_replica_leader_broadcast_chan_receivers
-                    .remove(&replica_id)
-                    .unwrap();
+    pub fn leader_count(&self) -> u32 {
+        self.leaders.len() as u32
+    }
 
-                // update
-                replica_leader_broadcast_chan_receivers
-                    .push(hashmap_entry_replica_id.pop().unwrap());
 
-                // insert back the entry corresponding to replica_id
-                hashmap_replica_leader_broadcast_chan_receivers
-                    .insert(replica_id, hashmap_entry_replica_id);
-            }
 
-            // get the broadcast channel from curent leader to all acceptors
-            let (leader_acceptor_broadcast_chan_sender, leader_acceptor_broadcast_chan_receivers) =
-                broadcast_channel::construct::<u32>(acceptor_count.clone() as u32);
-            let (scout_acceptor_broadcast_chan_sender, scout_acceptor_broadcast_chan_receivers) =
-                broadcast_channel::construct::<P1a>(acceptor_count.clone() as u32);
-            let (commander_acceptor_broadcast_chan_sender, commander_acceptor_broadcast_chan_receivers) = 
-                broadcast_channel::construct::<P2a>(acceptor_count.clone() as u32);
-
-
-            // get the mpsc channel from acceptors to the leaders
-            let (acceptor_leader_for_commander_mpsc_chan_sender, acceptor_leader_for_commander_mpsc_chan_receiver) 
-                = unbounded();
-            let (acceptor_leader_for_scout_mpsc_chan_sender, acceptor_leader_for_scout_mpsc_chan_receiver)
-                = unbounded();
-            // to be used in the acceptor
-            acceptor_all_leaders_for_commanders_mpsc_chan_senders
-                .push(acceptor_leader_for_commander_mpsc_chan_sender);
-            acceptor_all_leaders_for_scouts_mpsc_chan_senders
-                .push(acceptor_leader_for_scout_mpsc_chan_sender);
-
-
-            // build the leader
-            let leader_context = leader::new(
-                leader_id as u32,
-                acceptor_count as u32,
-                replica_leader_broadcast_chan_receivers,
-                hashmap_leader_replica_broadcast_chan_senders
-                    .remove(&leader_id)
-                    .unwrap(),
-                scout_acceptor_broadcast_chan_sender,
-                commander_acceptor_broadcast_chan_sender,
-                acceptor_leader_for_commander_mpsc_chan_receiver,
-                acceptor_leader_for_scout_mpsc_chan_receiver,
-                split_leader_control_chan_receivers.pop().unwrap(),
-            );
 
-            // start the leader in paused mode
-            leader_context.start();
+    // ballot churn counters of every leader, indexed by leader ID
+    pub fn ballot_churn(&self) -> &[Arc<BallotChurn>] {
+        &self.ballot_churn
+    }
+
+    // the commands of the clients so far, for checking them with History::check
+    pub fn history(&self) -> &Arc<History<S>> {
+        &self.history
+    }
+
 
-            // collect the receiver handles for acceptors in the hashmap
-            let mut split_scout_acceptor_broadcast_chan_receivers = 
-                scout_acceptor_broadcast_chan_receivers.handle_split();
-            split_scout_acceptor_broadcast_chan_receivers.reverse();
-            hashmap_scout_acceptor_broadcast_chan_receivers
-                .insert(leader_id, split_scout_acceptor_broadcast_chan_receivers);
 
 
-            let mut split_commander_acceptor_broadcast_chan_receivers = 
-                commander_acceptor_broadcast_chan_receivers.handle_split();
-            split_commander_acceptor_broadcast_chan_receivers.reverse();
-            hashmap_commander_acceptor_broadcast_chan_receivers
-                .insert(leader_id, split_commander_acceptor_broadcast_chan_receivers);
+    // wait until every client has received the responses for all its commands
+    // an error if that didn't happen within the timeout, or if some commands were given up on
+    pub fn wait_for_clients(&self, timeout: Duration) -> Result<(), OperationError> {
+        let deadline = Instant::now() + timeout;
+        let mut clients_done = 0u32;
+        let mut timed_out = Vec::new();
+        while clients_done < self.client_count() {
+            let now = Instant::now();
+            let message = if now < deadline { self.transport.recv_timeout(deadline - now) } else { None };
+            match message {
+                Some(Message::ClientDone(done)) => {
+                    clients_done += 1;
+                    timed_out.extend(done.get_timed_out().into_iter().map(|command_id| (done.get_client_id(), command_id)));
+                }
+                // nothing else is meant for the control node
+                Some(_) => {}
+                None => return Err(OperationError::Timeout { clients_done, clients: self.client_count() }),
+            }
         }
 
+        if timed_out.is_empty() {
+            Ok(())
+        } else {
+            Err(OperationError::CommandsTimedOut(timed_out))
+        }
+    }
+}
 
 
 
@@ -328,123 +615,436 @@ impl SystemHandles {
 
 
 
+// build the node with the given ID and start it in paused mode on its endpoint
+// used for running a node in a process of its own, the control node has to be in the cluster too
+// every replica gets its own state machine from new_state_machine, the clients send the commands generated by workload
+// a client records its history where nothing reads it, it is only checked for the clients of a SystemHandles or a Simulation
+pub fn start_node<S: StateMachine, F: Fn() -> S>(
+    node: NodeId,
+    endpoint: SystemTransport<S>,
+    cluster: ClusterSize,
+    options: &SystemOptions,
+    new_state_machine: &F,
+    workload: Workload<S::Command>,
+) -> thread::JoinHandle<()> {
+    let history = Arc::new(History::new());
+    start_node_with_churn(node, endpoint, cluster, options, new_state_machine, workload, Arc::new(BallotChurn::default()), history)
+}
 
+#[allow(clippy::too_many_arguments)]
+fn start_node_with_churn<S: StateMachine, F: Fn() -> S>(
+    node: NodeId,
+    endpoint: SystemTransport<S>,
+    cluster: ClusterSize,
+    options: &SystemOptions,
+    new_state_machine: &F,
+    workload: Workload<S::Command>,
+    ballot_churn: Arc<BallotChurn>,
+    history: Arc<History<S>>,
+) -> thread::JoinHandle<()> {
+    node::spawn(build_node(node, endpoint, cluster, options, new_state_machine, workload, ballot_churn, history))
+}
 
+// the role of the node on its endpoint, not running yet
+#[allow(clippy::too_many_arguments)]
+fn build_node<S: StateMachine, F: Fn() -> S>(
+    node: NodeId,
+    endpoint: SystemTransport<S>,
+    cluster: ClusterSize,
+    options: &SystemOptions,
+    new_state_machine: &F,
+    workload: Workload<S::Command>,
+    ballot_churn: Arc<BallotChurn>,
+    history: Arc<History<S>>,
+) -> Box<dyn Node<S>> {
+    match node {
+        NodeId::Control => panic!("The control node is not started, it is driven by the SystemHandles"),
+
+        NodeId::Client(client_id) => {
+            Box::new(client::new::<S>(client_id, endpoint, cluster.replicas, workload, options.client_retry.clone(), history))
+        }
 
+        NodeId::Replica(replica_id) => Box::new(replica::new(
+            replica_id,
+            new_state_machine(),
+            endpoint,
+            cluster.replicas,
+            cluster.leaders,
+            options,
+        )),
+
+        NodeId::Leader(leader_id) => Box::new(leader::new::<S>(
+            leader_id,
+            endpoint,
+            cluster.replicas,
+            cluster.leaders,
+            cluster.acceptors,
+            options,
+            ballot_churn,
+        )),
+
+        NodeId::Acceptor(acceptor_id) => {
+            // storage backend of the acceptor
+            let storage: Box<dyn AcceptorStorage<S::Command>> = match &options.acceptor_wal_dir {
+                Some(dir) => {
+                    let path = dir.join(format!("acceptor-{}.wal", acceptor_id));
+                    Box::new(FileWal::open(&path).unwrap_or_else(|err| {
+                        panic!("Can't open the log {} of acceptor {}: {}", path.display(), acceptor_id, err)
+                    }))
+                }
+                None => Box::new(MemoryStorage),
+            };
+            Box::new(acceptor::new::<S>(acceptor_id, endpoint, storage))
+        }
+    }
+}
 
-        // iterate over each acceptor
-        for acceptor_id in 0..acceptor_count {
-            // collect the receiver handles of the broadcast channels from all the leaders
 
-            let mut scout_acceptor_broadcast_chan_receivers: Vec<Receiver<P1a>> = Vec::new();
-            for leader_id in 0..leader_count {
-                let mut hashmap_entry_scout_id = hashmap_scout_acceptor_broadcast_chan_receivers
-                    .remove(&leader_id)
-                    .unwrap();
-                scout_acceptor_broadcast_chan_receivers
-                    .push(hashmap_entry_scout_id.pop().unwrap());
-                hashmap_scout_acceptor_broadcast_chan_receivers
-                    .insert(leader_id, hashmap_entry_scout_id);
-            }
 
 
-            let mut commander_acceptor_broadcast_chan_receivers: Vec<Receiver<P2a>> = Vec::new();
-            for leader_id in 0..leader_count {
-                let mut hashmap_entry_commander_id = hashmap_commander_acceptor_broadcast_chan_receivers
-                    .remove(&leader_id)
-                    .unwrap();
-                commander_acceptor_broadcast_chan_receivers
-                    .push(hashmap_entry_commander_id.pop().unwrap());
-                hashmap_commander_acceptor_broadcast_chan_receivers
-                    .insert(leader_id, hashmap_entry_commander_id);
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel_transport::{self, ChannelTransport};
+    use crate::codec::{Encode, Decode};
+    use crate::utils::{Ballot, Pvalue};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use std::collections::{HashMap, HashSet};
+    use std::io;
+
+    #[test]
+    fn it_works() {
+        let client_count = 3_usize;
+        let replica_count = 3_usize;
+        let leader_count = 3_usize;
+        let acceptor_count = 3_usize;
+        let num_msgs = 8u32;
+        let system_handles = SystemHandles::system_handle_management(
+            client_count,
+            replica_count,
+            leader_count,
+            acceptor_count,
+        );
+        assert_eq!(
+            system_handles.running_threads(),
+            client_count + replica_count + leader_count + acceptor_count
+        );
+        let outcome = system_handles.operation_control(
+            num_msgs,
+            client_count as u32,
+            replica_count as u32,
+            leader_count as u32,
+        );
+        assert_eq!(outcome, Ok(()));
+        assert_eq!(system_handles.running_threads(), 0);
+    }
 
+    // the endpoint of a node that doesn't take the first exit signal
+    struct IgnoresFirstExit {
+        inner: ChannelTransport<SystemMessage<Counter>>,
+        ignored: Mutex<bool>,
+    }
 
-            // build the acceptor
-            let acceptor_context = acceptor::new(
-                acceptor_id as u32,
-                scout_acceptor_broadcast_chan_receivers,
-                commander_acceptor_broadcast_chan_receivers,
-                acceptor_all_leaders_for_commanders_mpsc_chan_senders.clone(),
-                acceptor_all_leaders_for_scouts_mpsc_chan_senders.clone(),
-                split_acceptor_control_chan_receivers.pop().unwrap(),
-            );
+    impl IgnoresFirstExit {
+        // whether the message is the exit signal to be dropped
+        fn ignores(&self, message: &Option<SystemMessage<Counter>>) -> bool {
+            let mut ignored = self.ignored.lock().unwrap();
+            if let (Some(Message::Control(ControlSignal::Exit)), false) = (message, *ignored) {
+                *ignored = true;
+                return true;
+            }
+            false
+        }
+    }
+
+    impl Transport<SystemMessage<Counter>> for IgnoresFirstExit {
+        fn get_id(&self) -> NodeId {
+            self.inner.get_id()
+        }
 
-            // start the acceptor in paused mode
-            acceptor_context.start();
+        fn send(&self, to: NodeId, message: SystemMessage<Counter>) {
+            self.inner.send(to, message);
         }
 
+        fn recv(&self) -> Option<SystemMessage<Counter>> {
+            loop {
+                let message = self.inner.recv();
+                if !self.ignores(&message) {
+                    return message;
+                }
+            }
+        }
 
+        // the dropped signal looks like nothing having arrived
+        fn recv_timeout(&self, timeout: Duration) -> Option<SystemMessage<Counter>> {
+            let message = self.inner.recv_timeout(timeout);
+            if self.ignores(&message) { None } else { message }
+        }
 
-        SystemHandles {
-            client_control_chan_sender,
-            replica_control_chan_sender,
-            leader_control_chan_sender,
-            acceptor_control_chan_sender,
+        fn try_recv(&self) -> Option<SystemMessage<Counter>> {
+            let message = self.inner.try_recv();
+            if self.ignores(&message) { None } else { message }
         }
     }
 
+    #[test]
+    fn deactivate_keeps_the_threads_that_refuse_to_exit() {
+        let cluster = ClusterSize { clients: 1, replicas: 1, leaders: 1, acceptors: 1 };
+        let mut endpoints = channel_transport::construct::<SystemMessage<Counter>>(&cluster.nodes());
+        let control: SystemTransport<Counter> = Box::new(endpoints.remove(&NodeId::Control).unwrap());
+        let local_nodes = endpoints
+            .into_iter()
+            .map(|(node, endpoint)| {
+                let endpoint: SystemTransport<Counter> = match node {
+                    NodeId::Acceptor(_) => Box::new(IgnoresFirstExit { inner: endpoint, ignored: Mutex::new(false) }),
+                    _ => Box::new(endpoint),
+                };
+                (node, endpoint)
+            })
+            .collect();
+        let system_handles = SystemHandles::with_transports(
+            control,
+            cluster,
+            local_nodes,
+            SystemOptions::default(),
+            Counter::default,
+            Arc::new(counter_workload),
+        );
+        assert_eq!(system_handles.running_threads(), 4);
+
+        // the acceptor is still there after the first exit signal, and goes with the second
+        system_handles.deactivate();
+        assert_eq!(system_handles.running_threads(), 1);
+        system_handles.deactivate();
+        assert_eq!(system_handles.running_threads(), 0);
+    }
+
+    // a node that counts how often its loop hands it something to do
+    struct Counted {
+        node: Box<dyn Node<Counter>>,
+        work: Arc<AtomicU32>,
+    }
 
+    impl Node<Counter> for Counted {
+        fn get_id(&self) -> NodeId {
+            self.node.get_id()
+        }
 
+        fn transport(&self) -> &dyn Transport<SystemMessage<Counter>> {
+            self.node.transport()
+        }
 
+        fn handle_message(&mut self, message: SystemMessage<Counter>) {
+            self.work.fetch_add(1, Ordering::SeqCst);
+            self.node.handle_message(message);
+        }
 
+        fn handle_timers(&mut self) {
+            self.work.fetch_add(1, Ordering::SeqCst);
+            self.node.handle_timers();
+        }
 
+        fn next_wakeup(&self) -> Option<Instant> {
+            self.node.next_wakeup()
+        }
 
-    pub fn operation_control(
-        &self,
-        num_broadcasts: u32,
-        client_count: u32,
-        replica_count: u32,
-        leader_count: u32,
-    ) {
-        // activating clients, replicas, leaders and acceptors; broadcast will start now
-        self.client_control_chan_sender
-            .send(client::ControlSignal::Run(num_broadcasts));
-        self.replica_control_chan_sender
-            .send(replica::ControlSignal::Run(client_count * num_broadcasts));
-        self.leader_control_chan_sender
-            .send(leader::ControlSignal::Run(
-                replica_count * client_count * num_broadcasts,
-            ));
-        self.acceptor_control_chan_sender
-            .send(acceptor::ControlSignal::Run(
-                leader_count * replica_count * client_count * num_broadcasts,
-            ));
+        fn has_exited(&self) -> bool {
+            self.node.has_exited()
+        }
+    }
 
-        thread::sleep(Duration::from_secs(30));
-        
-        // Exit signal being sent to all
-        self.client_control_chan_sender
-            .send(client::ControlSignal::Exit);
-        self.replica_control_chan_sender
-            .send(replica::ControlSignal::Exit);
-        self.leader_control_chan_sender
-            .send(leader::ControlSignal::Exit);
-        self.acceptor_control_chan_sender
-            .send(acceptor::ControlSignal::Exit);
-
-        // some grace period so that everyone has exited/deactivated    
-        thread::sleep(Duration::from_secs(15));
+    #[test]
+    fn idle_nodes_block_instead_of_spinning() {
+        let nodes = vec![NodeId::Control, NodeId::Replica(0), NodeId::Leader(0), NodeId::Acceptor(0)];
+        let mut endpoints = channel_transport::construct::<SystemMessage<Counter>>(&nodes);
+        let mut endpoint = |node| -> Box<dyn Transport<SystemMessage<Counter>>> { Box::new(endpoints.remove(&node).unwrap()) };
+        let options = SystemOptions::default();
+        let idle: Vec<Box<dyn Node<Counter>>> = vec![
+            Box::new(replica::new(0, Counter::default(), endpoint(NodeId::Replica(0)), 1, 1, &options)),
+            Box::new(leader::new::<Counter>(0, endpoint(NodeId::Leader(0)), 1, 1, 1, &options, Arc::new(BallotChurn::default()))),
+            Box::new(acceptor::new::<Counter>(0, endpoint(NodeId::Acceptor(0)), Box::new(MemoryStorage))),
+        ];
+        let control = endpoints.remove(&NodeId::Control).unwrap();
+
+        // paused nodes have no timer to wait for, their loops sleep in recv
+        for node in &idle {
+            assert_eq!(node.next_wakeup(), None, "{} wants to wake up while paused", node.get_id());
+        }
+        let counters: Vec<_> = (0..idle.len()).map(|_| Arc::new(AtomicU32::new(0))).collect();
+        let threads: Vec<_> = idle
+            .into_iter()
+            .zip(&counters)
+            .map(|(node, work)| node::spawn(Box::new(Counted { node, work: work.clone() })))
+            .collect();
+        thread::sleep(Duration::from_millis(200));
+        for work in &counters {
+            assert_eq!(work.load(Ordering::SeqCst), 0);
+        }
+
+        // a running replica and acceptor with nothing to do go back to sleep, the leader keeps its heartbeats
+        control.send(NodeId::Replica(0), Message::Control(ControlSignal::Run(0)));
+        control.send(NodeId::Acceptor(0), Message::Control(ControlSignal::Run(0)));
+        thread::sleep(Duration::from_millis(100));
+        let settled: Vec<u32> = counters.iter().map(|work| work.load(Ordering::SeqCst)).collect();
+        assert_eq!(settled[0], 2);
+        assert_eq!(settled[2], 2);
+        thread::sleep(Duration::from_millis(200));
+        let later: Vec<u32> = counters.iter().map(|work| work.load(Ordering::SeqCst)).collect();
+        assert_eq!(later, settled);
+
+        control.broadcast(&nodes[1..], Message::Control(ControlSignal::Exit));
+        for handle in threads {
+            handle.join().unwrap();
+        }
     }
-}
 
+    #[test]
+    fn leader_sends_a_decision_again() {
+        // a single leader with three acceptors, the test stands in for the only replica
+        let acceptors = transport::nodes(NodeId::Acceptor, 3);
+        let mut nodes = vec![NodeId::Control, NodeId::Replica(0), NodeId::Leader(0)];
+        nodes.extend(&acceptors);
+        let mut endpoints = channel_transport::construct::<SystemMessage<Counter>>(&nodes);
+        let mut threads = vec![leader::new::<Counter>(
+            0,
+            Box::new(endpoints.remove(&NodeId::Leader(0)).unwrap()),
+            1,
+            1,
+            3,
+            &SystemOptions::default(),
+            Arc::new(BallotChurn::default()),
+        ).start()];
+        for acceptor_id in 0..3 {
+            let endpoint = endpoints.remove(&NodeId::Acceptor(acceptor_id)).unwrap();
+            threads.push(acceptor::new::<Counter>(acceptor_id, Box::new(endpoint), Box::new(MemoryStorage)).start());
+        }
+        let control = endpoints.remove(&NodeId::Control).unwrap();
+        let replica = endpoints.remove(&NodeId::Replica(0)).unwrap();
+        control.broadcast(&acceptors, Message::Control(ControlSignal::Run(0)));
+        control.send(NodeId::Leader(0), Message::Control(ControlSignal::Run(0)));
+
+        let add = |command_id| Command::create(0, command_id, Operation::Apply(CounterCommand::Add(1)));
+        let next_decision = || loop {
+            match replica.recv_timeout(Duration::from_secs(5)) {
+                Some(Message::Decision(decision)) => break decision.get_details(),
+                Some(_) => {}
+                None => panic!("No decision from the leader"),
+            }
+        };
+        replica.send(NodeId::Leader(0), Message::Propose(Propose::create(0, 1, add(1))));
+        assert_eq!(next_decision(), (add(1), 1));
+
+        // the decision got lost on its way to the replica, which proposes something else for the slot
+        replica.send(NodeId::Leader(0), Message::Propose(Propose::create(0, 1, add(2))));
+        assert_eq!(next_decision(), (add(1), 1));
+
+        control.broadcast(&nodes[2..], Message::Control(ControlSignal::Exit));
+        for handle in threads {
+            handle.join().unwrap();
+        }
+    }
 
+    #[test]
+    fn reconfig_moves_later_slots_to_the_new_leaders() {
+        // a single replica, with the test standing in for one client and three leaders
+        let leaders = transport::nodes(NodeId::Leader, 3);
+        let mut nodes = vec![NodeId::Control, NodeId::Client(0), NodeId::Replica(0)];
+        nodes.extend(&leaders);
+        let mut endpoints = channel_transport::construct::<SystemMessage<Counter>>(&nodes);
+        let thread = replica::new(
+            0,
+            Counter::default(),
+            Box::new(endpoints.remove(&NodeId::Replica(0)).unwrap()),
+            1,
+            3,
+            &SystemOptions::default(),
+        ).start();
+        let control = endpoints.remove(&NodeId::Control).unwrap();
+        let client = endpoints.remove(&NodeId::Client(0)).unwrap();
+        let leaders: Vec<ChannelTransport<SystemMessage<Counter>>> =
+            leaders.iter().map(|leader| endpoints.remove(leader).unwrap()).collect();
+        let proposal = |message| match message {
+            Message::Propose(propose) => propose,
+            message => panic!("Unexpected message {:?}", message),
+        };
+
+        // leader 0 alone from slot 1 + WINDOW on
+        control.send(NodeId::Replica(0), Message::Control(ControlSignal::Run(0)));
+        let reconfig = Command::create(1, 1, Operation::Reconfig(vec![0]));
+        leaders[0].send(NodeId::Replica(0), Message::Decision(Decision::create(1, reconfig)));
+        for command_id in 1..=10 {
+            let command = Command::create(0, command_id, Operation::Apply(CounterCommand::Add(1)));
+            client.send(NodeId::Replica(0), Message::Request(Request::create(command)));
+        }
 
+        // deciding whatever leader 0 is asked for, until the replica has proposed beyond the switch
+        let mut proposed_to_0 = Vec::new();
+        while proposed_to_0.len() < 8 {
+            let propose = proposal(leaders[0].recv_timeout(Duration::from_secs(5)).unwrap());
+            proposed_to_0.push(propose.get_slot());
+            leaders[0].send(NodeId::Replica(0), Message::Decision(Decision::create(propose.get_slot(), propose.get_command())));
+        }
+        control.send(NodeId::Replica(0), Message::Control(ControlSignal::Exit));
+        thread.join().unwrap();
 
+        assert_eq!(proposed_to_0, (2..=9).collect::<Vec<u32>>());
+        for leader in &leaders[1..] {
+            let slots: Vec<u32> = std::iter::from_fn(|| leader.try_recv()).map(|message| proposal(message).get_slot()).collect();
+            assert_eq!(slots, (2..1 + replica::WINDOW).collect::<Vec<u32>>());
+        }
+    }
 
+    #[test]
+    fn leader_failover() {
+        let client_count = 3_usize;
+        let replica_count = 3_usize;
+        let leader_count = 3_usize;
+        let acceptor_count = 3_usize;
+        let num_msgs = 100u32;
+        let system_handles = SystemHandles::system_handle_management(
+            client_count,
+            replica_count,
+            leader_count,
+            acceptor_count,
+        );
+        system_handles.activate(
+            num_msgs,
+            client_count as u32,
+            replica_count as u32,
+            leader_count as u32,
+        );
 
+        // some commands have gone through the leader whose ballot has been adopted
+        let completed = || system_handles.history().get_operations().iter().filter(|operation| operation.completed.is_some()).count();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while completed() == 0 {
+            assert!(Instant::now() < deadline, "No command answered");
+            thread::sleep(Duration::from_millis(1));
+        }
 
+        // stopping it, and any other leader that still thinks it leads
+        let churn = system_handles.ballot_churn();
+        let active: Vec<u32> = (0..leader_count as u32).filter(|leader_id| churn[*leader_id as usize].is_active()).collect();
+        assert!(!active.is_empty() && active.len() < leader_count, "active leaders {:?}", active);
+        for leader_id in &active {
+            system_handles.stop_leader(*leader_id);
+        }
+        assert!(completed() < client_count * num_msgs as usize, "every command answered before the failover");
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // one of the others takes over and gets the rest decided
+        let adoptions: Vec<u32> = churn.iter().map(|leader| leader.get_adoptions()).collect();
+        assert_eq!(system_handles.wait_for_clients(Duration::from_secs(20)), Ok(()));
+        assert!((0..leader_count).any(|leader_id| !active.contains(&(leader_id as u32)) && churn[leader_id].get_adoptions() > adoptions[leader_id]));
+        system_handles.deactivate();
+    }
 
     #[test]
-    fn it_works() {
-        let client_count = 3 as usize;
-        let replica_count = 3 as usize;
-        let leader_count = 3 as usize;
-        let acceptor_count = 3 as usize;
+    fn reconfigure_without_the_active_leader() {
+        let client_count = 3_usize;
+        let replica_count = 3_usize;
+        let leader_count = 3_usize;
+        let acceptor_count = 3_usize;
         let num_msgs = 8u32;
         let system_handles = SystemHandles::system_handle_management(
             client_count,
@@ -452,12 +1052,1351 @@ mod tests {
             leader_count,
             acceptor_count,
         );
-        system_handles.operation_control(
+        system_handles.activate(
+            num_msgs,
+            client_count as u32,
+            replica_count as u32,
+            leader_count as u32,
+        );
+
+        // leader 2 starts with the highest ballot, the others wait on it until it retires
+        system_handles.reconfigure(vec![0, 1]);
+
+        assert_eq!(system_handles.wait_for_clients(Duration::from_secs(20)), Ok(()));
+        system_handles.deactivate();
+    }
+
+    #[test]
+    fn randomized_backoff() {
+        let client_count = 3_usize;
+        let replica_count = 3_usize;
+        let leader_count = 3_usize;
+        let acceptor_count = 3_usize;
+        let num_msgs = 8u32;
+        let system_handles = SystemHandles::system_handle_management_with_options(
+            client_count,
+            replica_count,
+            leader_count,
+            acceptor_count,
+            SystemOptions {
+                backoff_policy: BackoffPolicy::RandomizedExponential {
+                    base: Duration::from_millis(1),
+                    max: Duration::from_millis(50),
+                },
+                ..SystemOptions::default()
+            },
+        );
+        system_handles.activate(
             num_msgs,
             client_count as u32,
             replica_count as u32,
             leader_count as u32,
         );
-        assert_eq!(2 + 2, 4);
+
+        assert_eq!(system_handles.wait_for_clients(Duration::from_secs(20)), Ok(()));
+
+        // every leader has scouted at least once and someone has been adopted
+        let churn = system_handles.ballot_churn();
+        assert!(churn.iter().all(|leader| leader.get_scouts() >= 1));
+        assert!(churn.iter().any(|leader| leader.get_adoptions() >= 1));
+        system_handles.deactivate();
+    }
+
+    // keeps a running total and reports how many commands it has seen
+    #[derive(Default)]
+    struct Sum {
+        total: u32,
+        applied: u32,
+    }
+
+    impl StateMachine for Sum {
+        type Command = u32;
+        type Result = (u32, u32);
+        type Snapshot = (u32, u32);
+
+        fn apply(&mut self, command: &u32) -> (u32, u32) {
+            self.total += command;
+            self.applied += 1;
+            (self.total, self.applied)
+        }
+
+        fn snapshot(&self) -> (u32, u32) {
+            (self.total, self.applied)
+        }
+
+        fn restore(&mut self, snapshot: (u32, u32)) {
+            (self.total, self.applied) = snapshot;
+        }
+    }
+
+    #[test]
+    fn custom_state_machine() {
+        let client_count = 2_usize;
+        let replica_count = 3_usize;
+        let leader_count = 2_usize;
+        let acceptor_count = 3_usize;
+        let num_msgs = 5u32;
+        let system_handles = SystemHandles::system_handle_management_with_state_machine(
+            client_count,
+            replica_count,
+            leader_count,
+            acceptor_count,
+            SystemOptions::default(),
+            Sum::default,
+            Arc::new(|num| num * 10),
+        );
+        system_handles.activate(
+            num_msgs,
+            client_count as u32,
+            replica_count as u32,
+            leader_count as u32,
+        );
+
+        assert_eq!(system_handles.wait_for_clients(Duration::from_secs(20)), Ok(()));
+        system_handles.deactivate();
+
+        // every command was executed once, the n-th one saw the total of the first n
+        let mut operations = system_handles.history().get_operations();
+        assert_eq!(operations.len(), client_count * num_msgs as usize);
+        operations.sort_by_key(|operation| operation.completed.as_ref().unwrap().1 .1);
+        let mut total = 0;
+        for (position, operation) in operations.iter().enumerate() {
+            assert_eq!(operation.command, operation.command_id * 10);
+            total += operation.command;
+            assert_eq!(operation.completed.as_ref().unwrap().1, (total, position as u32 + 1));
+        }
+
+        // the last result is the final state, the sum of the commands of all the clients
+        let expected: u32 = (1..=num_msgs).map(|num| num * 10).sum::<u32>() * client_count as u32;
+        assert_eq!(total, expected);
+        assert_eq!(system_handles.history().check(Sum::default), Ok(()));
+    }
+
+    #[test]
+    fn counter_wraps_around() {
+        let mut counter = Counter::default();
+        assert_eq!(counter.apply(&CounterCommand::Add(i32::MAX)), i32::MAX);
+        assert_eq!(counter.apply(&CounterCommand::Add(1)), i32::MIN);
+        assert_eq!(counter.apply(&CounterCommand::Subtract(1)), i32::MAX);
+        assert_eq!(counter.apply(&CounterCommand::Multiply(2)), -2);
+    }
+
+    #[test]
+    fn kv_store_operations() {
+        let mut store = KvStore::default();
+        assert_eq!(store.apply(&KvCommand::get("a")), Err(KvError::KeyNotFound("a".to_string())));
+        assert_eq!(store.apply(&KvCommand::put("a", "1")), Ok(None));
+        assert_eq!(store.apply(&KvCommand::put("a", "2")), Ok(Some("1".to_string())));
+        assert_eq!(store.apply(&KvCommand::get("a")), Ok(Some("2".to_string())));
+
+        // CAS only goes through against the current value
+        assert_eq!(
+            store.apply(&KvCommand::cas("a", Some("1"), "3")),
+            Err(KvError::CasMismatch { key: "a".to_string(), expected: Some("1".to_string()), current: Some("2".to_string()) })
+        );
+        assert_eq!(store.apply(&KvCommand::cas("a", Some("2"), "3")), Ok(Some("2".to_string())));
+        assert_eq!(store.apply(&KvCommand::cas("b", None, "1")), Ok(None));
+
+        assert_eq!(store.apply(&KvCommand::delete("a")), Ok(Some("3".to_string())));
+        assert_eq!(store.apply(&KvCommand::delete("a")), Err(KvError::KeyNotFound("a".to_string())));
+
+        // a restored replica sees the same store
+        let mut restored = KvStore::default();
+        restored.restore(store.snapshot());
+        assert_eq!(restored.get("b"), Some(&"1".to_string()));
+        assert_eq!(restored.len(), 1);
+
+        // commands survive the acceptor log encoding
+        for num in 0..8 {
+            let command = kv_workload(num);
+            let mut buf = Vec::new();
+            command.encode(&mut buf);
+            assert_eq!(KvCommand::decode(&mut codec::Cursor::new(&buf)), Ok(command));
+        }
+    }
+
+    // counter replicas wired to an in-process transport
+    // the test plays the part of the control node, of leader 0 and of client 0
+    struct ReplicaHarness {
+        control: ChannelTransport<SystemMessage<Counter>>,
+        leader: ChannelTransport<SystemMessage<Counter>>,
+        client: ChannelTransport<SystemMessage<Counter>>,
+        replicas: Vec<NodeId>,
+        threads: Vec<thread::JoinHandle<()>>,
+    }
+
+    // the replicas start paused
+    fn start_replicas(count: u32, snapshot_interval: u32) -> ReplicaHarness {
+        start_replicas_with(count, &SystemOptions { snapshot_interval, ..SystemOptions::default() })
+    }
+
+    fn start_replicas_with(count: u32, options: &SystemOptions) -> ReplicaHarness {
+        let replicas = transport::nodes(NodeId::Replica, count);
+        let mut nodes = vec![NodeId::Control, NodeId::Leader(0), NodeId::Client(0)];
+        nodes.extend(&replicas);
+        let mut endpoints = channel_transport::construct(&nodes);
+
+        let threads = (0..count)
+            .map(|replica_id| {
+                replica::new(
+                    replica_id,
+                    Counter::default(),
+                    Box::new(endpoints.remove(&NodeId::Replica(replica_id)).unwrap()),
+                    count,
+                    1,
+                    options,
+                ).start()
+            })
+            .collect();
+
+        ReplicaHarness {
+            control: endpoints.remove(&NodeId::Control).unwrap(),
+            leader: endpoints.remove(&NodeId::Leader(0)).unwrap(),
+            client: endpoints.remove(&NodeId::Client(0)).unwrap(),
+            replicas,
+            threads,
+        }
+    }
+
+    impl ReplicaHarness {
+        fn run(&self, replica_id: u32) {
+            self.control.send(NodeId::Replica(replica_id), Message::Control(ControlSignal::Run(0)));
+        }
+
+        fn decide(&self, replica_id: u32, slot: u32, command: Command<CounterCommand>) {
+            self.leader.send(NodeId::Replica(replica_id), Message::Decision(Decision::create(slot, command)));
+        }
+
+        fn request(&self, replica_id: u32, command: Command<CounterCommand>) {
+            self.client.send(NodeId::Replica(replica_id), Message::Request(Request::create(command)));
+        }
+
+        // next response to client 0, None if there is none within the timeout
+        fn response(&self, timeout: Duration) -> Option<Response<i32>> {
+            match self.client.recv_timeout(timeout)? {
+                Message::Response(response) => Some(response),
+                _ => None,
+            }
+        }
+
+        fn exit(self) {
+            self.control.broadcast(&self.replicas, Message::Control(ControlSignal::Exit));
+            for handle in self.threads {
+                handle.join().unwrap();
+            }
+        }
+    }
+
+    fn add_one(command_id: u32) -> Command<CounterCommand> {
+        Command::create(0, command_id, Operation::Apply(CounterCommand::Add(1)))
+    }
+
+    #[test]
+    fn replica_answers_duplicates_from_session() {
+        // snapshot every 2 slots
+        let harness = start_replicas(1, 2);
+        harness.run(0);
+
+        for slot in 1..=4 {
+            harness.decide(0, slot, add_one(slot));
+        }
+        // slot 1 is long gone from the decisions when its command is decided again
+        harness.decide(0, 5, add_one(1));
+        harness.decide(0, 6, Command::create(0, 5, Operation::Apply(CounterCommand::Multiply(10))));
+
+        // the command decided again is answered from the session instead of being executed again
+        let timeout = Duration::from_secs(5);
+        let results: Vec<i32> = (0..6)
+            .map(|_| harness.response(timeout).unwrap().get_result())
+            .collect();
+        assert_eq!(results, vec![1, 2, 3, 4, 1, 40]);
+
+        // so is a retried request, without being proposed
+        harness.request(0, add_one(3));
+        let response = harness.response(timeout).unwrap();
+        assert_eq!((response.get_command_id(), response.get_result()), (3, 3));
+        assert!(harness.response(Duration::from_millis(200)).is_none());
+        assert!(harness.leader.try_recv().is_none());
+
+        harness.exit();
+    }
+
+    #[test]
+    fn replica_keeps_results_until_acknowledged() {
+        let timeout = Duration::from_secs(5);
+        let harness = start_replicas(1, replica::SNAPSHOT_INTERVAL);
+        harness.run(0);
+
+        // the response to command 1 got lost, the client keeps acknowledging only what came before it
+        let add = |command_id, acknowledged| {
+            Command::create_with_acknowledged(0, command_id, acknowledged, Operation::Apply(CounterCommand::Add(1)))
+        };
+        for command_id in 1..=40 {
+            harness.decide(0, command_id, add(command_id, 1));
+        }
+        for _ in 1..=40 {
+            harness.response(timeout).unwrap();
+        }
+
+        // the retry is answered however many commands have been executed since
+        harness.request(0, add(1, 1));
+        let response = harness.response(timeout).unwrap();
+        assert_eq!((response.get_command_id(), response.get_result()), (1, 1));
+
+        // command 42 is given up on, then everything before 44 is acknowledged
+        harness.decide(0, 41, add(41, 41));
+        harness.decide(0, 42, add(43, 41));
+        harness.decide(0, 43, add(44, 44));
+        let results: Vec<(u32, i32)> = (0..3)
+            .map(|_| harness.response(timeout).unwrap())
+            .map(|response| (response.get_command_id(), response.get_result()))
+            .collect();
+        assert_eq!(results, vec![(41, 41), (43, 42), (44, 43)]);
+
+        // a late copy of the command given up on is not executed, an acknowledged one isn't answered again
+        harness.decide(0, 44, add(42, 41));
+        harness.request(0, add(1, 1));
+        harness.decide(0, 45, add(45, 45));
+        let response = harness.response(timeout).unwrap();
+        assert_eq!((response.get_command_id(), response.get_result()), (45, 44));
+        assert!(harness.response(Duration::from_millis(200)).is_none());
+        assert!(harness.leader.try_recv().is_none());
+
+        harness.exit();
+    }
+
+    #[test]
+    fn replica_catches_up_from_peer() {
+        let timeout = Duration::from_secs(5);
+        let harness = start_replicas(2, 2);
+
+        // replica 0 has been running for a while and has compacted its decisions
+        harness.run(0);
+        for slot in 1..=6 {
+            harness.decide(0, slot, add_one(slot));
+        }
+        for _ in 1..=6 {
+            harness.response(timeout).unwrap();
+        }
+
+        // replica 1 joins late and only sees the decisions from slot 7 onward
+        harness.run(1);
+        let multiply = Command::create(0, 7, Operation::Apply(CounterCommand::Multiply(10)));
+        harness.decide(0, 7, multiply.clone());
+        harness.decide(1, 7, multiply);
+
+        // both end up executing slot 7 on top of the six additions
+        for _ in 0..2 {
+            let response = loop {
+                let response = harness.response(timeout).unwrap();
+                if response.get_command_id() == 7 {
+                    break response;
+                }
+            };
+            assert_eq!(response.get_result(), 60);
+        }
+
+        harness.exit();
+    }
+
+    #[test]
+    fn idle_replica_catches_up_when_it_runs() {
+        let timeout = Duration::from_secs(5);
+        // the catch-up timer never fires here, only the replica starting to run can make it ask
+        let harness = start_replicas_with(2, &SystemOptions {
+            snapshot_interval: 2,
+            catch_up_timeout: Duration::from_secs(60),
+            ..SystemOptions::default()
+        });
+
+        harness.run(0);
+        for slot in 1..=6 {
+            harness.decide(0, slot, add_one(slot));
+        }
+        for _ in 1..=6 {
+            harness.response(timeout).unwrap();
+        }
+
+        // replica 1 joins an idle cluster, no decision reaches it from here on
+        harness.run(1);
+
+        // a retried command 6 is answered from the session table it got from replica 0
+        let deadline = Instant::now() + timeout;
+        let response = loop {
+            assert!(Instant::now() < deadline, "replica 1 never caught up");
+            harness.request(1, add_one(6));
+            if let Some(response) = harness.response(Duration::from_millis(100)) {
+                break response;
+            }
+        };
+        assert_eq!(response.get_command_id(), 6);
+        assert_eq!(response.get_result(), 6);
+
+        // and the counter it restored is at 6
+        harness.decide(1, 7, add_one(7));
+        let response = harness.response(timeout).unwrap();
+        assert_eq!(response.get_command_id(), 7);
+        assert_eq!(response.get_result(), 7);
+
+        harness.exit();
+    }
+
+    // writes the accept to the log and then takes the acceptor down before the P2b goes out
+    struct CrashAfterAccept(FileWal);
+
+    impl AcceptorStorage<CounterCommand> for CrashAfterAccept {
+        fn record_promise(&mut self, ballot: &Ballot) -> io::Result<()> {
+            AcceptorStorage::<CounterCommand>::record_promise(&mut self.0, ballot)
+        }
+
+        fn record_accept(&mut self, pvalue: &Pvalue<CounterCommand>) -> io::Result<()> {
+            self.0.record_accept(pvalue)?;
+            panic!("acceptor crashed between accepting and replying");
+        }
+
+        fn load(&mut self) -> io::Result<AcceptorState<CounterCommand>> {
+            self.0.load()
+        }
+    }
+
+    // a single acceptor wired to an in-process transport
+    // the test plays the part of the control node and of leaders 0 to 2
+    struct AcceptorHarness {
+        control: ChannelTransport<SystemMessage<Counter>>,
+        leaders: HashMap<u32, ChannelTransport<SystemMessage<Counter>>>,
+        thread: thread::JoinHandle<()>,
+    }
+
+    fn start_acceptor(storage: Box<dyn AcceptorStorage<CounterCommand>>) -> AcceptorHarness {
+        let mut nodes = vec![NodeId::Control, NodeId::Acceptor(0)];
+        nodes.extend(transport::nodes(NodeId::Leader, 3));
+        let mut endpoints = channel_transport::construct(&nodes);
+
+        let thread = acceptor::new::<Counter>(
+            0,
+            Box::new(endpoints.remove(&NodeId::Acceptor(0)).unwrap()),
+            storage,
+        ).start();
+
+        let control = endpoints.remove(&NodeId::Control).unwrap();
+        control.send(NodeId::Acceptor(0), Message::Control(ControlSignal::Run(0)));
+        let leaders = (0..3)
+            .map(|leader_id| (leader_id, endpoints.remove(&NodeId::Leader(leader_id)).unwrap()))
+            .collect();
+
+        AcceptorHarness { control, leaders, thread }
+    }
+
+    impl AcceptorHarness {
+        fn p1a(&self, p1a: P1a) {
+            self.leaders[&p1a.get_leader_id()].send(NodeId::Acceptor(0), Message::P1a(p1a));
+        }
+
+        fn p2a(&self, p2a: P2a<CounterCommand>) {
+            self.leaders[&p2a.get_leader_id()].send(NodeId::Acceptor(0), Message::P2a(p2a));
+        }
+
+        // P1b sent back to the leader, None if there is none within the timeout
+        fn p1b(&self, leader_id: u32, timeout: Duration) -> Option<P1b<CounterCommand>> {
+            match self.leaders[&leader_id].recv_timeout(timeout)? {
+                Message::P1b(p1b) => Some(p1b),
+                _ => None,
+            }
+        }
+
+        // P2b sent back to the leader, None if there is none within the timeout
+        fn p2b(&self, leader_id: u32, timeout: Duration) -> Option<P2b> {
+            match self.leaders[&leader_id].recv_timeout(timeout)? {
+                Message::P2b(p2b) => Some(p2b),
+                _ => None,
+            }
+        }
+
+        fn exit(self) {
+            self.control.send(NodeId::Acceptor(0), Message::Control(ControlSignal::Exit));
+            self.thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn acceptor_recovers_from_wal() {
+        let path = std::env::temp_dir().join(format!("paxos-acceptor-{}.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let timeout = Duration::from_secs(5);
+
+        // leader 1 gets a promise and then the acceptor crashes right after accepting its command
+        let ballot_1 = Ballot::create(1);
+        let command = Command::create(0, 1, Operation::Apply(CounterCommand::Add(7)));
+        let crashing = start_acceptor(Box::new(CrashAfterAccept(FileWal::open(&path).unwrap())));
+        crashing.p1a(P1a::create(1, ballot_1.clone(), 0, 0));
+        assert!(crashing.p1b(1, timeout).unwrap().get_ballot() == ballot_1);
+        crashing.p2a(P2a::create(1, Pvalue::create(ballot_1.clone(), 1, command.clone()), 0));
+        // the acceptor is gone without replying
+        assert!(crashing.p2b(1, Duration::from_millis(500)).is_none());
+
+        // restarted from the same log
+        let restarted = start_acceptor(Box::new(FileWal::open(&path).unwrap()));
+
+        // the promise to leader 1 survived, so leader 0 with a lower ballot is turned down
+        restarted.p1a(P1a::create(0, Ballot::create(0), 0, 0));
+        assert!(restarted.p1b(0, timeout).unwrap().get_ballot() == ballot_1);
+        restarted.p2a(P2a::create(0, Pvalue::create(Ballot::create(0), 1, Command::create(1, 1, Operation::Apply(CounterCommand::Multiply(3)))), 0));
+        assert!(restarted.p2b(0, timeout).unwrap().get_ballot() == ballot_1);
+
+        // the accepted command survived, so a leader with a higher ballot has to propose it again for slot 1
+        let ballot_2 = ballot_1.increment(2);
+        restarted.p1a(P1a::create(2, ballot_2.clone(), 1, 0));
+        let p1b = restarted.p1b(2, timeout).unwrap();
+        assert!(p1b.get_ballot() == ballot_2);
+        let pvalues = p1b.get_pvalues();
+        assert_eq!(pvalues.len(), 1);
+        assert_eq!(pvalues[0].get_slot(), 1);
+        assert!(pvalues[0].get_ballot_num() == ballot_1);
+        assert_eq!(pvalues[0].get_command(), command);
+
+        restarted.exit();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn wal_of_another_version_is_not_loaded() {
+        let path = std::env::temp_dir().join(format!("paxos-acceptor-version-{}.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut wal = FileWal::open(&path).unwrap();
+        AcceptorStorage::<CounterCommand>::record_promise(&mut wal, &Ballot::create(1)).unwrap();
+        assert!(AcceptorStorage::<CounterCommand>::load(&mut wal).unwrap().ballot_num == Some(Ballot::create(1)));
+
+        // the same log as written by another version of the encoding
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[0] = codec::WIRE_VERSION + 1;
+        std::fs::write(&path, bytes).unwrap();
+        let err = AcceptorStorage::<CounterCommand>::load(&mut FileWal::open(&path).unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.get_ref().and_then(|err| err.downcast_ref::<codec::DecodeError>()),
+            Some(&codec::DecodeError::UnsupportedVersion(codec::WIRE_VERSION + 1))
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn wal_with_a_bad_record_in_the_middle_is_not_loaded() {
+        let path = std::env::temp_dir().join(format!("paxos-acceptor-corrupt-{}.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut wal = FileWal::open(&path).unwrap();
+        AcceptorStorage::<CounterCommand>::record_promise(&mut wal, &Ballot::create(1)).unwrap();
+        AcceptorStorage::<CounterCommand>::record_promise(&mut wal, &Ballot::create(2)).unwrap();
+
+        // the checksum of the first record, behind the version byte and the length
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[5] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        // the promise in the second record can't be dropped along with it
+        let err = AcceptorStorage::<CounterCommand>::load(&mut FileWal::open(&path).unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+        // a torn last record is dropped, the one before it is kept
+        bytes[5] ^= 0xff;
+        bytes.truncate(bytes.len() - 3);
+        std::fs::write(&path, &bytes).unwrap();
+        let state = AcceptorStorage::<CounterCommand>::load(&mut FileWal::open(&path).unwrap()).unwrap();
+        assert!(state.ballot_num == Some(Ballot::create(1)));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn acceptor_keeps_the_highest_ballot_per_slot() {
+        let timeout = Duration::from_secs(5);
+        let acceptor = start_acceptor(Box::new(MemoryStorage));
+        let command = |command_id| Command::create(0, command_id, Operation::Apply(CounterCommand::Add(command_id as i32)));
+
+        // leader 0 gets slots 1 to 3 accepted
+        let ballot_0 = Ballot::create(0);
+        acceptor.p1a(P1a::create(0, ballot_0.clone(), 0, 0));
+        assert!(acceptor.p1b(0, timeout).unwrap().get_ballot() == ballot_0);
+        for slot in 1..=3 {
+            acceptor.p2a(P2a::create(0, Pvalue::create(ballot_0.clone(), slot, command(slot)), 0));
+            assert!(acceptor.p2b(0, timeout).unwrap().get_ballot() == ballot_0);
+        }
+
+        // leader 1 takes over and gets another command accepted for slot 2
+        let ballot_1 = ballot_0.increment(1);
+        acceptor.p1a(P1a::create(1, ballot_1.clone(), 0, 0));
+        assert_eq!(acceptor.p1b(1, timeout).unwrap().get_pvalues().len(), 3);
+        acceptor.p2a(P2a::create(1, Pvalue::create(ballot_1.clone(), 2, command(4)), 0));
+        assert!(acceptor.p2b(1, timeout).unwrap().get_ballot() == ballot_1);
+
+        // leader 2 knows slot 1 is decided, it gets slot 2 at the ballot of leader 1 only and slot 3 as it was
+        let ballot_2 = ballot_1.increment(2);
+        acceptor.p1a(P1a::create(2, ballot_2.clone(), 0, 1));
+        let p1b = acceptor.p1b(2, timeout).unwrap();
+        assert!(p1b.get_ballot() == ballot_2);
+        let pvalues: Vec<(u32, Ballot, Command<CounterCommand>)> = p1b
+            .get_pvalues()
+            .into_iter()
+            .map(|pvalue| (pvalue.get_slot(), pvalue.get_ballot_num(), pvalue.get_command()))
+            .collect();
+        assert_eq!(pvalues, vec![(2, ballot_1, command(4)), (3, ballot_0, command(3))]);
+
+        acceptor.exit();
+    }
+
+    #[test]
+    fn acceptor_crash_and_restart() {
+        let path = std::env::temp_dir().join(format!("paxos-acceptor-restart-{}.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let timeout = Duration::from_secs(5);
+
+        let acceptor = start_acceptor(Box::new(FileWal::open(&path).unwrap()));
+        let ballot_1 = Ballot::create(1);
+        acceptor.p1a(P1a::create(1, ballot_1.clone(), 0, 0));
+        assert!(acceptor.p1b(1, timeout).unwrap().get_ballot() == ballot_1);
+
+        // nothing is answered while crashed
+        acceptor.control.send(NodeId::Acceptor(0), Message::Control(ControlSignal::Crash));
+        acceptor.p1a(P1a::create(2, ballot_1.increment(2), 0, 0));
+        assert!(acceptor.p1b(2, Duration::from_millis(300)).is_none());
+
+        // back with the promise from its log, the P1a sent while it was down is gone
+        acceptor.control.send(NodeId::Acceptor(0), Message::Control(ControlSignal::Restart));
+        acceptor.p1a(P1a::create(0, Ballot::create(0), 0, 0));
+        assert!(acceptor.p1b(0, timeout).unwrap().get_ballot() == ballot_1);
+
+        acceptor.exit();
+        let _ = std::fs::remove_file(&path);
+    }
+
+
+
+
+    #[test]
+    fn cluster_config() {
+        let servers = "[[nodes]]\nrole = \"replica\"\nid = 0\n\
+                       [[nodes]]\nrole = \"leader\"\nid = 0\n\
+                       [[nodes]]\nrole = \"acceptor\"\nid = 0\n\
+                       [[nodes]]\nrole = \"acceptor\"\nid = 1\n\
+                       [[nodes]]\nrole = \"acceptor\"\nid = 2\n";
+
+        // the example shipped with the repo
+        let config = ClusterConfig::load(std::path::Path::new("cluster.toml")).unwrap();
+        assert_eq!(config.cluster, ClusterSize { clients: 1, replicas: 3, leaders: 3, acceptors: 3 });
+        assert_eq!(config.addrs.len(), 11);
+        assert!(config.require_addresses().is_ok());
+        assert_eq!(config.options.quorums(3), (2, 2));
+
+        // options left out keep their defaults, addresses are optional until the nodes run in separate processes
+        let config = ClusterConfig::parse(&format!("[options]\nfailure_timeout_ms = 250\nphase1_quorum = 3\nphase2_quorum = 1\n{}", servers)).unwrap();
+        assert_eq!(config.options.failure_timeout, Duration::from_millis(250));
+        assert_eq!(config.options.heartbeat_interval, leader::HEARTBEAT_INTERVAL);
+        assert_eq!(config.options.quorums(3), (3, 1));
+        assert_eq!(config.require_addresses(), Err(ConfigError::MissingAddress(NodeId::Replica(0))));
+
+        let error = |text: &str| ClusterConfig::parse(text).unwrap_err();
+        assert_eq!(error(&format!("{}[[nodes]]\nrole = \"learner\"\nid = 0\n", servers)), ConfigError::UnknownRole("learner".to_string()));
+        assert_eq!(error(&format!("{}[[nodes]]\nrole = \"leader\"\nid = 0\n", servers)), ConfigError::DuplicateNode(NodeId::Leader(0)));
+        assert_eq!(error(&format!("{}[[nodes]]\nrole = \"leader\"\nid = 2\n", servers)), ConfigError::MissingNode(NodeId::Leader(1)));
+        assert_eq!(
+            error("[[nodes]]\nrole = \"replica\"\nid = 0\n[[nodes]]\nrole = \"leader\"\nid = 0\n"),
+            ConfigError::NoNodes("acceptor")
+        );
+        assert_eq!(
+            error(&format!("{}[[nodes]]\nrole = \"client\"\nid = 0\naddress = \"127.0.0.1:7000\"\n[[nodes]]\nrole = \"client\"\nid = 1\naddress = \"127.0.0.1:7000\"\n", servers)),
+            ConfigError::DuplicateAddress {
+                address: "127.0.0.1:7000".parse().unwrap(),
+                first: NodeId::Client(0),
+                second: NodeId::Client(1),
+            }
+        );
+        assert_eq!(
+            error(&format!("[options]\nphase2_quorum = 4\n{}", servers)),
+            ConfigError::QuorumOutOfRange { phase: 2, quorum: 4, acceptors: 3 }
+        );
+        assert_eq!(
+            error(&format!("[options]\nphase1_quorum = 1\n{}", servers)),
+            ConfigError::QuorumsDontIntersect { phase1: 1, phase2: 2, acceptors: 3 }
+        );
+        assert!(matches!(error(&format!("[options]\nbackoff = \"never\"\n{}", servers)), ConfigError::InvalidOption { name: "backoff", .. }));
+        assert!(matches!(
+            error(&format!("[options]\nheartbeat_interval_ms = 100\nfailure_timeout_ms = 100\n{}", servers)),
+            ConfigError::InvalidOption { name: "failure_timeout_ms", .. }
+        ));
+        assert!(matches!(
+            error(&format!("[options]\nbackoff = \"randomized-exponential\"\nbackoff_base_ms = 500\nbackoff_max_ms = 100\n{}", servers)),
+            ConfigError::InvalidOption { name: "backoff_base_ms", .. }
+        ));
+        assert!(matches!(
+            error(&format!("[options]\nbackoff = \"randomized-exponential\"\nbackoff_base_ms = 2000\n{}", servers)),
+            ConfigError::InvalidOption { name: "backoff_base_ms", .. }
+        ));
+        assert!(matches!(error(&format!("[options]\nquorum = 2\n{}", servers)), ConfigError::Syntax(_)));
+    }
+
+
+
+
+    // random values of every message type, for the codec properties below
+    mod arbitrary {
+        use super::*;
+        use crate::replica::Snapshot;
+        use crate::utils::CatchUpMessage;
+        use std::collections::{BTreeMap, BTreeSet};
+
+        pub type KvMessage = SystemMessage<KvStore>;
+
+        fn string(rng: &mut StdRng) -> String {
+            let len = rng.gen_range(0..6);
+            (0..len).map(|_| ['a', 'b', 'z', '0', ' ', 'é', '✓'][rng.gen_range(0..7)]).collect()
+        }
+
+        fn option_string(rng: &mut StdRng) -> Option<String> {
+            if rng.gen() { Some(string(rng)) } else { None }
+        }
+
+        fn ids(rng: &mut StdRng) -> Vec<u32> {
+            (0..rng.gen_range(0..4)).map(|_| rng.gen()).collect()
+        }
+
+        fn kv_command(rng: &mut StdRng) -> KvCommand {
+            match rng.gen_range(0..4) {
+                0 => KvCommand::Get(string(rng)),
+                1 => KvCommand::Put(string(rng), string(rng)),
+                2 => KvCommand::Delete(string(rng)),
+                _ => KvCommand::Cas { key: string(rng), expected: option_string(rng), new: string(rng) },
+            }
+        }
+
+        fn kv_result(rng: &mut StdRng) -> KvResult {
+            match rng.gen_range(0..3) {
+                0 => Ok(option_string(rng)),
+                1 => Err(KvError::KeyNotFound(string(rng))),
+                _ => Err(KvError::CasMismatch { key: string(rng), expected: option_string(rng), current: option_string(rng) }),
+            }
+        }
+
+        fn command(rng: &mut StdRng) -> Command<KvCommand> {
+            let operation = match rng.gen_range(0..3) {
+                0 => Operation::Null,
+                1 => Operation::Apply(kv_command(rng)),
+                _ => Operation::Reconfig(ids(rng)),
+            };
+            Command::create_with_acknowledged(rng.gen(), rng.gen(), rng.gen(), operation)
+        }
+
+        fn ballot(rng: &mut StdRng) -> Ballot {
+            Ballot::create_with_count(rng.gen(), rng.gen())
+        }
+
+        fn pvalue(rng: &mut StdRng) -> Pvalue<KvCommand> {
+            Pvalue::create(ballot(rng), rng.gen(), command(rng))
+        }
+
+        // built through its own decoder, the fields are private to the replica
+        // sets and maps in the bytes are sorted so that the snapshot encodes back to the same bytes
+        fn snapshot(rng: &mut StdRng) -> Snapshot<KvStore> {
+            let mut buf = Vec::new();
+            rng.gen::<u32>().encode(&mut buf);
+            let state: BTreeMap<String, String> = (0..rng.gen_range(0..3)).map(|_| (string(rng), string(rng))).collect();
+            state.encode(&mut buf);
+            let sessions: BTreeSet<u32> = (0..rng.gen_range(0..3)).map(|_| rng.gen()).collect();
+            (sessions.len() as u32).encode(&mut buf);
+            for client_id in sessions {
+                client_id.encode(&mut buf);
+                rng.gen::<u32>().encode(&mut buf);
+                let beyond: BTreeSet<u32> = ids(rng).into_iter().collect();
+                beyond.into_iter().collect::<Vec<u32>>().encode(&mut buf);
+                let results: Vec<(u32, KvResult)> = (0..rng.gen_range(0..3)).map(|_| (rng.gen(), kv_result(rng))).collect();
+                results.encode(&mut buf);
+            }
+            let configs: BTreeMap<u32, Vec<u32>> = (0..rng.gen_range(0..3)).map(|_| (rng.gen(), ids(rng))).collect();
+            configs.encode(&mut buf);
+            Snapshot::decode(&mut codec::Cursor::new(&buf)).unwrap()
+        }
+
+        pub fn message(rng: &mut StdRng) -> KvMessage {
+            match rng.gen_range(0..12) {
+                0 => Message::Control(match rng.gen_range(0..6) {
+                    0 => ControlSignal::Paused,
+                    1 => ControlSignal::Run(rng.gen()),
+                    2 => ControlSignal::Crash,
+                    3 => ControlSignal::Restart,
+                    4 => ControlSignal::Wakeup,
+                    _ => ControlSignal::Exit,
+                }),
+                1 => Message::Request(Request::create(command(rng))),
+                2 => Message::Response(Response::create(rng.gen(), kv_result(rng))),
+                3 => Message::Propose(Propose::create(rng.gen(), rng.gen(), command(rng))),
+                4 => Message::Decision(Decision::create(rng.gen(), command(rng))),
+                5 => Message::P1a(P1a::create(rng.gen(), ballot(rng), rng.gen(), rng.gen())),
+                6 => Message::P1b(P1b::create(rng.gen(), ballot(rng), (0..rng.gen_range(0..3)).map(|_| pvalue(rng)).collect(), rng.gen())),
+                7 => Message::P2a(P2a::create(rng.gen(), pvalue(rng), rng.gen())),
+                8 => Message::P2b(P2b::create(rng.gen(), ballot(rng), rng.gen())),
+                9 => Message::Heartbeat(Heartbeat::create(rng.gen())),
+                10 => Message::CatchUp(if rng.gen() {
+                    CatchUpMessage::Request(crate::utils::CatchUpRequest::create(rng.gen(), rng.gen()))
+                } else {
+                    let snapshot = if rng.gen() { Some(snapshot(rng)) } else { None };
+                    let decisions = (0..rng.gen_range(0..3)).map(|_| (rng.gen(), command(rng))).collect();
+                    CatchUpMessage::Reply(crate::utils::CatchUpReply::create(snapshot, decisions))
+                }),
+                _ => Message::ClientDone(crate::utils::ClientDone::create(rng.gen(), (0..rng.gen_range(0..4)).map(|_| rng.gen()).collect())),
+            }
+        }
+    }
+
+    #[test]
+    fn message_codec_round_trips() {
+        let mut rng = StdRng::seed_from_u64(16);
+        for _ in 0..2000 {
+            let message = arbitrary::message(&mut rng);
+            let bytes = codec::to_bytes(&message);
+            assert_eq!(bytes[0], codec::WIRE_VERSION);
+
+            // decoding gives the same message back, down to the last byte
+            // the messages have no PartialEq, the encoding is canonical though
+            let decoded: arbitrary::KvMessage = codec::from_bytes(&bytes).unwrap();
+            assert_eq!(codec::to_bytes(&decoded), bytes);
+
+            // every cut short version is rejected
+            for len in 0..bytes.len() {
+                assert!(
+                    matches!(codec::from_bytes::<arbitrary::KvMessage>(&bytes[..len]), Err(codec::DecodeError::UnexpectedEnd { .. })),
+                    "{} bytes of {:?} decoded",
+                    len,
+                    message
+                );
+            }
+
+            // so is anything after the message
+            let mut longer = bytes.clone();
+            longer.push(0);
+            assert_eq!(
+                codec::from_bytes::<arbitrary::KvMessage>(&longer).unwrap_err(),
+                codec::DecodeError::TrailingBytes { offset: bytes.len() }
+            );
+
+            // and another version of the encoding
+            let mut other_version = bytes.clone();
+            other_version[0] = codec::WIRE_VERSION + 1;
+            assert_eq!(
+                codec::from_bytes::<arbitrary::KvMessage>(&other_version).unwrap_err(),
+                codec::DecodeError::UnsupportedVersion(codec::WIRE_VERSION + 1)
+            );
+        }
+    }
+
+    // the decoder never panics, whatever the bytes, it gives an error or a message
+    #[test]
+    fn message_codec_survives_garbage() {
+        let mut rng = StdRng::seed_from_u64(16);
+        for _ in 0..2000 {
+            // corrupted messages
+            let mut bytes = codec::to_bytes(&arbitrary::message(&mut rng));
+            for _ in 0..rng.gen_range(1..4) {
+                let index = rng.gen_range(0..bytes.len());
+                bytes[index] = rng.gen();
+            }
+            let _ = codec::from_bytes::<arbitrary::KvMessage>(&bytes);
+
+            // plain noise behind the right version
+            let mut noise = vec![codec::WIRE_VERSION];
+            noise.extend((0..rng.gen_range(0..64)).map(|_| rng.gen::<u8>()));
+            let _ = codec::from_bytes::<arbitrary::KvMessage>(&noise);
+        }
+
+        // a bad tag is reported where it is
+        let bytes = [codec::WIRE_VERSION, 0, 7];
+        assert_eq!(
+            codec::from_bytes::<arbitrary::KvMessage>(&bytes).unwrap_err(),
+            codec::DecodeError::InvalidTag { type_name: "ControlSignal", tag: 7, offset: 2 }
+        );
+    }
+
+    // waiting on a future without an async runtime, the thread sleeps until it is woken
+    fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+        struct ThreadWaker(thread::Thread);
+        impl std::task::Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = std::task::Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut context = std::task::Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            match future.as_mut().poll(&mut context) {
+                std::task::Poll::Ready(value) => return value,
+                std::task::Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn client_handles() {
+        let cluster = ClusterSize { clients: 2, replicas: 3, leaders: 2, acceptors: 3 };
+        let (system_handles, clients) =
+            SystemHandles::with_client_handles(cluster, SystemOptions::default(), KvStore::default, ClientOptions::default());
+        system_handles.activate(0, 0, 0, 0);
+
+        // commands outstanding from several threads at once, on both handles
+        thread::scope(|scope| {
+            for (thread_id, client) in (0..4).zip(clients.iter().cycle()) {
+                scope.spawn(move || {
+                    for num in 0..5 {
+                        let key = format!("{}-{}", thread_id, num);
+                        assert_eq!(client.submit(KvCommand::put(&key, "1")), Ok(Ok(None)));
+                    }
+                });
+            }
+        });
+
+        // every command was applied once, in the same order on every replica
+        assert_eq!(clients[0].submit(KvCommand::put("3-4", "2")), Ok(Ok(Some("1".to_string()))));
+        assert_eq!(block_on(clients[1].submit_async(KvCommand::get("3-4"))), Ok(Ok(Some("2".to_string()))));
+        let pending = [clients[0].submit_async(KvCommand::delete("0-0")), clients[0].submit_async(KvCommand::get("0-0"))];
+        let [deleted, read] = pending.map(block_on);
+        assert_eq!(deleted, Ok(Ok(Some("1".to_string()))));
+        assert_eq!(read, Ok(Err(KvError::KeyNotFound("0-0".to_string()))));
+
+        // the exit signal closes the handles
+        system_handles.deactivate();
+        assert_eq!(clients[0].submit(KvCommand::get("a")), Err(ClientError::Closed));
+
+        // both handles recorded into the history of the system
+        // the last submission may have gone out before the handle saw the exit signal, it never returned either way
+        let operations = system_handles.history().get_operations();
+        assert_eq!(operations.iter().filter(|operation| operation.completed.is_some()).count(), 24);
+        assert_eq!(system_handles.history().check(KvStore::default), Ok(()));
+
+        // nobody answers a cluster that was never activated
+        let retry = RetryPolicy { deadline: Duration::from_millis(100), ..RetryPolicy::default() };
+        let options = ClientOptions { retry, first_command_id: 7 };
+        let (system_handles, clients) =
+            SystemHandles::with_client_handles(cluster, SystemOptions::default(), KvStore::default, options);
+        assert_eq!(clients[1].submit(KvCommand::get("a")), Err(ClientError::Timeout { command_id: 7 }));
+        system_handles.deactivate();
+    }
+
+    // client endpoint losing the first sends of every request, and every request from command ID lost_from on
+    struct LossyClient {
+        endpoint: ChannelTransport<SystemMessage<KvStore>>,
+        first_lost: usize,
+        lost_from: u32,
+        sends: Mutex<HashMap<u32, usize>>,
+    }
+
+    impl Transport<SystemMessage<KvStore>> for LossyClient {
+        fn get_id(&self) -> NodeId {
+            self.endpoint.get_id()
+        }
+
+        fn send(&self, to: NodeId, message: SystemMessage<KvStore>) {
+            if let Message::Request(request) = &message {
+                let command_id = request.get_command().get_command_id();
+                let mut sends = self.sends.lock().unwrap();
+                let count = sends.entry(command_id).or_insert(0);
+                *count += 1;
+                if *count <= self.first_lost || command_id >= self.lost_from {
+                    return;
+                }
+            }
+            self.endpoint.send(to, message)
+        }
+
+        fn recv(&self) -> Option<SystemMessage<KvStore>> {
+            self.endpoint.recv()
+        }
+
+        fn recv_timeout(&self, timeout: Duration) -> Option<SystemMessage<KvStore>> {
+            self.endpoint.recv_timeout(timeout)
+        }
+
+        fn try_recv(&self) -> Option<SystemMessage<KvStore>> {
+            self.endpoint.try_recv()
+        }
+    }
+
+    #[test]
+    fn client_retransmits_lost_requests() {
+        let cluster = ClusterSize { clients: 1, replicas: 3, leaders: 1, acceptors: 3 };
+        let retry = RetryPolicy {
+            retry_interval: Duration::from_millis(20),
+            max_retry_interval: Duration::from_millis(80),
+            deadline: Duration::from_secs(2),
+        };
+        let options = SystemOptions { client_retry: retry, ..SystemOptions::default() };
+
+        // the first two broadcasts of every command are lost, the commands from 4 on never get through
+        let mut endpoints = channel_transport::construct::<SystemMessage<KvStore>>(&cluster.nodes());
+        let lossy = |endpoint, first_lost, lost_from| -> SystemTransport<KvStore> {
+            Box::new(LossyClient { endpoint, first_lost, lost_from, sends: Mutex::new(HashMap::new()) })
+        };
+        let control: SystemTransport<KvStore> = Box::new(endpoints.remove(&NodeId::Control).unwrap());
+        let local_nodes = endpoints
+            .into_iter()
+            .map(|(node, endpoint)| match node {
+                NodeId::Client(_) => (node, lossy(endpoint, 6, 4)),
+                _ => (node, Box::new(endpoint) as SystemTransport<KvStore>),
+            })
+            .collect();
+        let system_handles =
+            SystemHandles::with_transports(control, cluster, local_nodes, options, KvStore::default, Arc::new(kv_workload));
+
+        // the commands that never got through are reported, the rest was answered
+        system_handles.activate(5, 1, 3, 1);
+        assert_eq!(
+            system_handles.wait_for_clients(Duration::from_secs(20)),
+            Err(OperationError::CommandsTimedOut(vec![(0, 4), (0, 5)]))
+        );
+        system_handles.deactivate();
+    }
+
+    #[test]
+    fn linearizability_checker() {
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let recorded = |client_id, command, invoked, completed: Option<(u64, i32)>| Recorded {
+            client_id,
+            command_id: 1,
+            command,
+            invoked: at(invoked),
+            completed: completed.map(|(millis, result)| (at(millis), result)),
+        };
+
+        // the multiplication overlaps the first addition, it may have come before it
+        let first = recorded(0, CounterCommand::Add(1), 0, Some((10, 1)));
+        let overlapping = recorded(1, CounterCommand::Multiply(1), 5, Some((15, 0)));
+        let never_returned = recorded(2, CounterCommand::Subtract(7), 40, None);
+        assert_eq!(check_linearizable(Counter::default, &[first.clone(), overlapping.clone(), never_returned.clone()]), Ok(()));
+
+        // the second addition started after the first one was done, it can't have seen 0 again
+        let second = recorded(3, CounterCommand::Add(1), 20, Some((30, 1)));
+        let history = [first.clone(), overlapping, second.clone(), never_returned];
+        let violation = check_linearizable(Counter::default, &history).unwrap_err();
+        assert_eq!(violation.operations, vec![first, second]);
+
+        // one pass keeps the addition of 1, it is needed while the two unanswered additions of 2 are there
+        // without them the addition of 3 is wrong on its own
+        let add_three = recorded(2, CounterCommand::Add(3), 4, Some((8, 5)));
+        let history = [
+            recorded(0, CounterCommand::Add(2), 3, None),
+            recorded(1, CounterCommand::Add(1), 6, Some((9, 3))),
+            add_three.clone(),
+            recorded(3, CounterCommand::Add(2), 6, None),
+        ];
+        let violation = check_linearizable(Counter::default, &history).unwrap_err();
+        assert_eq!(violation.operations, vec![add_three]);
+
+        // a command never answered may have been executed in between
+        let history = History::<Counter>::new();
+        history.invoke(0, 1, CounterCommand::Add(5), at(0));
+        history.invoke(1, 1, CounterCommand::Add(1), at(1));
+        history.complete(1, 1, 6, at(2));
+        history.complete(1, 1, 1, at(3));
+        assert_eq!(history.check(Counter::default), Ok(()));
+    }
+
+    // the results the clients got have to fit an order of their commands on a single counter
+    // every randomized run ends with this check
+    fn assert_linearizable(history: &History<Counter>, seed: u64) {
+        if let Err(violation) = history.check(Counter::default) {
+            panic!("seed {}: {}", seed, violation);
+        }
+    }
+
+    fn simulate(seed: u64) -> Simulation<Counter> {
+        let cluster = ClusterSize { clients: 2, replicas: 3, leaders: 2, acceptors: 3 };
+        let mut simulation = Simulation::new(
+            seed,
+            cluster,
+            SystemOptions::default(),
+            SimulationOptions::default(),
+            Counter::default,
+            Arc::new(counter_workload),
+        );
+        simulation.activate(4);
+        assert_eq!(simulation.wait_for_clients(Duration::from_secs(10)), Ok(()), "seed {}", seed);
+        assert_linearizable(simulation.get_history(), seed);
+        simulation
+    }
+
+    // every decision is checked for agreement along the way
+    #[test]
+    fn simulated_schedules() {
+        let traces: HashSet<u64> = (0..300).map(|seed| simulate(seed).trace_hash()).collect();
+        // the seed decides the schedule
+        assert!(traces.len() > 250, "only {} different schedules", traces.len());
+
+        // and the same seed gives the same run again
+        let first = simulate(7);
+        let second = simulate(7);
+        assert_eq!(first.trace_hash(), second.trace_hash());
+        assert_eq!(first.elapsed(), second.elapsed());
+        assert_eq!(first.get_num_steps(), second.get_num_steps());
+    }
+
+    fn simulate_backoff(seed: u64, backoff_policy: BackoffPolicy, faults: FaultPlan) -> Simulation<Counter> {
+        let cluster = ClusterSize { clients: 2, replicas: 3, leaders: 3, acceptors: 3 };
+        let mut simulation = Simulation::new(
+            seed,
+            cluster,
+            SystemOptions { backoff_policy, ..SystemOptions::default() },
+            SimulationOptions { faults, ..SimulationOptions::default() },
+            Counter::default,
+            Arc::new(counter_workload),
+        );
+        simulation.activate(4);
+        assert_eq!(simulation.wait_for_clients(Duration::from_secs(10)), Ok(()), "seed {}", seed);
+        simulation
+    }
+
+    fn total_scouts(simulation: &Simulation<Counter>) -> u32 {
+        simulation.get_ballot_churn().iter().map(|leader| leader.get_scouts()).sum()
+    }
+
+    #[test]
+    fn backoff_policies_churn_differently() {
+        for seed in 0..20 {
+            // the leaders preempted by the winner wait on it, every leader scouts once
+            let waiting = simulate_backoff(seed, BackoffPolicy::WaitForWinner, FaultPlan::default());
+            assert!(waiting.get_ballot_churn().iter().all(|leader| leader.get_scouts() == 1), "seed {}", seed);
+
+            // the leaders preempted right away scout again at once and preempt the winner in turn
+            let immediate = simulate_backoff(seed, BackoffPolicy::Immediate, FaultPlan::default());
+            assert!(total_scouts(&immediate) > total_scouts(&waiting), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn waiting_on_a_winner_cut_off_from_the_acceptors() {
+        // leader 2 holds the highest ballot and keeps heartbeating, but reaches only acceptor 0
+        // the leaders preempted by it through acceptor 0 would wait on it forever
+        let cut = LinkFaults { drop: 1.0, ..LinkFaults::default() };
+        let plan = FaultPlan::default()
+            .with_links_between(NodeId::Leader(2), NodeId::Acceptor(1), cut.clone())
+            .with_links_between(NodeId::Leader(2), NodeId::Acceptor(2), cut);
+        for seed in 0..20 {
+            let simulation = simulate_backoff(seed, BackoffPolicy::WaitForWinner, plan.clone());
+            assert_eq!(simulation.get_ballot_churn()[2].get_adoptions(), 0, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn faulty_network() {
+        let cluster = ClusterSize { clients: 2, replicas: 3, leaders: 2, acceptors: 3 };
+        // duplicates and delays everywhere, the clients also lose and reorder their requests and responses
+        let faults = LinkFaults { duplicate: 0.1, delay: 0.2, max_delay: Duration::from_millis(20), ..LinkFaults::default() };
+        let mut plan = FaultPlan::new(faults);
+        for client in transport::nodes(NodeId::Client, 2) {
+            for replica in transport::nodes(NodeId::Replica, 3) {
+                plan = plan.with_links_between(client, replica, LinkFaults::uniform(0.1, Duration::from_millis(20)));
+            }
+        }
+        let system_handles =
+            SystemHandles::with_faults(cluster, SystemOptions::default(), plan, 42, Counter::default, Arc::new(counter_workload));
+
+        // the clients send again whatever got lost
+        system_handles.activate(10, 2, 3, 2);
+        assert_eq!(system_handles.wait_for_clients(Duration::from_secs(20)), Ok(()));
+        assert_linearizable(system_handles.history(), 42);
+        system_handles.deactivate();
+    }
+
+    fn simulate_with_faults(seed: u64, faults: FaultPlan) -> (Simulation<Counter>, Result<(), OperationError>) {
+        let cluster = ClusterSize { clients: 2, replicas: 3, leaders: 2, acceptors: 3 };
+        let simulation_options = SimulationOptions { faults, ..SimulationOptions::default() };
+        let mut simulation = Simulation::new(
+            seed,
+            cluster,
+            SystemOptions::default(),
+            simulation_options,
+            Counter::default,
+            Arc::new(counter_workload),
+        );
+        simulation.activate(4);
+        let outcome = simulation.wait_for_clients(Duration::from_secs(60));
+        assert_linearizable(simulation.get_history(), seed);
+        (simulation, outcome)
+    }
+
+    #[test]
+    fn simulated_faults() {
+        let faults = LinkFaults {
+            drop: 0.05,
+            duplicate: 0.1,
+            delay: 0.2,
+            max_delay: Duration::from_millis(50),
+            reorder: 0.1,
+        };
+
+        // whatever is lost, a decision comes again from the leaders and every command gets answered
+        let lossy = FaultPlan::new(faults.clone());
+        for seed in 0..100 {
+            assert_eq!(simulate_with_faults(seed, lossy.clone()).1, Ok(()), "seed {}", seed);
+        }
+
+        // a network that loses nothing but the requests and responses gets every command answered
+        let mut plan = FaultPlan::new(LinkFaults { drop: 0.0, ..faults.clone() });
+        for client in transport::nodes(NodeId::Client, 2) {
+            for replica in transport::nodes(NodeId::Replica, 3) {
+                plan = plan.with_links_between(client, replica, LinkFaults { drop: 0.2, ..faults.clone() });
+            }
+        }
+        for seed in 0..100 {
+            assert_eq!(simulate_with_faults(seed, plan.clone()).1, Ok(()), "seed {}", seed);
+        }
+
+        // the faults come from the seed too
+        let (first, _) = simulate_with_faults(3, lossy.clone());
+        let (second, _) = simulate_with_faults(3, lossy);
+        assert_eq!(first.trace_hash(), second.trace_hash());
+    }
+
+    // the clients send 4 commands each before the partition starts at 1s, and 4 more right after
+    const PARTITION_START: Duration = Duration::from_secs(1);
+    // by then the messages sent before the partition have all arrived
+    const PARTITION_SETTLED: Duration = Duration::from_millis(1100);
+
+    fn simulate_partition(seed: u64, plan: FaultPlan) -> (Simulation<Counter>, Result<(), OperationError>) {
+        let cluster = ClusterSize { clients: 2, replicas: 3, leaders: 2, acceptors: 3 };
+        let simulation_options = SimulationOptions { faults: plan, ..SimulationOptions::default() };
+        let mut simulation = Simulation::new(
+            seed,
+            cluster,
+            SystemOptions::default(),
+            simulation_options,
+            Counter::default,
+            Arc::new(counter_workload),
+        );
+        simulation.activate(4);
+        assert_eq!(simulation.wait_for_clients(PARTITION_START), Ok(()), "seed {}", seed);
+        simulation.run_for(PARTITION_SETTLED - simulation.elapsed());
+
+        for client in transport::nodes(NodeId::Client, cluster.clients) {
+            simulation.send(client, Message::Control(ControlSignal::Run(8)));
+        }
+        let outcome = simulation.wait_for_clients(Duration::from_secs(30));
+        assert_linearizable(simulation.get_history(), seed);
+        (simulation, outcome)
+    }
+
+    // leaders that sent a decision in the time range
+    fn deciding_leaders(simulation: &Simulation<Counter>, from: Duration, until: Duration) -> HashSet<NodeId> {
+        simulation
+            .get_decisions_sent()
+            .iter()
+            .filter(|decision| decision.at >= from && decision.at < until)
+            .map(|decision| decision.from)
+            .collect()
+    }
+
+    #[test]
+    fn partition_isolating_the_active_leader() {
+        let heal = Duration::from_secs(4);
+        for seed in 0..10 {
+            // a run without the partition goes the same way up to its start, it tells the leader active by then
+            let (unpartitioned, _) = simulate_partition(seed, FaultPlan::default());
+            let active = unpartitioned
+                .get_decisions_sent()
+                .iter()
+                .rev()
+                .find(|decision| decision.at < PARTITION_START)
+                .unwrap()
+                .from;
+
+            let minority = Partition::isolate(&[active, NodeId::Acceptor(0)]).starting_at(PARTITION_START).healing_at(heal);
+            let (simulation, outcome) = simulate_partition(seed, FaultPlan::default().with_partition(minority));
+
+            // the other leader takes over with the other two acceptors, the commands are answered before the heal
+            assert_eq!(outcome, Ok(()), "seed {}", seed);
+            assert!(simulation.elapsed() < heal, "seed {}: done only at {:?}", seed, simulation.elapsed());
+            let deciding = deciding_leaders(&simulation, PARTITION_SETTLED, heal);
+            assert!(!deciding.is_empty(), "seed {}", seed);
+            assert!(!deciding.contains(&active), "seed {}: {} decided without a quorum", seed, active);
+        }
+    }
+
+    #[test]
+    fn partition_splitting_replicas_from_leaders() {
+        let heal = Duration::from_secs(3);
+        for seed in 0..10 {
+            let mut replica_side = transport::nodes(NodeId::Replica, 3);
+            replica_side.extend(transport::nodes(NodeId::Client, 2));
+            let split = Partition::new(vec![replica_side]).starting_at(PARTITION_START).healing_at(heal);
+            let (simulation, outcome) = simulate_partition(seed, FaultPlan::default().with_partition(split));
+
+            // no proposal gets to a leader, nothing is decided until the heal, then the commands go through
+            assert_eq!(outcome, Ok(()), "seed {}", seed);
+            assert!(simulation.elapsed() >= heal, "seed {}", seed);
+            assert!(deciding_leaders(&simulation, PARTITION_SETTLED, heal).is_empty(), "seed {}", seed);
+            assert!(!deciding_leaders(&simulation, heal, simulation.elapsed()).is_empty(), "seed {}", seed);
+        }
+    }
+
+    // one replica, one leader and one acceptor crash after the first commands and come back once the next ones are answered
+    // the acceptors log to disk, so that the one restarted keeps its promises
+    #[test]
+    fn simulated_crashes() {
+        let cluster = ClusterSize { clients: 2, replicas: 3, leaders: 2, acceptors: 3 };
+        for seed in 0..20 {
+            let wal_dir = std::env::temp_dir().join(format!("paxos-crashes-{}-{}", std::process::id(), seed));
+            std::fs::create_dir_all(&wal_dir).unwrap();
+            let options = SystemOptions { acceptor_wal_dir: Some(wal_dir.clone()), ..SystemOptions::default() };
+            let mut simulation = Simulation::new(
+                seed,
+                cluster,
+                options,
+                SimulationOptions::default(),
+                Counter::default,
+                Arc::new(counter_workload),
+            );
+            simulation.activate(4);
+            assert_eq!(simulation.wait_for_clients(Duration::from_secs(1)), Ok(()), "seed {}", seed);
+
+            let mut rng = StdRng::seed_from_u64(seed);
+            let crashed = [
+                NodeId::Replica(rng.gen_range(0..cluster.replicas)),
+                NodeId::Leader(rng.gen_range(0..cluster.leaders)),
+                NodeId::Acceptor(rng.gen_range(0..cluster.acceptors)),
+            ];
+            for node in crashed {
+                simulation.crash(node);
+            }
+            for client in transport::nodes(NodeId::Client, cluster.clients) {
+                simulation.send(client, Message::Control(ControlSignal::Run(8)));
+            }
+            // one of each role down leaves a quorum of every role
+            assert_eq!(simulation.wait_for_clients(Duration::from_secs(5)), Ok(()), "seed {}: crashed {:?}", seed, crashed);
+            simulation.run_for(Duration::from_millis(500));
+            for node in crashed {
+                simulation.restart(node);
+            }
+            for client in transport::nodes(NodeId::Client, cluster.clients) {
+                simulation.send(client, Message::Control(ControlSignal::Run(12)));
+            }
+
+            // every command is answered in the end, and every decision agreed with the others for its slot
+            let outcome = simulation.wait_for_clients(Duration::from_secs(30));
+            assert_eq!(outcome, Ok(()), "seed {}: crashed {:?}", seed, crashed);
+            assert_linearizable(simulation.get_history(), seed);
+            std::fs::remove_dir_all(&wal_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn partitioned_cluster() {
+        let cluster = ClusterSize { clients: 2, replicas: 3, leaders: 2, acceptors: 3 };
+        // leader 0 with a minority of the acceptors, for the first half second
+        let minority = Partition::isolate(&[NodeId::Leader(0), NodeId::Acceptor(0)]).healing_at(Duration::from_millis(500));
+        let plan = FaultPlan::default().with_partition(minority);
+        let system_handles =
+            SystemHandles::with_faults(cluster, SystemOptions::default(), plan, 0, Counter::default, Arc::new(counter_workload));
+
+        system_handles.activate(10, 2, 3, 2);
+        assert_eq!(system_handles.wait_for_clients(Duration::from_secs(20)), Ok(()));
+        assert_linearizable(system_handles.history(), 0);
+        system_handles.deactivate();
     }
 }
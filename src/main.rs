@@ -1,22 +1,33 @@
-use paxos::SystemHandles;
+use paxos::{ClusterConfig, SystemHandles, Counter, counter_workload};
+use std::env;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
 
 
 fn main() {
-        let client_count = 5 as usize;
-        let replica_count = 3 as usize;
-        let leader_count = 3 as usize;
-        let acceptor_count = 3 as usize;
         let num_msgs = 10u32;
-        let system_handles = SystemHandles::system_handle_management(
-            client_count,
-            replica_count,
-            leader_count,
-            acceptor_count,
-        );
-        system_handles.operation_control(
+
+        // the cluster of the config given as the only argument, else the default one
+        let system_handles = match env::args().nth(1) {
+            Some(path) => {
+                let config = ClusterConfig::load(Path::new(&path)).unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                });
+                SystemHandles::from_config(&config, Counter::default, Arc::new(counter_workload))
+            }
+            None => SystemHandles::system_handle_management(5, 3, 3, 3),
+        };
+
+        let outcome = system_handles.operation_control(
             num_msgs,
-            client_count as u32,
-            replica_count as u32,
-            leader_count as u32,
+            system_handles.client_count(),
+            system_handles.replica_count(),
+            system_handles.leader_count(),
         );
+        if let Err(err) = outcome {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
 }
@@ -0,0 +1,62 @@
+use std::thread;
+use std::time::Instant;
+
+use crate::replica::SystemMessage;
+use crate::state_machine::StateMachine;
+use crate::transport::{NodeId, Transport};
+
+
+// a role as seen from whatever drives it, its own thread or the simulator
+// the node only reacts to the messages handed to it and to the time going by, it never blocks
+// the time comes from the endpoint, so a simulated node runs on the clock of the simulation
+pub trait Node<S: StateMachine>: Send {
+    fn get_id(&self) -> NodeId;
+
+    // endpoint of the node, messages go out through it
+    fn transport(&self) -> &dyn Transport<SystemMessage<S>>;
+
+    // handle one message received on the endpoint
+    fn handle_message(&mut self, message: SystemMessage<S>);
+
+    // do whatever is due by now, e.g. heartbeats, retries, commands waiting to go out
+    // called after every message too
+    fn handle_timers(&mut self);
+
+    // the next time handle_timers has something to do
+    // None when only a message can change anything, e.g. while paused
+    fn next_wakeup(&self) -> Option<Instant>;
+
+    // once exited the node handles nothing anymore and can be dropped
+    fn has_exited(&self) -> bool;
+}
+
+
+
+
+// running the node on a thread of its own until it exits
+// the thread sleeps on the endpoint until a message arrives or the next wakeup is due
+pub fn spawn<S: StateMachine>(mut node: Box<dyn Node<S>>) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .spawn(move || {
+            while !node.has_exited() {
+                let message = {
+                    let transport = node.transport();
+                    match node.next_wakeup() {
+                        Some(wakeup) => transport.recv_timeout(wakeup.saturating_duration_since(transport.now())),
+                        None => match transport.recv() {
+                            Some(message) => Some(message),
+                            None => panic!("{} transport closed", node.get_id()),
+                        },
+                    }
+                };
+
+                if let Some(message) = message {
+                    node.handle_message(message);
+                }
+                if !node.has_exited() {
+                    node.handle_timers();
+                }
+            }
+        })
+        .unwrap()
+}
@@ -1,50 +1,180 @@
-use crossbeam::channel::{Receiver, Sender, TryRecvError};
 use std::collections::VecDeque;
 use std::thread;
-use std::time::Duration;
-use std::collections::HashMap;
-use std::sync::atomic::compiler_fence;
-use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use crate::broadcast_channel::BroadcastSender;
-use crate::utils::{Operation, Command, Request, Decision, Response, Propose};
+use crate::codec::{Encode, Decode, Cursor, DecodeError};
+use crate::node::{self, Node};
+use crate::state_machine::StateMachine;
+use crate::SystemOptions;
+use crate::transport::{self, NodeId, Transport};
+use crate::utils::{Operation, Command, Request, Decision, Response, Propose, CatchUpRequest, CatchUpReply, CatchUpMessage, ControlSignal, Message};
 
 
+// number of slots a replica may propose ahead of slot_out
+// a reconfiguration decided in slot s governs the slots from s + WINDOW onward
+pub const WINDOW: u32 = 5;
+
+// default number of executed slots between two snapshots
+pub const SNAPSHOT_INTERVAL: u32 = 100;
+
+// by default a replica holding decisions beyond a missing slot_out for this long asks its peers for the missing ones
+// and proposes its own outstanding commands again, also when nothing later has been decided
+pub const CATCH_UP_TIMEOUT: Duration = Duration::from_millis(50);
+
 enum OperatingState {
     Paused,
     Run(u32),
+    // everything is dropped until the restart signal
+    Crashed,
     Exit,
 }
 
-#[derive(Clone)]
-pub enum ControlSignal {
-    Paused,
-    Run(u32),
-    Exit,
+// per-client sessions, keyed by client ID
+// a command that comes back after being executed is answered from here instead of being executed again
+// every command carries the client's acknowledgement, what the client is done with is dropped when it is executed
+#[derive(Debug, Clone)]
+pub struct SessionTable<R> {
+    sessions: HashMap<u32, Session<R>>,
 }
 
-pub struct Context {
-    // ID of the leader
-    id: u32,
+#[derive(Debug, Clone)]
+struct Session<R> {
+    // every command ID up to this one has been executed
+    // IDs are handed out in order by the clients, so the executed ones collapse into this prefix
+    up_to: u32,
 
+    // executed command IDs beyond up_to, the client may have several commands in flight
+    // the holes left by commands given up on are closed by the acknowledgements
+    beyond: BTreeSet<u32>,
 
+    // results of the executed commands the client hasn't acknowledged yet, in execution order
+    results: VecDeque<(u32, R)>,
+}
 
-    // for broadcast mechanism
+impl<R> Default for Session<R> {
+    fn default() -> Session<R> {
+        Session { up_to: 0, beyond: BTreeSet::new(), results: VecDeque::new() }
+    }
+}
 
-    // handle for the broadcast channel between all clients and the replica
-    client_replica_broadcast_chan_receiver: Vec<Receiver<Request>>,
+impl<R> Default for SessionTable<R> {
+    fn default() -> SessionTable<R> {
+        SessionTable { sessions: HashMap::new() }
+    }
+}
+
+impl<R: Clone> SessionTable<R> {
+    pub fn is_executed(&self, client_id: u32, command_id: u32) -> bool {
+        match self.sessions.get(&client_id) {
+            Some(session) => command_id <= session.up_to || session.beyond.contains(&command_id),
+            None => false,
+        }
+    }
 
-    // vec of handle for the mpsc channels from the replica to all the clients
-    replica_all_clients_mpsc_chan_senders: Vec<Sender<Response>>,
+    // cached result of an executed command, None once acknowledged or if the command has no result
+    pub fn get_result(&self, client_id: u32, command_id: u32) -> Option<R> {
+        let session = self.sessions.get(&client_id)?;
+        session.results
+            .iter()
+            .rev()
+            .find(|(id, _)| *id == command_id)
+            .map(|(_, result)| result.clone())
+    }
 
-    // handle to send broadcast messages to the leaders
-    replica_leader_broadcast_chan_sender: BroadcastSender<Propose>,
+    pub fn record(&mut self, client_id: u32, command_id: u32, result: Option<R>) {
+        let session = self.sessions.entry(client_id).or_default();
+        if command_id > session.up_to {
+            session.beyond.insert(command_id);
+        }
+        session.collapse();
+
+        if let Some(result) = result {
+            session.results.push_back((command_id, result));
+        }
+    }
+
+    // the client has answered or given up on every command before acknowledged
+    // those count as executed from now on, a late copy of one given up on is not executed any more
+    pub fn acknowledge(&mut self, client_id: u32, acknowledged: u32) {
+        let session = self.sessions.entry(client_id).or_default();
+        if acknowledged > session.up_to + 1 {
+            session.up_to = acknowledged - 1;
+            session.beyond = session.beyond.split_off(&acknowledged);
+            session.collapse();
+        }
+        session.results.retain(|(command_id, _)| *command_id >= acknowledged);
+    }
+}
+
+impl<R> Session<R> {
+    // moving up_to over the executed IDs right after it
+    fn collapse(&mut self) {
+        while self.beyond.remove(&(self.up_to + 1)) {
+            self.up_to += 1;
+        }
+    }
+}
+
+
+
+
+// application state after executing every slot below slot
+#[derive(Debug)]
+pub struct Snapshot<S: StateMachine> {
+    slot: u32,
+    state: S::Snapshot,
+    sessions: SessionTable<S::Result>,
+    configs: BTreeMap<u32, Vec<u32>>,
+}
+
+// the state machine itself doesn't have to be Clone
+impl<S: StateMachine> Clone for Snapshot<S> {
+    fn clone(&self) -> Snapshot<S> {
+        Snapshot {
+            slot: self.slot,
+            state: self.state.clone(),
+            sessions: self.sessions.clone(),
+            configs: self.configs.clone(),
+        }
+    }
+}
+
+// message between the nodes of a system replicating the state machine S
+pub type SystemMessage<S> = Message<<S as StateMachine>::Command, <S as StateMachine>::Result, Snapshot<S>>;
+
+// endpoint of a node of such a system
+pub type SystemTransport<S> = Box<dyn Transport<SystemMessage<S>>>;
+
+impl<S: StateMachine> Snapshot<S> {
+    pub fn get_slot(&self) -> u32 {
+        self.slot
+    }
+
+    pub fn get_state(&self) -> S::Snapshot {
+        self.state.clone()
+    }
+}
 
-    // handle for the receiver side of broadcast channel between all leaders and the replica
-    leader_replica_broadcast_chan_receiver: Vec<Receiver<Decision>>,
 
-    // handle to receive contral signals
-    control_chan_receiver: Receiver<ControlSignal>,
+
+
+pub struct Context<S: StateMachine> {
+    // ID of the leader
+    id: u32,
+
+
+
+    // for communication
+
+    // endpoint of the replica, for everything it sends and receives
+    transport: Box<dyn Transport<SystemMessage<S>>>,
+
+    // all the other replicas, asked for missing decisions when catching up
+    peers: Vec<NodeId>,
+
+    // messages that arrived while the replica was paused, handled once it runs
+    deferred: VecDeque<SystemMessage<S>>,
 
     // operation state of the replica
     operating_state: OperatingState,
@@ -54,7 +184,7 @@ pub struct Context {
     // for consensus mechanism
     // all taken from the PMMC paper
     // application state
-    state: i32, 
+    state: S,
 
     // index of the next slot in replica has not proposed any command yet
     slot_in: u32,
@@ -63,257 +193,386 @@ pub struct Context {
     slot_out: u32,
 
     // set of requests that replica hasn't proposed or decided yet
-    requests: VecDeque<Command>,
+    requests: VecDeque<Command<S::Command>>,
 
     // set of proposals that are currently outstanding
-    proposals: HashMap<u32, Command>,
+    proposals: HashMap<u32, Command<S::Command>>,
 
     // set of proposals that are known to have been decided
-    decisions: HashMap<u32, Command>,
+    // slots below the last snapshot are dropped
+    decisions: HashMap<u32, Command<S::Command>>,
+
+    // executed commands and their results, per client
+    sessions: SessionTable<S::Result>,
 
-    // skipping the leaders for now
-    //static configuration
+    // IDs of the leaders in the current configuration
+    // proposals are only sent to these leaders
+    leaders: Vec<u32>,
 
+    // leader sets indexed by the first slot they govern
+    // only the one in force at the last snapshot and later ones are kept
+    configs: BTreeMap<u32, Vec<u32>>,
+
+    // last snapshot taken
+    snapshot: Option<Snapshot<S>>,
+
+    // state before the first slot, a replica keeps nothing on disk and comes back from a crash with it
+    initial: Snapshot<S>,
+
+    // number of executed slots between two snapshots
+    snapshot_interval: u32,
+
+    // time slot_out may be missing before the peers are asked for it
+    catch_up_timeout: Duration,
+
+    // time at which the peers are asked for the missing decisions
+    // set while slot_out is missing but later slots are decided, or while the own proposal for slot_out is outstanding
+    // restarted whenever slot_out moves on
+    catch_up_due: Option<Instant>,
 }
 
-pub fn new(
+pub fn new<S: StateMachine>(
     id: u32,
-    client_replica_broadcast_chan_receiver: Vec<Receiver<Request>>,
-    replica_all_clients_mpsc_chan_senders: Vec<Sender<Response>>,
-    replica_leader_broadcast_chan_sender: BroadcastSender<Propose>,
-    leader_replica_broadcast_chan_receiver: Vec<Receiver<Decision>>,
-    control_chan_receiver: Receiver<ControlSignal>,
-) -> Context {
-    let context = Context {
+    state: S,
+    transport: Box<dyn Transport<SystemMessage<S>>>,
+    num_replicas: u32,
+    num_leaders: u32,
+    options: &SystemOptions,
+) -> Context<S> {
+    // initial configuration has all the leaders
+    let leaders: Vec<u32> = (0..num_leaders).collect();
+    let initial = Snapshot {
+        slot: 1,
+        state: state.snapshot(),
+        sessions: SessionTable::default(),
+        configs: BTreeMap::from([(1u32, leaders.clone())]),
+    };
+
+    Context {
         id,
-        client_replica_broadcast_chan_receiver,
-        replica_all_clients_mpsc_chan_senders,
-        replica_leader_broadcast_chan_sender,
-        leader_replica_broadcast_chan_receiver,
-        control_chan_receiver,
+        transport,
+        peers: transport::nodes(NodeId::Replica, num_replicas)
+            .into_iter()
+            .filter(|node| *node != NodeId::Replica(id))
+            .collect(),
+        deferred: VecDeque::new(),
         operating_state: OperatingState::Paused,
-        state: 0i32,
+        state,
         slot_in: 1u32,
         slot_out: 1u32,
         requests: VecDeque::new(),
         proposals: HashMap::new(),
-        decisions: HashMap::new()
-    };
-
-    context
+        decisions: HashMap::new(),
+        sessions: SessionTable::default(),
+        configs: BTreeMap::from([(1u32, leaders.clone())]),
+        leaders,
+        snapshot: None,
+        initial,
+        snapshot_interval: options.snapshot_interval,
+        catch_up_timeout: options.catch_up_timeout,
+        catch_up_due: None,
+    }
 }
 
-impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
-            .spawn(move || {
-                loop {
-                    match self.operating_state {
-                        OperatingState::Paused => {
-                            // println!("Replica {} in paused mode", self.id);
-                            let signal = self.control_chan_receiver.recv().unwrap();
-                            // transition in operating state
-                            self.handle_control_signal(signal);
-                        }
-
-                        OperatingState::Run(num_msgs) => {
-                            // send message to the receiver
-                            match self.control_chan_receiver.try_recv() {
-                                Ok(signal) => {
-                                    // Exit signal received
-                                    self.handle_control_signal(signal);
-                                }
-                                Err(TryRecvError::Empty) => {
-                                    self.processing_broadcast_message_from_client();
-                                    self.processing_decision_message_from_leader();
-                                    self.propose();  
-                                }
-                                Err(TryRecvError::Disconnected) => {
-                                    panic!("Replica control channel detached")
-                                }
-                            };
-                        }
-
-                        OperatingState::Exit => {
-                            // uncomment the following if you want to see the log
-                            // println!("Decision list at replica {} is {:#?}", self.id, self.decisions);
-                            println!("The state at replica {} is {}", self.id, self.state);
-                            println!("Replica {} deactivated.......................", self.id);
-                            break;
-                        }
-                    }
+impl<S: StateMachine> Context<S> {
+    pub fn start(self) -> thread::JoinHandle<()> {
+        node::spawn(Box::new(self))
+    }
 
-                    thread::sleep(Duration::from_nanos(100));
-                }
-            })
-            .unwrap();
-    }
-
-    // receiving and processing of the messages received from clients
-    // sending broadcast messages to the leaders
-    fn processing_broadcast_message_from_client(&mut self) {
-        // process the messages received from the clients
-        // iterate over the receiver handles from all the clients to scan for any possible messages
-        for handle in &self.client_replica_broadcast_chan_receiver {
-            // using try_recv() so that we have non-blocking operation for replica
-            match handle.try_recv() {
-                // received a new message from client
-                Ok(message) => {
-                    // println!("The received message at replica {} is {:#?}", self.id, message);
-                    // push into the requests 
-                    self.requests.push_back(message.clone().get_command());
-                }
 
-                _ => {}
-            }
-        }
 
+    // a request from a client is queued for proposing, unless it has been executed already
+    fn processing_request_from_client(&mut self, request: Request<S::Command>) {
+        // println!("The received message at replica {} is {:#?}", self.id, request);
+        let command = request.get_command();
+        if self.sessions.is_executed(command.get_client_id(), command.get_command_id()) {
+            // a retry of an executed command, no need to go through consensus again
+            self.respond(command.get_client_id(), command.get_command_id());
+        } else {
+            // push into the requests 
+            self.requests.push_back(command);
+        }
     }
 
 
 
-    fn processing_decision_message_from_leader(&mut self) {
-        // process the decision messages received from the leader
-        for handle in &self.leader_replica_broadcast_chan_receiver {
-            match handle.try_recv() {
-                Ok(message) => {
-                    let (command, slot) = message.get_details();
-                    self.decisions.insert(slot, command);
-                    // println!("Decision message inserted");
-                    
+    // sending the cached result of an executed command to its client
+    // nothing is sent once the client has acknowledged the command, it doesn't wait for it any more
+    fn respond(&self, client_id: u32, command_id: u32) {
+        if let Some(result) = self.sessions.get_result(client_id, command_id) {
+            self.transport.send(NodeId::Client(client_id), Message::Response(Response::create(command_id, result)));
+        }
+    }
 
 
-                    while self.decisions.contains_key(&self.slot_out) == true {
-                        let command_prime = self.decisions.get(&self.slot_out).unwrap().clone();
-                        if self.proposals.contains_key(&self.slot_out) == true {
-                            // removed from proposals
-                            let command_prime_prime = self.proposals.remove(&self.slot_out).unwrap();
-                            if command_prime_prime != command_prime {
-                                self.requests.push_back(command_prime_prime);
-                            }
-                        }
 
+    fn processing_decision_from_leader(&mut self, decision: Decision<S::Command>) {
+        let (command, slot) = decision.get_details();
+        // decisions for executed slots are late copies from other leaders
+        if slot >= self.slot_out {
+            self.decisions.insert(slot, command);
+        }
+        // println!("Decision message inserted");
+        self.perform_decisions();
 
+        let snapshot_slot = self.get_snapshot_slot();
+        if self.slot_out > snapshot_slot && self.slot_out - snapshot_slot >= self.snapshot_interval {
+            self.take_snapshot();
+        }
+    }
 
 
-                        let mut next = 0i32;
-                        let mut result = 0i32;  
 
-                        // skipping the true case as it only involves slot_out increment only
-                        // increment done outside
-                        if self.decision_contains_command(command_prime.clone()) == false {
-                            // getting updated state
-                            // state and result same for our case -> bit unclear
-                            match command_prime.get_operation() {
-                                Operation::Add(x) => {
-                                    next = self.state.clone() + x;
-                                    result = self.state.clone() + x;
-                                }
+    // executing the decided commands in slot order, starting at slot_out
+    fn perform_decisions(&mut self) {
+        if self.decisions.contains_key(&self.slot_out) {
+            self.catch_up_due = None;
+        }
+        while self.decisions.contains_key(&self.slot_out) {
+            let command_prime = self.decisions.get(&self.slot_out).unwrap().clone();
+            if self.proposals.contains_key(&self.slot_out) {
+                // removed from proposals
+                let command_prime_prime = self.proposals.remove(&self.slot_out).unwrap();
+                if command_prime_prime != command_prime {
+                    self.requests.push_back(command_prime_prime);
+                }
+            }
 
-                                Operation::Subtract(y) => {
-                                    next = self.state.clone() - y;
-                                    result = self.state.clone() - y;
 
-                                }
 
-                                Operation::Multiply(z) => {
-                                    next = self.state.clone() * z;
-                                    result = self.state.clone() * z;
-                                }
 
-                                _ => {}
-                            }
-                            self.replica_all_clients_mpsc_chan_senders[command_prime.get_client_id() as usize]
-                                .send(Response::create(command_prime.get_command_id(), result));
+            // skipping the true case as it only involves slot_out increment only
+            // increment done outside
+            // null commands don't touch the application state
+            // reconfig commands only record the new leader set, it is picked up in propose
+            if command_prime.get_operation() != Operation::Null {
+                self.sessions.acknowledge(command_prime.get_client_id(), command_prime.get_acknowledged());
+            }
+            match command_prime.get_operation() {
+                Operation::Apply(operation) => {
+                    let (client_id, command_id) = (command_prime.get_client_id(), command_prime.get_command_id());
+                    if !self.sessions.is_executed(client_id, command_id) {
+                        let result = self.state.apply(&operation);
+                        self.sessions.record(client_id, command_id, Some(result));
+                    }
+                    // a command decided again is answered with the cached result
+                    self.respond(client_id, command_id);
+                }
 
-                            compiler_fence(Ordering::Acquire);
-                            self.slot_out += 1;
-                            self.state = next;
-                            compiler_fence(Ordering::Release);
-                        } else {
-                            self.slot_out += 1;
-                        }
-                        
+                Operation::Reconfig(leaders) => {
+                    let (client_id, command_id) = (command_prime.get_client_id(), command_prime.get_command_id());
+                    if !self.sessions.is_executed(client_id, command_id) {
+                        self.configs.insert(self.slot_out + WINDOW, leaders);
+                        self.sessions.record(client_id, command_id, None);
                     }
                 }
 
-                _ => {}
+                Operation::Null => {}
             }
-
-
+            self.slot_out += 1;
         }
     }
 
 
 
-    // for checking whether a command is in the decision
-    // I think we can do better
-    fn decision_contains_command(&self, command: Command) -> bool {
-        for slot in self.decisions.keys() {
-            if (self.decisions.get(slot).unwrap().clone() == command) && (slot.clone() < self.slot_out) {
-                return true;
-            } 
+    // sending the decisions from the requested slot onward
+    // the snapshot goes along when some of them have already been compacted away
+    fn handle_catch_up_request(&self, request: CatchUpRequest) {
+        let snapshot = match &self.snapshot {
+            Some(snapshot) if snapshot.slot > request.get_slot() => Some(snapshot.clone()),
+            _ => None,
+        };
+        let from = match &snapshot {
+            Some(snapshot) => snapshot.slot,
+            None => request.get_slot(),
+        };
+        let mut decisions: Vec<(u32, Command<S::Command>)> = self.decisions
+            .iter()
+            .filter(|(slot, _)| **slot >= from)
+            .map(|(slot, command)| (*slot, command.clone()))
+            .collect();
+        decisions.sort_unstable_by_key(|(slot, _)| *slot);
+
+        // nothing the peer doesn't have already
+        if snapshot.is_none() && decisions.first().is_none_or(|(slot, _)| *slot != request.get_slot()) {
+            return;
         }
-        return false;
+
+        // println!("Replica {} helps replica {} catch up from slot {}", self.id, request.get_replica_id(), from);
+        self.transport.send(
+            NodeId::Replica(request.get_replica_id()),
+            Message::CatchUp(CatchUpMessage::Reply(CatchUpReply::create(snapshot, decisions))),
+        );
     }
 
-    /*
-    fn perform(&self, command: Command)-> i32 {
 
-        let mut next = 0i32;
-        let mut result = 0i32;  
 
-        // skipping the true case as it only involves slot_out increment only
-        // increment done outside
-        if self.decision_contains_command(command.clone()) == false {
-            // getting updated state
-            // state and result same for our case -> bit unclear
-            match command.get_operation() {
-                Operation::Add(x) => {
-                    next = self.state.clone() + x;
-                    result = self.state.clone() + x;
-                }
+    // installing the snapshot if it is ahead of slot_out and then executing the decisions
+    fn handle_catch_up_reply(&mut self, reply: CatchUpReply<S::Command, Snapshot<S>>) {
+        let (snapshot, decisions) = reply.get_details();
 
-                Operation::Subtract(y) => {
-                    next = self.state.clone() - y;
-                    result = self.state.clone() - y;
+        if let Some(snapshot) = snapshot {
+            if snapshot.slot > self.slot_out {
+                println!("Replica {} installs the snapshot at slot {}", self.id, snapshot.slot);
+                self.state.restore(snapshot.state.clone());
+                self.sessions = snapshot.sessions.clone();
+                self.configs = snapshot.configs.clone();
+                self.slot_out = snapshot.slot;
+                self.decisions.retain(|decided_slot, _| *decided_slot >= snapshot.slot);
 
+                // own proposals for the skipped slots may have lost, they are proposed again
+                let mut skipped: Vec<u32> = self.proposals.keys().filter(|slot| **slot < snapshot.slot).cloned().collect();
+                skipped.sort_unstable();
+                for slot in skipped {
+                    let command = self.proposals.remove(&slot).unwrap();
+                    if !self.sessions.is_executed(command.get_client_id(), command.get_command_id()) {
+                        self.requests.push_back(command);
+                    }
                 }
+                self.snapshot = Some(snapshot);
+            }
+        }
 
-                Operation::Multiply(z) => {
-                    next = self.state.clone() * z;
-                    result = self.state.clone() * z;
-                }
+        for (slot, command) in decisions {
+            if slot >= self.slot_out {
+                self.decisions.insert(slot, command);
+            }
+        }
+        self.perform_decisions();
+    }
+
+
+
+    // asking the peers for the missing decisions once slot_out has been missing for the catch-up timeout
+    // the own proposals of the missing slots go to the leaders again, they may never have got there
+    fn catch_up_timer(&mut self) {
+        let stuck = !self.decisions.contains_key(&self.slot_out)
+            && (self.decisions.keys().any(|slot| *slot > self.slot_out) || self.proposals.contains_key(&self.slot_out));
+        if !stuck {
+            self.catch_up_due = None;
+            return;
+        }
+
+        let now = self.transport.now();
+        match self.catch_up_due {
+            None => {
+                self.catch_up_due = Some(now + self.catch_up_timeout);
+            }
 
-                _ => {}
+            Some(due) if now >= due => {
+                self.ask_to_catch_up();
+                self.propose_again();
+                self.catch_up_due = Some(now + self.catch_up_timeout);
             }
+
+            Some(_) => {}
         }
-        
+    }
+
+
+
+    // asking the peers for the decisions from slot_out on, or a snapshot if they have compacted them
+    // also sent once the replica runs, it may join a cluster that has been deciding for a while and is idle now
+    fn ask_to_catch_up(&self) {
+        // println!("Replica {} asks to catch up from slot {}", self.id, self.slot_out);
+        self.transport.broadcast(
+            &self.peers,
+            Message::CatchUp(CatchUpMessage::Request(CatchUpRequest::create(self.id, self.slot_out))),
+        );
+    }
 
-        
-        // send response to the client
-        self.replica_all_clients_mpsc_chan_senders[command.get_client_id() as usize]
-            .send(Response::create(command.get_command_id(), result));
 
-        println!("state update sent {} at replica {}", next, self.id);
-        next
+
+    // the outstanding proposals from slot_out on, in slot order
+    // a leader that has it already ignores a proposal, one that lost it or was cut off takes it up
+    fn propose_again(&self) {
+        let mut missing: Vec<u32> = self
+            .proposals
+            .keys()
+            .filter(|slot| **slot >= self.slot_out && !self.decisions.contains_key(slot))
+            .cloned()
+            .collect();
+        missing.sort_unstable();
+
+        let leaders: Vec<NodeId> = self.leaders.iter().map(|leader_id| NodeId::Leader(*leader_id)).collect();
+        for slot in missing {
+            self.transport.broadcast(&leaders, Message::Propose(Propose::create(self.id, slot, self.proposals[&slot].clone())));
+        }
+    }
+
 
 
+    // everything the replica knows is lost, it is back at the initial state
+    // the decisions come back from the peers, the replica asks for them as soon as it runs again
+    fn reset(&mut self) {
+        let initial = self.initial.clone();
+        self.state.restore(initial.state);
+        self.sessions = initial.sessions;
+        self.leaders = initial.configs[&initial.slot].clone();
+        self.configs = initial.configs;
+        self.slot_in = initial.slot;
+        self.slot_out = initial.slot;
+        self.requests.clear();
+        self.proposals.clear();
+        self.decisions.clear();
+        self.snapshot = None;
+        self.catch_up_due = None;
+        self.deferred.clear();
     }
-    */
-   
+
+
+
+    // first slot not covered by the last snapshot
+    fn get_snapshot_slot(&self) -> u32 {
+        self.snapshot.as_ref().map_or(1, |snapshot| snapshot.slot)
+    }
+
+
+
+    // snapshot of the application state at slot_out
+    // the decisions below slot_out are covered by it and dropped
+    fn take_snapshot(&mut self) {
+        let slot = self.slot_out;
+        self.decisions.retain(|decided_slot, _| *decided_slot >= slot);
+
+        // the configuration in force at slot and the later ones are all that's needed from here on
+        if let Some(in_force) = self.configs.range(..=slot).next_back().map(|(first_slot, _)| *first_slot) {
+            self.configs = self.configs.split_off(&in_force);
+        }
+
+        self.snapshot = Some(Snapshot {
+            slot,
+            state: self.state.snapshot(),
+            sessions: self.sessions.clone(),
+            configs: self.configs.clone(),
+        });
+    }
+
+
 
     fn propose(&mut self) {
-        // if requests is not empty
-        while self.requests.is_empty() == false {
-            // skipped first as there is no reconfig operation
-            if self.decisions.contains_key(&self.slot_in) == false {
+        // slots below the snapshot are decided even though the decisions are gone
+        if self.slot_in < self.get_snapshot_slot() {
+            self.slot_in = self.get_snapshot_slot();
+        }
+
+        // if requests is not empty and slot_in is within the window
+        while self.slot_in < self.slot_out + WINDOW && !self.requests.is_empty() {
+            // reconfig decided WINDOW slots back takes effect from slot_in
+            if let Some((_, leaders)) = self.configs.range(..=self.slot_in).next_back() {
+                if *leaders != self.leaders {
+                    println!("Replica {} switched to leaders {:?} from slot {}", self.id, leaders, self.slot_in);
+                    self.leaders = leaders.clone();
+                }
+            }
+
+            if !self.decisions.contains_key(&self.slot_in) {
                 let command = self.requests.pop_front().unwrap();
-                self.proposals.insert(self.slot_in.clone(), command.clone());
-                // uncomment the following line later
-                // broadcast to leaders
+                self.proposals.insert(self.slot_in, command.clone());
+                // send to the leaders of the current configuration
                 // println!("Replica {} has broadcast propose message", self.id);
-                self.replica_leader_broadcast_chan_sender.send(Propose::create(self.slot_in.clone(), command));
+                let leaders: Vec<NodeId> = self.leaders.iter().map(|leader_id| NodeId::Leader(*leader_id)).collect();
+                self.transport.broadcast(&leaders, Message::Propose(Propose::create(self.id, self.slot_in, command)));
             }
             self.slot_in += 1;
         }
@@ -332,12 +591,164 @@ impl Context {
             ControlSignal::Run(num_msgs) => {
                 println!("Replica {} activated!", self.id);
                 self.operating_state = OperatingState::Run(num_msgs);
+
+                // the messages deferred while paused come first
+                let deferred = std::mem::take(&mut self.deferred);
+                for message in deferred {
+                    self.handle_message(message);
+                }
+                self.ask_to_catch_up();
             }
 
             ControlSignal::Exit => {
                 // println!("Exit signal at Replica {} received", self.id);
+                // uncomment the following if you want to see the log
+                // println!("Decision list at replica {} is {:#?}", self.id, self.decisions);
+                println!("The state at replica {} is {:?}", self.id, self.state.snapshot());
+                println!("Replica {} deactivated.......................", self.id);
                 self.operating_state = OperatingState::Exit;
             }
+
+            ControlSignal::Crash => {
+                println!("Replica {} crashed", self.id);
+                self.reset();
+                self.operating_state = OperatingState::Crashed;
+            }
+
+            ControlSignal::Restart => {
+                println!("Replica {} restarted", self.id);
+                self.reset();
+                self.operating_state = OperatingState::Run(0);
+                self.ask_to_catch_up();
+            }
+
+            ControlSignal::Wakeup => {}
+        }
+    }
+}
+
+
+
+
+impl<S: StateMachine> Node<S> for Context<S> {
+    fn get_id(&self) -> NodeId {
+        NodeId::Replica(self.id)
+    }
+
+    fn transport(&self) -> &dyn Transport<SystemMessage<S>> {
+        self.transport.as_ref()
+    }
+
+    fn handle_message(&mut self, message: SystemMessage<S>) {
+        match (&self.operating_state, message) {
+            // a crashed replica only comes back on the restart signal, or exits
+            (OperatingState::Crashed, Message::Control(signal @ (ControlSignal::Restart | ControlSignal::Exit))) => {
+                self.handle_control_signal(signal)
+            }
+            (OperatingState::Crashed, _) => {}
+            (_, Message::Control(signal)) => self.handle_control_signal(signal),
+            // only control signals are handled while paused, the rest waits until the replica runs
+            (OperatingState::Paused, message) => self.deferred.push_back(message),
+            (OperatingState::Run(_), Message::Request(request)) => self.processing_request_from_client(request),
+            (OperatingState::Run(_), Message::Decision(decision)) => self.processing_decision_from_leader(decision),
+            (OperatingState::Run(_), Message::CatchUp(CatchUpMessage::Request(request))) => self.handle_catch_up_request(request),
+            (OperatingState::Run(_), Message::CatchUp(CatchUpMessage::Reply(reply))) => self.handle_catch_up_reply(reply),
+            // nothing else is meant for a replica
+            _ => {}
+        }
+    }
+
+    fn handle_timers(&mut self) {
+        if let OperatingState::Run(_) = self.operating_state {
+            self.catch_up_timer();
+            self.propose();
+        }
+    }
+
+    // asking the peers for missing decisions is the only thing a replica does on its own
+    fn next_wakeup(&self) -> Option<Instant> {
+        match self.operating_state {
+            OperatingState::Run(_) => self.catch_up_due,
+            _ => None,
+        }
+    }
+
+    fn has_exited(&self) -> bool {
+        matches!(self.operating_state, OperatingState::Exit)
+    }
+}
+
+
+
+
+// snapshots travel in catch-up replies, so they need an encoding once the nodes are in separate processes
+// only for state machines whose snapshots and results can be encoded themselves
+
+impl<R: Encode> Encode for SessionTable<R> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        // sorted by client so that equal tables encode the same
+        let mut client_ids: Vec<&u32> = self.sessions.keys().collect();
+        client_ids.sort();
+        (client_ids.len() as u32).encode(buf);
+        for client_id in client_ids {
+            let session = &self.sessions[client_id];
+            client_id.encode(buf);
+            session.up_to.encode(buf);
+            session.beyond.iter().cloned().collect::<Vec<u32>>().encode(buf);
+            (session.results.len() as u32).encode(buf);
+            for (command_id, result) in &session.results {
+                command_id.encode(buf);
+                result.encode(buf);
+            }
+        }
+    }
+}
+
+impl<R: Decode> Decode for SessionTable<R> {
+    fn decode(cursor: &mut Cursor) -> Result<SessionTable<R>, DecodeError> {
+        let len = cursor.get_u32()?;
+        let mut sessions = HashMap::new();
+        for _ in 0..len {
+            let client_id = cursor.get_u32()?;
+            let up_to = cursor.get_u32()?;
+            let beyond = Vec::<u32>::decode(cursor)?.into_iter().collect();
+            let num_results = cursor.get_u32()?;
+            let mut results = VecDeque::new();
+            for _ in 0..num_results {
+                let command_id = cursor.get_u32()?;
+                results.push_back((command_id, R::decode(cursor)?));
+            }
+            sessions.insert(client_id, Session { up_to, beyond, results });
         }
+        Ok(SessionTable { sessions })
+    }
+}
+
+
+impl<S: StateMachine> Encode for Snapshot<S>
+where
+    S::Snapshot: Encode,
+    S::Result: Encode,
+{
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.slot.encode(buf);
+        self.state.encode(buf);
+        self.sessions.encode(buf);
+        self.configs.encode(buf);
+    }
+}
+
+impl<S: StateMachine> Decode for Snapshot<S>
+where
+    S::Snapshot: Decode,
+    S::Result: Decode,
+{
+    fn decode(cursor: &mut Cursor) -> Result<Snapshot<S>, DecodeError> {
+        Ok(Snapshot {
+            slot: cursor.get_u32()?,
+            state: S::Snapshot::decode(cursor)?,
+            sessions: SessionTable::decode(cursor)?,
+            configs: BTreeMap::decode(cursor)?,
+        })
     }
 }
@@ -1,110 +1,114 @@
-use crossbeam::channel::{Sender, Receiver, TryRecvError};
-use std::collections::VecDeque;
-use std::thread;
-use std::time::Duration;
 use std::collections::HashMap;
 
-use crate::broadcast_channel::BroadcastSender;
-use crate::utils::{Operation, Command, Decision, Ballot, P1a, P1b, P2a, P2b, Adopted, Preempted, ScoutMessage, Pvalue};
+use crate::transport::{NodeId, Transport};
+use crate::utils::{Ballot, P1a, P1b, Adopted, Preempted, ScoutMessage, Pvalue, Payload, Message};
 
 
 
-pub struct Context {
+// phase 1 of a ballot, driven by the leader
+// the leader routes the P1b for this scout to handle_p1b until the scout has an outcome
+pub struct Context<C: Payload> {
     // Id of the scout
     scout_id: u32,
 
     // Id of the leader
     leader_id: u32,
 
-    // broadcast handle to all acceptors
-    scout_acceptor_broadcast_chan_sender: BroadcastSender<P1a>,
-
-    // receiving handle of the channels from the leader to the scout
-    // for receiving P1b
-    leader_scout_receiver: Receiver<P1b>,
-
-    // sending handle of the channel from the scout to the leader
-    // for sending adopted and preempted
-    scout_leader_sender: Sender<ScoutMessage>,
-
     // ballot num the scout is responsible for
     ballot_num: Ballot,
 
     // list of all acceptors that have replied back with P1b
     waitfor: Vec<u32>,
 
-    // list of all pvalues received
-    pvalues: Vec<Pvalue>, 
+    // pvalue with the highest ballot received for each slot
+    pvalues: HashMap<u32, Pvalue<C>>,
+
+    // slots up to this one are known to be decided, acceptors leave them out of P1b
+    slot_watermark: u32,
 
+    // number of acceptors that have to reply, intersects every phase 2 quorum
+    quorum: u32,
 }
 
-pub fn new(
+pub fn new<C: Payload>(
     scout_id: u32,
     leader_id: u32,
-    scout_acceptor_broadcast_chan_sender: BroadcastSender<P1a>,
-    leader_scout_receiver: Receiver<P1b>,
-    scout_leader_sender: Sender<ScoutMessage>,
     ballot_num: Ballot,
-) -> Context {
-    let context = Context {
+    slot_watermark: u32,
+    quorum: u32,
+) -> Context<C> {
+    Context {
         scout_id,
         leader_id,
-        scout_acceptor_broadcast_chan_sender,
-        leader_scout_receiver,
-        scout_leader_sender,
         ballot_num,
         waitfor: Vec::new(),
-        pvalues: Vec::new(),
-    };
-
-    context
+        pvalues: HashMap::new(),
+        slot_watermark,
+        quorum,
+    }
 }
 
 
 
-impl Context {
-    pub fn start(mut self, num_acceptors: u32) {
-        // broadcast the P1a message to all acceptors
-        self.scout_acceptor_broadcast_chan_sender
-            .send(P1a::create(self.leader_id.clone(), self.ballot_num.clone(), self.scout_id.clone()));
+impl<C: Payload> Context<C> {
+    // broadcast the P1a message to all acceptors
+    pub fn start<R: Clone, P: Clone>(&self, transport: &dyn Transport<Message<C, R, P>>, acceptors: &[NodeId]) {
+        transport.broadcast(
+            acceptors,
+            Message::P1a(P1a::create(self.leader_id, self.ballot_num.clone(), self.scout_id, self.slot_watermark)),
+        );
         // println!("Scout of the leader {} has broadcast P1a message", self.leader_id);
+    }
 
 
-        // thread spawning
-        thread::Builder::new()
-            .spawn(move || {
-                loop {
-                    match self.leader_scout_receiver.try_recv() {
-                        Ok(message) => {
-                            // checking the ballot
-                            if message.get_ballot() == self.ballot_num.clone() {
-                                // updating pvalues
-                                self.pvalues.append(&mut message.get_pvalues());
-                                // updating waitfor
-                                self.waitfor.push(message.get_acceptor_id());
-                                // observe that the following inequality is opposite from in the PMMC
-                                if self.waitfor.len() as u32 > num_acceptors/2 {
-                                    // sending adopted message
-                                    self.scout_leader_sender
-                                        .send(ScoutMessage::Adopted(Adopted::create(self.ballot_num.clone(), self.pvalues.clone())));
-                                    break;
-                                }
-                            } else {
-                                // sending preempted message
-                                self.scout_leader_sender
-                                    .send(ScoutMessage::Preempted(Preempted::create(message.get_ballot())));
-                                break;
-                            }
-
-                        }
-                        _ => {}
-
-                    }
-                }
-
-            })
-            .unwrap();
 
+    // the P1a again to the acceptors that haven't replied, one of the messages may have been lost
+    pub fn resend<R: Clone, P: Clone>(&self, transport: &dyn Transport<Message<C, R, P>>, acceptors: &[NodeId]) {
+        let silent: Vec<NodeId> = acceptors
+            .iter()
+            .filter(|acceptor| !matches!(acceptor, NodeId::Acceptor(id) if self.waitfor.contains(id)))
+            .cloned()
+            .collect();
+        self.start(transport, &silent);
+    }
 
+
+
+    pub fn get_ballot_num(&self) -> Ballot {
+        self.ballot_num.clone()
     }
-}
\ No newline at end of file
+
+
+
+    // taking in a P1b from an acceptor
+    // returns the adopted or preempted message once the scout is done, the scout can be dropped then
+    pub fn handle_p1b(&mut self, message: P1b<C>) -> Option<ScoutMessage<C>> {
+        // checking the ballot
+        if message.get_ballot() != self.ballot_num {
+            // sending preempted message
+            return Some(ScoutMessage::Preempted(Preempted::create(message.get_ballot())));
+        }
+
+        // updating pvalues, keeping only the highest ballot per slot
+        for pvalue in message.get_pvalues() {
+            let newer = match self.pvalues.get(&pvalue.get_slot()) {
+                Some(current) => pvalue.get_ballot_num() > current.get_ballot_num(),
+                None => true,
+            };
+            if newer {
+                self.pvalues.insert(pvalue.get_slot(), pvalue);
+            }
+        }
+        // updating waitfor
+        if !self.waitfor.contains(&message.get_acceptor_id()) {
+            self.waitfor.push(message.get_acceptor_id());
+        }
+        // PMMC waits for a majority, which is the default quorum
+        if self.waitfor.len() as u32 >= self.quorum {
+            // sending adopted message
+            let pvalues = self.pvalues.drain().map(|(_, pvalue)| pvalue).collect();
+            return Some(ScoutMessage::Adopted(Adopted::create(self.ballot_num.clone(), pvalues)));
+        }
+        None
+    }
+}
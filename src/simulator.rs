@@ -0,0 +1,444 @@
+use rand::Rng;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::client::Workload;
+use crate::faults::{FaultPlan, Injector};
+use crate::history::History;
+use crate::leader::BallotChurn;
+use crate::node::Node;
+use crate::replica::SystemMessage;
+use crate::state_machine::StateMachine;
+use crate::transport::{ClusterSize, NodeId, Transport};
+use crate::utils::{Command, ControlSignal, Message};
+use crate::{OperationError, SystemOptions};
+
+
+// how the simulated network delivers the messages
+#[derive(Debug, Clone)]
+pub struct SimulationOptions {
+    // every message takes a random time in this range to arrive, so messages overtake each other
+    pub min_latency: Duration,
+    pub max_latency: Duration,
+
+    // faults of the links on top of the latency, drawn from the seed of the simulation as well
+    pub faults: FaultPlan,
+}
+
+impl Default for SimulationOptions {
+    fn default() -> SimulationOptions {
+        SimulationOptions {
+            min_latency: Duration::from_micros(100),
+            max_latency: Duration::from_millis(10),
+            faults: FaultPlan::default(),
+        }
+    }
+}
+
+
+
+
+// a decision as it was sent by a leader to a replica or to another leader
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DecisionSent {
+    // time into the simulation
+    pub at: Duration,
+    pub from: NodeId,
+    pub to: NodeId,
+    pub slot: u32,
+}
+
+
+
+
+// the whole cluster in a single thread, on a virtual clock
+// every step either delivers the message due first or runs the timers of the node due first
+// the delivery times come from an RNG seeded with the seed, so the same seed always gives the same run
+// every decision is checked against the earlier ones for its slot as it goes by, a conflict panics with the seed
+pub struct Simulation<S: StateMachine> {
+    seed: u64,
+
+    // draws the latency of every message
+    rng: StdRng,
+
+    // clock and outgoing messages, shared with the endpoints of the nodes
+    wire: Arc<Mutex<Wire<SystemMessage<S>>>>,
+
+    // time the simulation started at, the clock only moves forward from it
+    start: Instant,
+
+    cluster: ClusterSize,
+
+    options: SimulationOptions,
+
+    // drops, duplicates, delays and reorders the messages as the fault plan says
+    injector: Injector<SystemMessage<S>>,
+
+    // nodes that haven't exited, in ID order
+    nodes: BTreeMap<NodeId, Box<dyn Node<S>>>,
+
+    // messages on their way, by delivery time and then by the order they were sent in
+    in_flight: BTreeMap<(Instant, u64), Envelope<SystemMessage<S>>>,
+
+    // number of messages sent so far, orders the messages due at the same time
+    num_sent: u64,
+
+    // messages delivered to the control node, played by the simulation
+    control_inbox: VecDeque<SystemMessage<S>>,
+
+    // command decided for every slot by any leader
+    decided: HashMap<u32, Command<S::Command>>,
+
+    // every decision sent, in the order sent, including the ones the faults then lost
+    decisions_sent: Vec<DecisionSent>,
+
+    // commands of the clients, on the clock of the simulation
+    history: Arc<History<S>>,
+
+    // ballot churn of every leader, by leader ID
+    ballot_churn: Vec<Arc<BallotChurn>>,
+
+    // hash over every step taken, equal for two runs that went the same way
+    trace: DefaultHasher,
+
+    num_steps: u64,
+}
+
+struct Envelope<M> {
+    from: NodeId,
+    to: NodeId,
+    message: M,
+}
+
+// what the endpoints share with the simulation
+struct Wire<M> {
+    // current time of the simulation
+    now: Instant,
+
+    // messages sent by the node being stepped, picked up by the simulation after the step
+    sent: Vec<Envelope<M>>,
+}
+
+// endpoint of a simulated node
+// the simulation hands the messages to the node itself, nothing is ever received through the endpoint
+struct SimEndpoint<M> {
+    id: NodeId,
+    wire: Arc<Mutex<Wire<M>>>,
+}
+
+impl<M: Clone + Send> Transport<M> for SimEndpoint<M> {
+    fn get_id(&self) -> NodeId {
+        self.id
+    }
+
+    fn send(&self, to: NodeId, message: M) {
+        self.wire.lock().unwrap().sent.push(Envelope { from: self.id, to, message });
+    }
+
+    fn recv(&self) -> Option<M> {
+        None
+    }
+
+    fn recv_timeout(&self, _timeout: Duration) -> Option<M> {
+        None
+    }
+
+    fn try_recv(&self) -> Option<M> {
+        None
+    }
+
+    fn now(&self) -> Instant {
+        self.wire.lock().unwrap().now
+    }
+}
+
+
+
+
+impl<S: StateMachine> Simulation<S> {
+    // every node of the cluster, paused until activate
+    // the seed also seeds the random number generators of the nodes
+    pub fn new<F: Fn() -> S>(
+        seed: u64,
+        cluster: ClusterSize,
+        options: SystemOptions,
+        simulation_options: SimulationOptions,
+        new_state_machine: F,
+        workload: Workload<S::Command>,
+    ) -> Simulation<S> {
+        let start = Instant::now();
+        let wire = Arc::new(Mutex::new(Wire { now: start, sent: Vec::new() }));
+        let options = SystemOptions { seed: Some(seed), ..options };
+        let history = Arc::new(History::new());
+        let ballot_churn: Vec<Arc<BallotChurn>> = (0..cluster.leaders).map(|_| Arc::new(BallotChurn::default())).collect();
+
+        let nodes = cluster
+            .nodes()
+            .into_iter()
+            .filter(|node| *node != NodeId::Control)
+            .map(|node| {
+                let endpoint = Box::new(SimEndpoint { id: node, wire: wire.clone() });
+                let leader_ballot_churn = match node {
+                    NodeId::Leader(leader_id) => ballot_churn[leader_id as usize].clone(),
+                    _ => Arc::new(BallotChurn::default()),
+                };
+                let built = crate::build_node(
+                    node,
+                    endpoint,
+                    cluster,
+                    &options,
+                    &new_state_machine,
+                    workload.clone(),
+                    leader_ballot_churn,
+                    history.clone(),
+                );
+                (node, built)
+            })
+            .collect();
+
+        Simulation {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            wire,
+            start,
+            cluster,
+            injector: Injector::new(simulation_options.faults.clone(), seed),
+            options: simulation_options,
+            nodes,
+            in_flight: BTreeMap::new(),
+            num_sent: 0,
+            control_inbox: VecDeque::new(),
+            decided: HashMap::new(),
+            decisions_sent: Vec::new(),
+            history,
+            ballot_churn,
+            trace: DefaultHasher::new(),
+            num_steps: 0,
+        }
+    }
+
+    pub fn get_seed(&self) -> u64 {
+        self.seed
+    }
+
+    // time gone by in the simulation
+    pub fn elapsed(&self) -> Duration {
+        self.now() - self.start
+    }
+
+    pub fn get_num_steps(&self) -> u64 {
+        self.num_steps
+    }
+
+    pub fn get_decisions_sent(&self) -> &[DecisionSent] {
+        &self.decisions_sent
+    }
+
+    // as SystemHandles::history
+    pub fn get_history(&self) -> &Arc<History<S>> {
+        &self.history
+    }
+
+    // as SystemHandles::ballot_churn
+    pub fn get_ballot_churn(&self) -> &[Arc<BallotChurn>] {
+        &self.ballot_churn
+    }
+
+    // hash of the whole run so far, two runs from the same seed have the same one
+    pub fn trace_hash(&self) -> u64 {
+        self.trace.finish()
+    }
+
+    fn now(&self) -> Instant {
+        self.wire.lock().unwrap().now
+    }
+
+
+
+
+    // sending from the control node, the message goes over the network like any other
+    pub fn send(&mut self, to: NodeId, message: SystemMessage<S>) {
+        self.wire.lock().unwrap().sent.push(Envelope { from: NodeId::Control, to, message });
+        self.dispatch_sent();
+    }
+
+    // the run signals of SystemHandles::activate, the clients send num_msgs commands each
+    pub fn activate(&mut self, num_msgs: u32) {
+        let nodes: Vec<NodeId> = self.nodes.keys().cloned().collect();
+        for node in nodes {
+            self.send(node, Message::Control(ControlSignal::Run(num_msgs)));
+        }
+    }
+
+    // the exit signal to a single node, it never comes back
+    pub fn stop(&mut self, node: NodeId) {
+        self.send(node, Message::Control(ControlSignal::Exit));
+    }
+
+    // as SystemHandles::crash and SystemHandles::restart
+    pub fn crash(&mut self, node: NodeId) {
+        self.send(node, Message::Control(ControlSignal::Crash));
+    }
+
+    pub fn restart(&mut self, node: NodeId) {
+        self.send(node, Message::Control(ControlSignal::Restart));
+    }
+
+
+
+
+    // the next event, false once nothing is left to happen
+    pub fn step(&mut self) -> bool {
+        let next_delivery = self.in_flight.keys().next().map(|(due, _)| *due);
+        let next_wakeup = self
+            .nodes
+            .iter()
+            .filter_map(|(node, running)| running.next_wakeup().map(|wakeup| (wakeup, *node)))
+            .min();
+
+        // a message and a timer due at the same time, the message goes first
+        let (due, timer_of) = match (next_delivery, next_wakeup) {
+            (None, None) => return false,
+            (Some(delivery), Some((wakeup, node))) if wakeup < delivery => (wakeup, Some(node)),
+            (Some(delivery), _) => (delivery, None),
+            (None, Some((wakeup, node))) => (wakeup, Some(node)),
+        };
+        {
+            let mut wire = self.wire.lock().unwrap();
+            wire.now = wire.now.max(due);
+        }
+        self.num_steps += 1;
+        (self.now() - self.start).as_nanos().hash(&mut self.trace);
+
+        match timer_of {
+            Some(node) => {
+                node.hash(&mut self.trace);
+                self.nodes.get_mut(&node).unwrap().handle_timers();
+            }
+            None => {
+                let (_, envelope) = self.in_flight.pop_first().unwrap();
+                (envelope.from, envelope.to).hash(&mut self.trace);
+                mem::discriminant(&envelope.message).hash(&mut self.trace);
+                self.deliver(envelope.to, envelope.message);
+            }
+        }
+
+        self.dispatch_sent();
+        true
+    }
+
+    fn deliver(&mut self, to: NodeId, message: SystemMessage<S>) {
+        if to == NodeId::Control {
+            self.control_inbox.push_back(message);
+            return;
+        }
+        // a node that has exited doesn't get anything anymore
+        if let Some(node) = self.nodes.get_mut(&to) {
+            node.handle_message(message);
+            if node.has_exited() {
+                self.nodes.remove(&to);
+            } else {
+                node.handle_timers();
+            }
+        }
+    }
+
+    // putting the messages sent during the last step on their way, each with a random latency
+    // the copies of a message left by the faults all go out with its latency, plus their own delay
+    fn dispatch_sent(&mut self) {
+        let (now, sent) = {
+            let mut wire = self.wire.lock().unwrap();
+            (wire.now, mem::take(&mut wire.sent))
+        };
+        let elapsed = now - self.start;
+        for envelope in sent {
+            if let Message::Decision(decision) = &envelope.message {
+                let (command, slot) = decision.clone().get_details();
+                self.decisions_sent.push(DecisionSent { at: elapsed, from: envelope.from, to: envelope.to, slot });
+                self.check_decision((command, slot));
+            }
+            let latency = self.rng.gen_range(self.options.min_latency..=self.options.max_latency);
+            let Envelope { from, to, message } = envelope;
+            for (delay, message) in self.injector.inject(from, to, message, elapsed) {
+                self.in_flight.insert((now + latency + delay, self.num_sent), Envelope { from, to, message });
+                self.num_sent += 1;
+            }
+        }
+    }
+
+    // no two leaders may ever decide different commands for the same slot
+    fn check_decision(&mut self, (command, slot): (Command<S::Command>, u32)) {
+        match self.decided.get(&slot) {
+            Some(first) if *first != command => panic!(
+                "Seed {}: slot {} decided as {:?} and as {:?} at {:?}",
+                self.seed,
+                slot,
+                first,
+                command,
+                self.elapsed()
+            ),
+            Some(_) => {}
+            None => {
+                self.decided.insert(slot, command);
+            }
+        }
+    }
+
+
+
+
+    // stepping until the simulation has gone on for the duration, or until nothing is left to happen
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.now() + duration;
+        while self.next_event().is_some_and(|due| due <= until) {
+            self.step();
+        }
+        let mut wire = self.wire.lock().unwrap();
+        wire.now = wire.now.max(until);
+    }
+
+    fn next_event(&self) -> Option<Instant> {
+        let next_delivery = self.in_flight.keys().next().map(|(due, _)| *due);
+        let next_wakeup = self.nodes.values().filter_map(|node| node.next_wakeup()).min();
+        match (next_delivery, next_wakeup) {
+            (Some(delivery), Some(wakeup)) => Some(delivery.min(wakeup)),
+            (delivery, wakeup) => delivery.or(wakeup),
+        }
+    }
+
+    // stepping until every client has reported being done, as SystemHandles::wait_for_clients
+    // the timeout is in simulated time
+    pub fn wait_for_clients(&mut self, timeout: Duration) -> Result<(), OperationError> {
+        let deadline = self.now() + timeout;
+        let mut clients_done = 0u32;
+        let mut timed_out = Vec::new();
+        while clients_done < self.cluster.clients {
+            match self.control_inbox.pop_front() {
+                Some(Message::ClientDone(done)) => {
+                    clients_done += 1;
+                    timed_out.extend(done.get_timed_out().into_iter().map(|command_id| (done.get_client_id(), command_id)));
+                }
+                // nothing else is meant for the control node
+                Some(_) => {}
+                None => {
+                    if self.next_event().is_none_or(|due| due > deadline) {
+                        return Err(OperationError::Timeout { clients_done, clients: self.cluster.clients });
+                    }
+                    self.step();
+                }
+            }
+        }
+
+        if timed_out.is_empty() {
+            Ok(())
+        } else {
+            Err(OperationError::CommandsTimedOut(timed_out))
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use crate::codec::{Encode, Decode, Cursor, DecodeError};
+use crate::utils::Payload;
+
+
+// the application replicated by the replicas
+// every replica applies the same decided commands in slot order, so every replica ends up in the same state
+pub trait StateMachine: Send + 'static {
+    // command submitted by the clients
+    type Command: Payload;
+
+    // result of applying a command, sent back to the client
+    type Result: Debug + Clone + Send + 'static;
+
+    // full copy of the application state
+    type Snapshot: Debug + Clone + Send + 'static;
+
+    // apply a decided command, must be deterministic
+    fn apply(&mut self, command: &Self::Command) -> Self::Result;
+
+    // copy of the current state
+    fn snapshot(&self) -> Self::Snapshot;
+
+    // replace the current state with the snapshot
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
+
+
+
+
+// the single integer from PMMC's examples
+#[derive(Debug, Clone, Default)]
+pub struct Counter {
+    state: i32,
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub enum CounterCommand {
+    Add(i32),
+    Subtract(i32),
+    Multiply(i32),
+}
+
+// written as add n, sub n or mul n
+impl FromStr for CounterCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CounterCommand, String> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (op, operand) = match words.as_slice() {
+            [op, operand] => (*op, operand.parse::<i32>().map_err(|err| format!("Invalid operand in {:?}: {}", s, err))?),
+            _ => return Err(format!("Invalid command {:?}, expected add, sub or mul and a number", s)),
+        };
+        match op {
+            "add" => Ok(CounterCommand::Add(operand)),
+            "sub" => Ok(CounterCommand::Subtract(operand)),
+            "mul" => Ok(CounterCommand::Multiply(operand)),
+            _ => Err(format!("Invalid command {:?}, expected add, sub or mul and a number", s)),
+        }
+    }
+}
+
+impl StateMachine for Counter {
+    type Command = CounterCommand;
+    type Result = i32;
+    type Snapshot = i32;
+
+    // state and result are the same for the counter
+    // overflow wraps around, a command must not bring down the replicas
+    fn apply(&mut self, command: &CounterCommand) -> i32 {
+        match command {
+            CounterCommand::Add(x) => { self.state = self.state.wrapping_add(*x); }
+            CounterCommand::Subtract(y) => { self.state = self.state.wrapping_sub(*y); }
+            CounterCommand::Multiply(z) => { self.state = self.state.wrapping_mul(*z); }
+        }
+        self.state
+    }
+
+    fn snapshot(&self) -> i32 {
+        self.state
+    }
+
+    fn restore(&mut self, snapshot: i32) {
+        self.state = snapshot;
+    }
+}
+
+// the commands sent by the demo clients, num is the command ID
+pub fn counter_workload(num: u32) -> CounterCommand {
+    match num % 4 {
+        0 => CounterCommand::Add(1i32),
+        1 => CounterCommand::Subtract(1i32),
+        2 => CounterCommand::Multiply(2i32),
+        _ => CounterCommand::Add(1i32),
+    }
+}
+
+impl Encode for CounterCommand {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            CounterCommand::Add(x) => { buf.push(0); x.encode(buf); }
+            CounterCommand::Subtract(y) => { buf.push(1); y.encode(buf); }
+            CounterCommand::Multiply(z) => { buf.push(2); z.encode(buf); }
+        }
+    }
+}
+
+impl Decode for CounterCommand {
+    fn decode(cursor: &mut Cursor) -> Result<CounterCommand, DecodeError> {
+        match cursor.get_u8()? {
+            0 => Ok(CounterCommand::Add(i32::decode(cursor)?)),
+            1 => Ok(CounterCommand::Subtract(i32::decode(cursor)?)),
+            2 => Ok(CounterCommand::Multiply(i32::decode(cursor)?)),
+            tag => cursor.invalid_tag("CounterCommand", tag),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::codec::{Encode, Decode, Cursor, DecodeError, WIRE_VERSION};
+use crate::utils::{Ballot, Pvalue, Payload};
+
+
+// record tags in the write-ahead log
+const PROMISE_RECORD: u8 = 1;
+const ACCEPT_RECORD: u8 = 2;
+
+// length and checksum in front of every record
+const RECORD_HEADER_LEN: usize = 8;
+
+// the version of the encoding at the start of the log, before the first record
+const FILE_HEADER_LEN: usize = 1;
+
+
+
+
+// the durable part of the acceptor state
+// this is what an acceptor gets back after a restart
+#[derive(Debug, Clone)]
+pub struct AcceptorState<C> {
+    pub ballot_num: Option<Ballot>,
+    pub accepted: BTreeMap<u32, Pvalue<C>>,
+}
+
+impl<C> Default for AcceptorState<C> {
+    fn default() -> AcceptorState<C> {
+        AcceptorState { ballot_num: None, accepted: BTreeMap::new() }
+    }
+}
+
+
+
+
+// storage backend of an acceptor
+// both records have to be durable once the call returns, P1b/P2b are only sent after that
+pub trait AcceptorStorage<C>: Send {
+    // the acceptor has promised not to accept anything below this ballot
+    fn record_promise(&mut self, ballot: &Ballot) -> io::Result<()>;
+
+    // the acceptor has accepted the pvalue
+    fn record_accept(&mut self, pvalue: &Pvalue<C>) -> io::Result<()>;
+
+    // rebuild the state from what has been recorded so far
+    fn load(&mut self) -> io::Result<AcceptorState<C>>;
+}
+
+
+
+
+// keeps nothing, a restarted acceptor starts from scratch
+// the default, as in PMMC without crash failures
+#[derive(Default)]
+pub struct MemoryStorage;
+
+impl<C> AcceptorStorage<C> for MemoryStorage {
+    fn record_promise(&mut self, _ballot: &Ballot) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn record_accept(&mut self, _pvalue: &Pvalue<C>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<AcceptorState<C>> {
+        Ok(AcceptorState::default())
+    }
+}
+
+
+
+
+// append-only log file, every record is fsynced before the call returns
+// the file starts with the WIRE_VERSION it was written with, a log of any other version isn't loaded
+// a record is [length: u32][checksum: u32][tag: u8][payload], integers in little endian
+pub struct FileWal {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileWal {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileWal> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut wal = FileWal { path, file };
+        // a new log, or one that crashed before its header was written
+        if wal.file.metadata()?.len() == 0 {
+            wal.file.write_all(&[WIRE_VERSION])?;
+            wal.file.sync_data()?;
+        }
+        Ok(wal)
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    fn append(&mut self, tag: u8, payload: Vec<u8>) -> io::Result<()> {
+        let mut body = Vec::with_capacity(payload.len() + 1);
+        body.push(tag);
+        body.extend(payload);
+
+        let mut record = Vec::with_capacity(body.len() + RECORD_HEADER_LEN);
+        (body.len() as u32).encode(&mut record);
+        checksum(&body).encode(&mut record);
+        record.extend(body);
+
+        self.file.write_all(&record)?;
+        self.file.sync_data()
+    }
+}
+
+impl<C: Payload> AcceptorStorage<C> for FileWal {
+    fn record_promise(&mut self, ballot: &Ballot) -> io::Result<()> {
+        let mut payload = Vec::new();
+        ballot.encode(&mut payload);
+        self.append(PROMISE_RECORD, payload)
+    }
+
+    fn record_accept(&mut self, pvalue: &Pvalue<C>) -> io::Result<()> {
+        let mut payload = Vec::new();
+        pvalue.encode(&mut payload);
+        self.append(ACCEPT_RECORD, payload)
+    }
+
+    fn load(&mut self) -> io::Result<AcceptorState<C>> {
+        let mut bytes = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut bytes)?;
+
+        let mut state = AcceptorState::default();
+        match bytes.first() {
+            Some(version) if *version != WIRE_VERSION => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, DecodeError::UnsupportedVersion(*version)));
+            }
+            Some(_) => {}
+            None => return Ok(state),
+        }
+        let mut offset = FILE_HEADER_LEN;
+
+        // replay every complete record
+        // a torn record can only be the last one written before a crash, it ends the replay
+        while let Some((tag, body, next)) = next_record(&bytes, offset) {
+            let mut cursor = Cursor::new(body);
+            match tag {
+                PROMISE_RECORD => {
+                    let ballot = Ballot::decode(&mut cursor).map_err(|_| corrupt())?;
+                    if state.ballot_num.as_ref().is_none_or(|b| ballot > *b) {
+                        state.ballot_num = Some(ballot);
+                    }
+                }
+
+                ACCEPT_RECORD => {
+                    let pvalue = Pvalue::<C>::decode(&mut cursor).map_err(|_| corrupt())?;
+                    // accepting a pvalue adopts its ballot as well
+                    if state.ballot_num.as_ref().is_none_or(|b| pvalue.get_ballot_num() > *b) {
+                        state.ballot_num = Some(pvalue.get_ballot_num());
+                    }
+                    let newer = match state.accepted.get(&pvalue.get_slot()) {
+                        Some(current) => pvalue.get_ballot_num() > current.get_ballot_num(),
+                        None => true,
+                    };
+                    if newer {
+                        state.accepted.insert(pvalue.get_slot(), pvalue);
+                    }
+                }
+
+                _ => return Err(corrupt()),
+            }
+            offset = next;
+        }
+
+        // a bad record with more bytes after it isn't torn, the records after it are promises and accepts that can't be dropped
+        // the log is left as it is
+        if record_end(&bytes, offset).is_some_and(|end| end < bytes.len()) {
+            return Err(corrupt());
+        }
+
+        // dropping the torn tail so that new records follow the last complete one
+        if offset < bytes.len() {
+            self.file.set_len(offset as u64)?;
+            self.file.sync_data()?;
+        }
+
+        Ok(state)
+    }
+}
+
+
+
+
+// where the record at the offset ends according to its length, whether it is complete or not
+// None if not even the length has been written
+fn record_end(bytes: &[u8], offset: usize) -> Option<usize> {
+    let len = Cursor::new(bytes.get(offset..)?).get_u32().ok()? as usize;
+    (offset + RECORD_HEADER_LEN).checked_add(len)
+}
+
+// finding the record at the offset
+// returns the tag, the payload and the offset of the next record
+fn next_record(bytes: &[u8], offset: usize) -> Option<(u8, &[u8], usize)> {
+    let mut cursor = Cursor::new(bytes.get(offset..)?);
+    let len = cursor.get_u32().ok()? as usize;
+    let sum = cursor.get_u32().ok()?;
+    let start = offset + cursor.get_offset();
+    let end = start.checked_add(len)?;
+    if len == 0 || end > bytes.len() || checksum(&bytes[start..end]) != sum {
+        return None;
+    }
+    Some((bytes[start], &bytes[start + 1..end], end))
+}
+
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt acceptor log record")
+}
+
+
+// FNV-1a, enough to catch a torn write
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash = 0x811c9dc5u32;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
@@ -0,0 +1,259 @@
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender, TrySendError};
+use log::{error, warn};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::codec::{self, Encode, Decode};
+use crate::transport::{NodeId, Transport};
+
+
+// frames bigger than this are taken for garbage, the connection they came on is closed
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+// frames waiting to be written to a single peer, beyond that new messages to the peer are dropped
+pub const OUTBOX_CAPACITY: usize = 1024;
+
+// time allowed for establishing a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// delays between the attempts to reach a peer, doubling from the first to the last
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(10);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+
+
+
+// transport over TCP, the nodes can live in different processes or on different hosts
+// every message goes in its own frame: the length of the payload as a little endian u32, then the message as written by codec::to_bytes
+// the connections are one way, every node connects to the peers it sends to and accepts the ones that send to it
+pub struct TcpTransport<M> {
+    // address of this endpoint
+    id: NodeId,
+
+    // receiving handle of the inbox of this node, fed by the connections accepted
+    inbox: Receiver<M>,
+
+    // sending handle of the inbox, for the messages this node sends to itself
+    inbox_sender: Sender<M>,
+
+    // encoded frames waiting to be written, per peer
+    // each one drained by a writer thread owning the connection to the peer
+    outboxes: HashMap<NodeId, Sender<Vec<u8>>>,
+
+    // address this node listens on
+    listen_addr: SocketAddr,
+
+    // set once the endpoint is dropped, stops the listener and the writers still trying to reach a peer
+    closed: Arc<AtomicBool>,
+
+    _message: PhantomData<fn(M)>,
+}
+
+impl<M: Encode + Decode + Send + 'static> TcpTransport<M> {
+    // listen on the address of id and get ready to send to every other node in addrs
+    // the peers don't have to be up yet, they are connected to on the first message
+    pub fn bind(id: NodeId, addrs: &HashMap<NodeId, SocketAddr>) -> io::Result<TcpTransport<M>> {
+        let listen_addr = *addrs.get(&id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("No address for node {}", id))
+        })?;
+        TcpTransport::with_listener(id, TcpListener::bind(listen_addr)?, addrs)
+    }
+
+    // the same on a listener bound already, e.g. to port 0 before the addresses of the peers were known
+    // the address of id in addrs doesn't matter, the peers have to be given the one of the listener
+    pub fn with_listener(id: NodeId, listener: TcpListener, addrs: &HashMap<NodeId, SocketAddr>) -> io::Result<TcpTransport<M>> {
+        let listen_addr = listener.local_addr()?;
+
+        let (inbox_sender, inbox) = unbounded();
+        let closed = Arc::new(AtomicBool::new(false));
+
+        // accepting the connections of the peers
+        let listener_inbox = inbox_sender.clone();
+        let listener_closed = closed.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if listener_closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let inbox = listener_inbox.clone();
+                    thread::spawn(move || read_frames(stream, inbox));
+                }
+            }
+        });
+
+        // one writer per peer
+        let mut outboxes = HashMap::new();
+        for (peer, addr) in addrs {
+            if *peer == id {
+                continue;
+            }
+            let (sender, receiver) = bounded(OUTBOX_CAPACITY);
+            let addr = *addr;
+            let writer_closed = closed.clone();
+            thread::spawn(move || write_frames(addr, receiver, writer_closed));
+            outboxes.insert(*peer, sender);
+        }
+
+        Ok(TcpTransport {
+            id,
+            inbox,
+            inbox_sender,
+            outboxes,
+            listen_addr,
+            closed,
+            _message: PhantomData,
+        })
+    }
+
+    pub fn get_listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+}
+
+impl<M> Drop for TcpTransport<M> {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // the listener only looks at the flag once a connection comes in
+        let _ = TcpStream::connect_timeout(&self.listen_addr, CONNECT_TIMEOUT);
+        // the writers finish the frames already queued, they see the outboxes closed once the senders are gone
+    }
+}
+
+impl<M: Encode + Decode + Clone + Send + 'static> Transport<M> for TcpTransport<M> {
+    fn get_id(&self) -> NodeId {
+        self.id
+    }
+
+    fn send(&self, to: NodeId, message: M) {
+        if to == self.id {
+            let _ = self.inbox_sender.send(message);
+            return;
+        }
+        if let Some(outbox) = self.outboxes.get(&to) {
+            let payload = codec::to_bytes(&message);
+            let mut frame = Vec::with_capacity(4 + payload.len());
+            (payload.len() as u32).encode(&mut frame);
+            frame.extend_from_slice(&payload);
+            // a peer that can't keep up loses messages, like on any network
+            if let Err(TrySendError::Full(_)) = outbox.try_send(frame) {
+                warn!("Outbox of {} to {} is full, message dropped", self.id, to);
+            }
+        }
+    }
+
+    fn recv(&self) -> Option<M> {
+        self.inbox.recv().ok()
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Option<M> {
+        self.inbox.recv_timeout(timeout).ok()
+    }
+
+    fn try_recv(&self) -> Option<M> {
+        self.inbox.try_recv().ok()
+    }
+}
+
+
+
+
+// addresses of the nodes written as node=addr separated by commas
+// e.g. replica:0=10.0.0.1:7000,leader:0=10.0.0.1:7001
+pub fn parse_addrs(spec: &str) -> Result<HashMap<NodeId, SocketAddr>, String> {
+    let mut addrs = HashMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (node, addr) = entry.split_once('=').ok_or_else(|| format!("Expected node=addr, got {:?}", entry))?;
+        let node: NodeId = node.trim().parse()?;
+        let addr: SocketAddr = addr.trim().parse().map_err(|err| format!("Invalid address of {}: {}", node, err))?;
+        if addrs.insert(node, addr).is_some() {
+            return Err(format!("{} is given twice", node));
+        }
+    }
+    Ok(addrs)
+}
+
+// written the way parse_addrs reads it, in node order
+pub fn format_addrs(addrs: &HashMap<NodeId, SocketAddr>) -> String {
+    let mut nodes: Vec<&NodeId> = addrs.keys().collect();
+    nodes.sort();
+    nodes.iter().map(|node| format!("{}={}", node, addrs[node])).collect::<Vec<String>>().join(",")
+}
+
+
+
+
+// reading the frames of one incoming connection into the inbox until the peer disconnects
+// a frame that doesn't decode is skipped, the length prefix still says where the next one starts
+fn read_frames<M: Decode>(mut stream: TcpStream, inbox: Sender<M>) {
+    let mut len_bytes = [0u8; 4];
+    loop {
+        if stream.read_exact(&mut len_bytes).is_err() {
+            return;
+        }
+        let len = u32::from_le_bytes(len_bytes) as usize;
+        if len > MAX_FRAME_LEN {
+            error!("Frame of {} bytes from {:?} is too big, closing the connection", len, stream.peer_addr());
+            return;
+        }
+
+        let mut payload = vec![0u8; len];
+        if stream.read_exact(&mut payload).is_err() {
+            return;
+        }
+
+        match codec::from_bytes::<M>(&payload) {
+            Ok(message) => {
+                // the endpoint is gone, nobody is going to read the messages anymore
+                if inbox.send(message).is_err() {
+                    return;
+                }
+            }
+            Err(err) => warn!("Malformed frame from {:?} skipped: {}", stream.peer_addr(), err),
+        }
+    }
+}
+
+
+
+
+// writing the frames queued for one peer, connecting on the first frame and reconnecting whenever the connection breaks
+// a frame is retried on the new connection until it is written whole
+fn write_frames(addr: SocketAddr, outbox: Receiver<Vec<u8>>, closed: Arc<AtomicBool>) {
+    let mut stream: Option<TcpStream> = None;
+    let mut delay = MIN_RECONNECT_DELAY;
+
+    // ends once the endpoint is dropped and every queued frame is out
+    while let Ok(frame) = outbox.recv() {
+        loop {
+            if stream.is_none() {
+                stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).ok();
+                if let Some(connected) = &stream {
+                    let _ = connected.set_nodelay(true);
+                }
+            }
+
+            if let Some(connected) = &mut stream {
+                if connected.write_all(&frame).is_ok() {
+                    delay = MIN_RECONNECT_DELAY;
+                    break;
+                }
+                // the peer may have restarted, the frame goes again on a new connection
+                stream = None;
+            }
+
+            // nobody is left to care about the frames a dead peer never got
+            if closed.load(Ordering::SeqCst) {
+                return;
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+
+// address of a node
+// the nodes of every role are numbered from 0
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub enum NodeId {
+    // whoever drives the system, sends the control signals and the reconfig commands
+    Control,
+    Client(u32),
+    Replica(u32),
+    Leader(u32),
+    Acceptor(u32),
+}
+
+// IDs of the first count nodes of a role, e.g. nodes(NodeId::Leader, 3)
+pub fn nodes(role: fn(u32) -> NodeId, count: u32) -> Vec<NodeId> {
+    (0..count).map(role).collect()
+}
+
+// written as role:id, e.g. leader:2, the control node is just control
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeId::Control => write!(f, "control"),
+            NodeId::Client(id) => write!(f, "client:{}", id),
+            NodeId::Replica(id) => write!(f, "replica:{}", id),
+            NodeId::Leader(id) => write!(f, "leader:{}", id),
+            NodeId::Acceptor(id) => write!(f, "acceptor:{}", id),
+        }
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NodeId, String> {
+        if s == "control" {
+            return Ok(NodeId::Control);
+        }
+        let (role, id) = s.split_once(':').ok_or_else(|| format!("Invalid node ID {:?}", s))?;
+        let id: u32 = id.parse().map_err(|_| format!("Invalid node ID {:?}", s))?;
+        match role {
+            "client" => Ok(NodeId::Client(id)),
+            "replica" => Ok(NodeId::Replica(id)),
+            "leader" => Ok(NodeId::Leader(id)),
+            "acceptor" => Ok(NodeId::Acceptor(id)),
+            _ => Err(format!("Invalid node ID {:?}", s)),
+        }
+    }
+}
+
+
+
+
+// number of nodes of every role in a system
+// every node has to know it, the roles address their peers by ID
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ClusterSize {
+    pub clients: u32,
+    pub replicas: u32,
+    pub leaders: u32,
+    pub acceptors: u32,
+}
+
+impl ClusterSize {
+    // counting the nodes of every role among the given IDs, the control node doesn't count
+    // the IDs of every role have to go from 0 without gaps, the error is the first node missing
+    pub fn from_nodes(ids: &[NodeId]) -> Result<ClusterSize, NodeId> {
+        let mut size = ClusterSize { clients: 0, replicas: 0, leaders: 0, acceptors: 0 };
+        for id in ids {
+            match id {
+                NodeId::Control => {}
+                NodeId::Client(_) => size.clients += 1,
+                NodeId::Replica(_) => size.replicas += 1,
+                NodeId::Leader(_) => size.leaders += 1,
+                NodeId::Acceptor(_) => size.acceptors += 1,
+            }
+        }
+        for node in size.nodes() {
+            if node != NodeId::Control && !ids.contains(&node) {
+                return Err(node);
+            }
+        }
+        Ok(size)
+    }
+
+    // IDs of all the nodes, the control node included
+    pub fn nodes(&self) -> Vec<NodeId> {
+        let mut all_nodes = vec![NodeId::Control];
+        all_nodes.extend(nodes(NodeId::Client, self.clients));
+        all_nodes.extend(nodes(NodeId::Replica, self.replicas));
+        all_nodes.extend(nodes(NodeId::Leader, self.leaders));
+        all_nodes.extend(nodes(NodeId::Acceptor, self.acceptors));
+        all_nodes
+    }
+}
+
+
+
+
+// how the messages get from one node to another
+// one endpoint per node, the roles only talk through it
+// an endpoint can be shared between threads, e.g. one receiving while others send
+// like any network it may lose messages, a send to a node that doesn't exist or is gone is not an error
+pub trait Transport<M: Clone>: Send + Sync {
+    // address of this endpoint
+    fn get_id(&self) -> NodeId;
+
+    // send the message to a single node
+    fn send(&self, to: NodeId, message: M);
+
+    // send the message to every node in to
+    fn broadcast(&self, to: &[NodeId], message: M) {
+        for node in to {
+            self.send(*node, message.clone());
+        }
+    }
+
+    // blocking until a message for this node arrives
+    // None once no message can arrive anymore
+    fn recv(&self) -> Option<M>;
+
+    // blocking until a message for this node arrives or the timeout is over
+    fn recv_timeout(&self, timeout: Duration) -> Option<M>;
+
+    // a message that is already waiting, without blocking
+    fn try_recv(&self) -> Option<M>;
+
+    // the time as seen by the node, for its timers
+    // the simulator runs its nodes on a clock of its own
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -1,11 +1,67 @@
 use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+use crate::codec::{Encode, Decode};
+
+
+// what the consensus core needs from a command of the replicated state machine
+// acceptors log commands and leaders compare them, but nothing ever looks inside
+pub trait Payload: Debug + Clone + Eq + Hash + Send + Encode + Decode + 'static {}
+
+impl<T: Debug + Clone + Eq + Hash + Send + Encode + Decode + 'static> Payload for T {}
+
+
+
+
+// sent by the control node to change the operating state of a node
+#[derive(Debug, Clone)]
+pub enum ControlSignal {
+    Paused,
+    Run(u32),
+    Exit,
+    // sent to a single node, which loses its volatile state and drops every message until restarted
+    Crash,
+    // the node comes back running with its durable state, crashed or not
+    Restart,
+    // changes nothing, the node only looks at its timers again
+    // sent by a ClientHandle to its own dispatcher when a new command goes out
+    Wakeup,
+}
+
+
+
+
+// every message that goes from one node to another
+// C is the command, R the result of a command and P the snapshot of a replica
+#[derive(Debug, Clone)]
+pub enum Message<C, R, P> {
+    Control(ControlSignal),
+    Request(Request<C>),
+    Response(Response<R>),
+    Propose(Propose<C>),
+    Decision(Decision<C>),
+    P1a(P1a),
+    P1b(P1b<C>),
+    P2a(P2a<C>),
+    P2b(P2b),
+    Heartbeat(Heartbeat),
+    CatchUp(CatchUpMessage<C, P>),
+    // sent by a client to the control node once all its commands have been answered or given up on
+    ClientDone(ClientDone),
+}
+
+
+
+
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
-pub enum Operation {
+pub enum Operation<C> {
     Null,
-    Add(i32),
-    Subtract(i32),
-    Multiply(i32),
+    // command for the replicated state machine
+    Apply(C),
+    // new set of leader IDs, takes effect WINDOW slots after the slot it is decided in
+    Reconfig(Vec<u32>),
 }
 
 
@@ -14,35 +70,58 @@ pub enum Operation {
 
 // the structure of command that is sent by clients to the replicas
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
-pub struct Command {
+pub struct Command<C> {
     client_id: u32,
     command_id: u32,
-    operation: Operation,
+    // every command ID of the client below this one has been answered or given up on
+    // the replicas forget those commands once this command is executed
+    acknowledged: u32,
+    operation: Operation<C>,
 }
 
-impl Command {
+impl<C: Clone> Command<C> {
+    // for a command that acknowledges nothing
     pub fn create(
         client_id: u32,
         command_id: u32,
-        operation: Operation 
-    ) -> Command {
+        operation: Operation<C>
+    ) -> Command<C> {
         Command{
             client_id,
             command_id,
+            acknowledged: 0,
+            operation
+        }
+    }
+
+    pub fn create_with_acknowledged(
+        client_id: u32,
+        command_id: u32,
+        acknowledged: u32,
+        operation: Operation<C>
+    ) -> Command<C> {
+        Command{
+            client_id,
+            command_id,
+            acknowledged,
             operation
         }
     }
 
     pub fn get_command_id(&self) -> u32 {
-        self.command_id.clone()
+        self.command_id
     }
 
-    pub fn get_operation(&self) -> Operation {
+    pub fn get_acknowledged(&self) -> u32 {
+        self.acknowledged
+    }
+
+    pub fn get_operation(&self) -> Operation<C> {
         self.operation.clone()
     }
     
     pub fn get_client_id(&self) -> u32 {
-        self.client_id.clone()
+        self.client_id
     }
 
 }
@@ -70,6 +149,19 @@ impl Ballot {
         Ballot{ count: self.count + 1u32, leader_id }
     } 
 
+    // for rebuilding a ballot that has been stored
+    pub fn create_with_count(count: u32, leader_id: u32) -> Ballot {
+        Ballot{ count, leader_id }
+    }
+
+    pub fn get_count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn get_leader_id(&self) -> u32 {
+        self.leader_id
+    }
+
 }
 
 
@@ -109,30 +201,30 @@ impl PartialEq for Ballot {
 
 // the structure of pvalues included in the messages between leader and acceptors
 #[derive(Debug, Clone)]
-pub struct Pvalue {
+pub struct Pvalue<C> {
     ballot: Ballot,
     slot: u32,
-    command: Command,
+    command: Command<C>,
 }
 
-impl Pvalue {
+impl<C: Clone> Pvalue<C> {
     pub fn get_ballot_num(&self) -> Ballot {
         self.ballot.clone()
     }
 
     pub fn get_slot(&self) -> u32 {
-        self.slot.clone()
+        self.slot
     }
 
-    pub fn get_command(&self) -> Command {
+    pub fn get_command(&self) -> Command<C> {
         self.command.clone()
     }
 
     pub fn create(
         ballot: Ballot,
         slot: u32,
-        command: Command,
-    ) -> Pvalue {
+        command: Command<C>,
+    ) -> Pvalue<C> {
         Pvalue{ ballot, slot, command }
     }
 }
@@ -144,16 +236,16 @@ impl Pvalue {
 
 // sent by clients to replicas
 #[derive(Debug, Clone)]
-pub struct Request {
-    command: Command,
+pub struct Request<C> {
+    command: Command<C>,
 }
 
-impl Request {
-    pub fn create(command: Command) -> Request {
+impl<C: Clone> Request<C> {
+    pub fn create(command: Command<C>) -> Request<C> {
         Request{ command }
     }
 
-    pub fn get_command(&self) -> Command {
+    pub fn get_command(&self) -> Command<C> {
         self.command.clone()
     }
 
@@ -165,21 +257,22 @@ impl Request {
 
 
 // sent by replicas to clients
-pub struct Response {
+#[derive(Debug, Clone)]
+pub struct Response<R> {
     command_id: u32,
-    result: i32,
+    result: R,
 }
 
-impl Response{
-    pub fn create(command_id: u32, result: i32) -> Response {
+impl<R: Clone> Response<R> {
+    pub fn create(command_id: u32, result: R) -> Response<R> {
         Response{ command_id, result }
     }
 
     pub fn get_command_id(&self) -> u32 {
-        self.command_id.clone()
+        self.command_id
     }
 
-    pub fn get_result(&self) -> i32 {
+    pub fn get_result(&self) -> R {
         self.result.clone()
     }
 }
@@ -189,23 +282,55 @@ impl Response{
 
 
 
+// sent by a client to the control node once it is done with all its commands
+#[derive(Debug, Clone)]
+pub struct ClientDone {
+    client_id: u32,
+    // commands that got no response before the deadline, in the order they were given up on
+    timed_out: Vec<u32>,
+}
+
+impl ClientDone {
+    pub fn create(client_id: u32, timed_out: Vec<u32>) -> ClientDone {
+        ClientDone{ client_id, timed_out }
+    }
+
+    pub fn get_client_id(&self) -> u32 {
+        self.client_id
+    }
+
+    pub fn get_timed_out(&self) -> Vec<u32> {
+        self.timed_out.clone()
+    }
+}
+
+
+
+
+
+
 // sent by replicas to the leaders
 #[derive(Debug, Clone)]
-pub struct Propose {
+pub struct Propose<C> {
+    replica_id: u32,
     slot: u32,
-    command: Command,
+    command: Command<C>,
 }
 
-impl Propose{
-    pub fn create(slot: u32, command: Command) -> Propose {
-        Propose{ slot, command }
+impl<C: Clone> Propose<C> {
+    pub fn create(replica_id: u32, slot: u32, command: Command<C>) -> Propose<C> {
+        Propose{ replica_id, slot, command }
+    }
+
+    pub fn get_replica_id(&self) -> u32 {
+        self.replica_id
     }
 
     pub fn get_slot(&self) -> u32 {
-        self.slot.clone()
+        self.slot
     }
 
-    pub fn get_command(&self) -> Command {
+    pub fn get_command(&self) -> Command<C> {
         self.command.clone()
     }
 }
@@ -216,17 +341,25 @@ impl Propose{
 
 // sent by the commander in leaders to the replicas
 #[derive(Debug, Clone, Hash)]
-pub struct Decision {
+pub struct Decision<C> {
     slot: u32,
-    command: Command,
+    command: Command<C>,
 }
 
-impl Decision {
-    pub fn get_details(self) -> (Command, u32) {
+impl<C: Clone> Decision<C> {
+    pub fn get_slot(&self) -> u32 {
+        self.slot
+    }
+
+    pub fn get_details(self) -> (Command<C>, u32) {
         (self.command, self.slot)
     }
 
-    pub fn create(slot: u32, command: Command) -> Decision {
+    pub fn get_command(&self) -> Command<C> {
+        self.command.clone()
+    }
+
+    pub fn create(slot: u32, command: Command<C>) -> Decision<C> {
         Decision{ slot, command }
     }
 }
@@ -238,17 +371,17 @@ impl Decision {
 
 // sent by scout to its leader
 #[derive(Debug, Clone)]
-pub struct Adopted {
+pub struct Adopted<C> {
     ballot: Ballot,
-    pvalues: Vec<Pvalue>,
+    pvalues: Vec<Pvalue<C>>,
 }
 
-impl Adopted {
-    pub fn create(ballot: Ballot, pvalues: Vec<Pvalue>) -> Adopted {
+impl<C: Clone> Adopted<C> {
+    pub fn create(ballot: Ballot, pvalues: Vec<Pvalue<C>>) -> Adopted<C> {
         Adopted{ ballot, pvalues }
     }
 
-    pub fn get_pvalues(&self) -> Vec<Pvalue> {
+    pub fn get_pvalues(&self) -> Vec<Pvalue<C>> {
         self.pvalues.clone()
     }
 
@@ -283,9 +416,110 @@ impl Preempted {
 
 
 
-// the message sent by scout thread to the leader
-pub enum ScoutMessage {
-    Adopted(Adopted),
+// sent periodically by every leader to all leaders
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    leader_id: u32,
+}
+
+impl Heartbeat {
+    pub fn create(leader_id: u32) -> Heartbeat {
+        Heartbeat{ leader_id }
+    }
+
+    pub fn get_leader_id(&self) -> u32 {
+        self.leader_id
+    }
+}
+
+
+
+
+
+
+// sent by a replica to its peers when it is stuck on a slot it never got the decision for
+#[derive(Debug, Clone)]
+pub struct CatchUpRequest {
+    replica_id: u32,
+    // the replica is missing the decisions from this slot onward
+    slot: u32,
+}
+
+impl CatchUpRequest {
+    pub fn create(replica_id: u32, slot: u32) -> CatchUpRequest {
+        CatchUpRequest{ replica_id, slot }
+    }
+
+    pub fn get_replica_id(&self) -> u32 {
+        self.replica_id
+    }
+
+    pub fn get_slot(&self) -> u32 {
+        self.slot
+    }
+}
+
+
+
+
+// sent by a replica in reply to a CatchUpRequest
+// P is the replica snapshot, included when the decisions asked for have been compacted away
+#[derive(Debug, Clone)]
+pub struct CatchUpReply<C, P> {
+    snapshot: Option<P>,
+    decisions: Vec<(u32, Command<C>)>,
+}
+
+impl<C: Clone, P: Clone> CatchUpReply<C, P> {
+    pub fn create(snapshot: Option<P>, decisions: Vec<(u32, Command<C>)>) -> CatchUpReply<C, P> {
+        CatchUpReply{ snapshot, decisions }
+    }
+
+    pub fn get_details(self) -> (Option<P>, Vec<(u32, Command<C>)>) {
+        (self.snapshot, self.decisions)
+    }
+
+    // borrowing the parts, a reply can carry a whole snapshot and shouldn't be copied to be encoded
+    pub fn get_snapshot(&self) -> Option<&P> {
+        self.snapshot.as_ref()
+    }
+
+    pub fn get_decisions(&self) -> &[(u32, Command<C>)] {
+        &self.decisions
+    }
+}
+
+
+
+
+
+
+// the message exchanged between the replicas for catching up
+#[derive(Debug, Clone)]
+pub enum CatchUpMessage<C, P> {
+    Request(CatchUpRequest),
+    Reply(CatchUpReply<C, P>),
+}
+
+
+
+
+
+
+// outcome of a scout, handled by its leader
+pub enum ScoutMessage<C> {
+    Adopted(Adopted<C>),
+    Preempted(Preempted),
+}
+
+
+
+
+
+
+// outcome of a commander, handled by its leader
+pub enum CommanderMessage<C> {
+    Decided(Decision<C>),
     Preempted(Preempted),
 }
 
@@ -300,6 +534,9 @@ pub struct P1a {
     leader_id: u32,
     ballot: Ballot,
     scout_id: u32,
+    // the leader knows all slots up to this one are decided
+    // only the pvalues of the slots above it are sent back
+    slot_watermark: u32,
 }
 
 impl P1a {
@@ -308,19 +545,24 @@ impl P1a {
     }
 
     pub fn get_leader_id(&self) -> u32 {
-        self.leader_id.clone()
+        self.leader_id
     }
 
     pub fn get_scout_id(&self) -> u32 {
-        self.scout_id.clone()
+        self.scout_id
+    }
+
+    pub fn get_slot_watermark(&self) -> u32 {
+        self.slot_watermark
     }
 
     pub fn create(
         leader_id: u32,
         ballot: Ballot,
         scout_id: u32,       
+        slot_watermark: u32,
     ) -> P1a {
-        P1a{ leader_id, ballot, scout_id }
+        P1a{ leader_id, ballot, scout_id, slot_watermark }
     }
 
 }
@@ -331,21 +573,21 @@ impl P1a {
 
 // sent by acceptor to the scout
 #[derive(Debug, Clone)]
-pub struct P1b {
+pub struct P1b<C> {
     acceptor_id: u32,
     ballot: Ballot,
-    // set of pvalues accepted by the acceptor
-    accepted: Vec<Pvalue>,
+    // pvalues accepted by the acceptor, the highest ballot for each slot above the watermark
+    accepted: Vec<Pvalue<C>>,
     scout_id: u32,
 }
 
-impl P1b {
+impl<C: Clone> P1b<C> {
     pub fn create(
         acceptor_id: u32, 
         ballot: Ballot, 
-        accepted: Vec<Pvalue>, 
+        accepted: Vec<Pvalue<C>>, 
         scout_id: u32,
-    ) ->P1b {
+    ) ->P1b<C> {
         P1b{ acceptor_id, ballot, accepted, scout_id }
     }
 
@@ -354,15 +596,15 @@ impl P1b {
     }
 
     pub fn get_scout_id(&self) -> usize {
-        self.scout_id.clone() as usize
+        self.scout_id as usize
     }
     
-    pub fn get_pvalues(&self) -> Vec<Pvalue> {
+    pub fn get_pvalues(&self) -> Vec<Pvalue<C>> {
         self.accepted.clone()
     }
 
     pub fn get_acceptor_id(&self) -> u32 {
-        self.acceptor_id.clone()
+        self.acceptor_id
     }
 }
 
@@ -372,15 +614,15 @@ impl P1b {
 
 // sent by commander to the acceptor
 #[derive(Debug, Clone)]
-pub struct P2a {
+pub struct P2a<C> {
     leader_id: u32,
-    pvalue: Pvalue,
+    pvalue: Pvalue<C>,
     commander_id: u32,
 }
 
 
-impl P2a {
-    pub fn get_pvalue(&self) -> Pvalue {
+impl<C: Clone> P2a<C> {
+    pub fn get_pvalue(&self) -> Pvalue<C> {
         self.pvalue.clone()
     }
 
@@ -389,18 +631,18 @@ impl P2a {
     }
 
     pub fn get_leader_id(&self) -> u32 {
-        self.leader_id.clone()
+        self.leader_id
     }
 
     pub fn get_commander_id(&self) -> u32 {
-        self.commander_id.clone()
+        self.commander_id
     }
 
     pub fn create(
         leader_id: u32,
-        pvalue: Pvalue,
+        pvalue: Pvalue<C>,
         commander_id: u32,
-    ) -> P2a {
+    ) -> P2a<C> {
         P2a{ leader_id, pvalue, commander_id }
     }
 }
@@ -433,35 +675,11 @@ impl P2b {
     }
 
     pub fn get_acceptor_id(&self) -> u32 {
-        self.acceptor_id.clone()
+        self.acceptor_id
     }
 
     pub fn get_commander_id(&self) -> usize {
-        self.commander_id.clone() as usize
+        self.commander_id as usize
     }
 
 }
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
@@ -0,0 +1,220 @@
+use paxos::{ClusterSize, Counter, NodeId, SystemHandles, SystemMessage, SystemOptions, SystemTransport, TcpTransport, counter_workload};
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+// the node processes are this test binary again, running only tcp_node_process
+// they learn their ID from this, report the port they got and read the addresses of everyone from stdin
+const NODE_VAR: &str = "PAXOS_TEST_NODE";
+
+const CLUSTER: ClusterSize = ClusterSize { clients: 2, replicas: 3, leaders: 3, acceptors: 3 };
+const NUM_MSGS: u32 = 10;
+
+
+// a port nobody listens on right now, somebody else may take it before it is listened on
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+// the addresses the first count nodes of the process say they listen on, read from its stdout
+// None if the process exits before, the rest of its output is read and thrown away in the background
+fn listening_addrs(child: &mut Child, count: usize) -> Option<Vec<SocketAddr>> {
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut addrs = Vec::new();
+    while addrs.len() < count {
+        let line = lines.next()?.ok()?;
+        if let Some((_, addr)) = line.split_once(" listening on ") {
+            addrs.push(addr.trim().parse().unwrap());
+        }
+    }
+    thread::spawn(move || lines.for_each(drop));
+    Some(addrs)
+}
+
+
+// killing the node processes if the test fails before they exit
+struct NodeProcess {
+    node: NodeId,
+    child: Child,
+}
+
+impl NodeProcess {
+    // waiting for the process to exit, false if it doesn't within the timeout
+    fn wait(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                assert!(status.success(), "Node {} exited with {}", self.node, status);
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+}
+
+impl Drop for NodeProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+
+
+
+// a single node of the cluster, only does something when started by tcp_cluster
+#[test]
+fn tcp_node_process() {
+    let node: NodeId = match env::var(NODE_VAR) {
+        Ok(node) => node.parse().unwrap(),
+        Err(_) => return,
+    };
+    // the port is the OS's pick, it can't be taken in the meantime
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    println!("{} listening on {}", node, listener.local_addr().unwrap());
+    let mut peers = String::new();
+    io::stdin().read_line(&mut peers).unwrap();
+    let addrs = paxos::parse_addrs(&peers).unwrap();
+
+    let endpoint = TcpTransport::<SystemMessage<Counter>>::with_listener(node, listener, &addrs).unwrap();
+    let thread = paxos::start_node(
+        node,
+        Box::new(endpoint),
+        CLUSTER,
+        &SystemOptions::default(),
+        &Counter::default,
+        Arc::new(counter_workload),
+    );
+    // the node exits on the exit signal of the control node
+    thread.join().unwrap();
+}
+
+
+
+
+// replicas, leaders and acceptors each in a process of their own, talking TCP over localhost
+// the clients and the control node run in the test process, over TCP as well
+// every node listens on port 0, the addresses are handed around once all of them are bound
+#[test]
+fn tcp_cluster() {
+    let mut addrs: HashMap<NodeId, SocketAddr> = HashMap::new();
+    let mut listeners: HashMap<NodeId, TcpListener> = HashMap::new();
+    let mut processes: Vec<NodeProcess> = Vec::new();
+    for node in CLUSTER.nodes() {
+        if let NodeId::Control | NodeId::Client(_) = node {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            addrs.insert(node, listener.local_addr().unwrap());
+            listeners.insert(node, listener);
+            continue;
+        }
+        let mut child = Command::new(env::current_exe().unwrap())
+            .args(["tcp_node_process", "--exact", "--nocapture", "--test-threads=1"])
+            .env(NODE_VAR, node.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let addr = listening_addrs(&mut child, 1).unwrap_or_else(|| panic!("Node {} exited before listening", node));
+        addrs.insert(node, addr[0]);
+        processes.push(NodeProcess { node, child });
+    }
+
+    let peers = paxos::format_addrs(&addrs);
+    for process in &mut processes {
+        writeln!(process.child.stdin.take().unwrap(), "{}", peers).unwrap();
+    }
+
+    let mut endpoint = |node: NodeId| -> SystemTransport<Counter> {
+        Box::new(TcpTransport::with_listener(node, listeners.remove(&node).unwrap(), &addrs).unwrap())
+    };
+    let clients = (0..CLUSTER.clients).map(|client_id| (NodeId::Client(client_id), endpoint(NodeId::Client(client_id)))).collect();
+    let system_handles = SystemHandles::with_transports(
+        endpoint(NodeId::Control),
+        CLUSTER,
+        clients,
+        SystemOptions::default(),
+        Counter::default,
+        Arc::new(counter_workload),
+    );
+
+    system_handles.activate(NUM_MSGS, CLUSTER.clients, CLUSTER.replicas, CLUSTER.leaders);
+    assert_eq!(system_handles.wait_for_clients(Duration::from_secs(30)), Ok(()), "Clients not answered over TCP");
+    system_handles.deactivate();
+
+    for process in &mut processes {
+        assert!(process.wait(Duration::from_secs(10)), "Node {} didn't exit", process.node);
+    }
+}
+
+
+
+
+// three servers and a client started from the paxos-node and paxos-client binaries, sharing a cluster config
+// the binaries only listen on the addresses of the config, taken from free_addr
+// another test may bind one of them first, the whole run is tried again on other ports when a node can't listen
+#[test]
+fn node_binaries() {
+    let output = (0..3)
+        .find_map(run_node_binaries)
+        .expect("No run of the binaries got all their ports");
+    assert!(output.status.success(), "paxos-client failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "put a 1 -> Ok(None)\n\
+         cas a 1 2 -> Ok(Some(\"1\"))\n\
+         get a -> Ok(Some(\"2\"))\n\
+         delete b -> Err(KeyNotFound(\"b\"))\n"
+    );
+}
+
+// the output of the client, None if a server or the client couldn't listen on its address
+fn run_node_binaries(attempt: u32) -> Option<Output> {
+    let mut config = String::from("[options]\nfailure_timeout_ms = 200\n");
+    let mut nodes = vec![NodeId::Client(0)];
+    for id in 0..3 {
+        nodes.extend([NodeId::Replica(id), NodeId::Leader(id), NodeId::Acceptor(id)]);
+    }
+    for node in nodes {
+        let (role, id) = node.to_string().split_once(':').map(|(role, id)| (role.to_string(), id.to_string())).unwrap();
+        config += &format!("[[nodes]]\nrole = \"{}\"\nid = {}\naddress = \"{}\"\n", role, id, free_addr());
+    }
+    let config_path = env::temp_dir().join(format!("paxos-cluster-{}-{}.toml", std::process::id(), attempt));
+    std::fs::write(&config_path, config).unwrap();
+    let config_path = config_path.to_str().unwrap();
+
+    // every server is listening with its three roles before the client starts
+    let mut servers: Vec<NodeProcess> = Vec::new();
+    for id in 0..3 {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_paxos-node"))
+            .args(["--role", "server", "--id", &id.to_string(), "--config", config_path, "--state-machine", "kv"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let listening = listening_addrs(&mut child, 3);
+        servers.push(NodeProcess { node: NodeId::Replica(id), child });
+        if listening.is_none() {
+            let _ = std::fs::remove_file(config_path);
+            return None;
+        }
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_paxos-client"))
+        .args(["--id", "0", "--config", config_path, "--state-machine", "kv", "--timeout-ms", "10000"])
+        .args(["put a 1", "cas a 1 2", "get a", "delete b"])
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_file(config_path);
+    if String::from_utf8_lossy(&output.stderr).contains("Can't listen") {
+        return None;
+    }
+    // the servers are killed when dropped
+    Some(output)
+}
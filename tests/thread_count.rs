@@ -0,0 +1,49 @@
+// a binary of its own, so no other test starts threads in this process while it counts
+// the threads are counted in /proc
+#![cfg(target_os = "linux")]
+
+use paxos::SystemHandles;
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+
+// threads of this process right now
+fn process_threads() -> usize {
+    fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+
+// the scouts and commanders live in the leader thread, a long run doesn't start a thread per ballot or proposal
+#[test]
+fn thread_count_stays_flat_during_a_run() {
+    let system_handles = SystemHandles::system_handle_management(3, 3, 3, 3);
+    let nodes = system_handles.running_threads();
+
+    // the highest thread count seen until the run is over, taken by a thread of its own
+    let done = Arc::new(AtomicBool::new(false));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let sampler = {
+        let (done, peak) = (done.clone(), peak.clone());
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                peak.fetch_max(process_threads(), Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(1));
+            }
+        })
+    };
+    thread::sleep(Duration::from_millis(10));
+    let before = process_threads();
+
+    system_handles.activate(200, 3, 3, 3);
+    assert_eq!(system_handles.wait_for_clients(Duration::from_secs(60)), Ok(()));
+    done.store(true, Ordering::SeqCst);
+    sampler.join().unwrap();
+
+    assert_eq!(system_handles.running_threads(), nodes);
+    assert_eq!(peak.load(Ordering::SeqCst), before);
+    system_handles.deactivate();
+    assert_eq!(system_handles.running_threads(), 0);
+}